@@ -266,6 +266,8 @@ class LocalPantsRunner:
                 finally:
                     metrics = self.graph_session.scheduler_session.metrics()
                     self.run_tracker.set_pantsd_scheduler_metrics(metrics)
+                    if self.options.goals and global_options.process_execution_local_cache_summary:
+                        logger.info(self.graph_session.scheduler_session.get_cache_summary())
                     self.run_tracker.end_run(engine_result)
 
                 return engine_result
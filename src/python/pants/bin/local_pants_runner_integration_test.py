@@ -0,0 +1,20 @@
+# Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from pants.testutil.pants_integration_test import run_pants
+
+
+def test_cache_summary_is_opt_in() -> None:
+    result = run_pants(["roots"])
+    result.assert_success()
+    assert "cache summary:" not in result.stderr
+
+    result = run_pants(["--process-execution-local-cache-summary", "roots"])
+    result.assert_success()
+    assert "cache summary:" in result.stderr
+
+
+def test_cache_summary_requires_goals() -> None:
+    result = run_pants(["--process-execution-local-cache-summary", "--version"])
+    result.assert_success()
+    assert "cache summary:" not in result.stderr
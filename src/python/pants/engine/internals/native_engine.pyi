@@ -112,6 +112,7 @@ def scheduler_execute(
 def scheduler_metrics(scheduler: PyScheduler, session: PySession) -> dict[str, int]: ...
 def scheduler_shutdown(scheduler: PyScheduler, timeout_secs: int) -> None: ...
 def session_new_run_id(session: PySession) -> None: ...
+def session_get_cache_summary(session: PySession) -> str: ...
 def session_poll_workunits(
     scheduler: PyScheduler, session: PySession, max_log_verbosity_level: int
 ) -> tuple[tuple[Workunit, ...], tuple[Workunit, ...]]: ...
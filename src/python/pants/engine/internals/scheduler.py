@@ -626,6 +626,9 @@ class SchedulerSession:
     def garbage_collect_store(self, target_size_bytes: int) -> None:
         self._scheduler.garbage_collect_store(target_size_bytes)
 
+    def get_cache_summary(self) -> str:
+        return native_engine.session_get_cache_summary(self.py_session)
+
     def get_observation_histograms(self) -> dict:
         return native_engine.session_get_observation_histograms(self.py_scheduler, self.py_session)
 
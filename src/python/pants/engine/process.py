@@ -50,6 +50,9 @@ class ProcessCacheScope(Enum):
     # Will run once per Session, i.e. once per run of Pants. This happens because the engine
     # de-duplicates identical work; the process is neither memoized in memory nor cached to disk.
     PER_SESSION = "per_session"
+    # Like PER_SESSION, but additionally the process caches skip it entirely: they neither compute
+    # its cache key nor read or write it.
+    NEVER = "never"
 
 
 @frozen_after_init
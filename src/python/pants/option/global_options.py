@@ -1420,6 +1420,15 @@ class GlobalOptions(Subsystem):
                 "when run with Docker."
             ),
         )
+        register(
+            "--process-execution-local-cache-summary",
+            type=bool,
+            default=False,
+            help=(
+                "If true, log a one line summary of the hits and misses of the local process cache "
+                "at the end of each run of goals."
+            ),
+        )
 
     @classmethod
     def validate_instance(cls, opts):
@@ -270,6 +270,13 @@ impl Store {
     self.local.remove(EntryType::File, digest).await
   }
 
+  ///
+  /// Flushes the local store to disk, so that everything stored so far survives a system crash.
+  ///
+  pub async fn sync_local(&self) -> Result<(), String> {
+    self.local.sync().await
+  }
+
   ///
   /// A convenience method for storing a file.
   ///
@@ -432,6 +439,39 @@ impl Store {
       .await
   }
 
+  ///
+  /// Loads a directory proto like `load_directory`, but without verifying that it is canonical,
+  /// for callers which canonicalize Directories that were recorded by others. Its fingerprint is
+  /// still verified if it is back-filled from remote.
+  ///
+  pub async fn load_directory_unverified(
+    &self,
+    digest: Digest,
+  ) -> Result<Option<remexec::Directory>, String> {
+    self
+      .load_bytes_with(
+        EntryType::Directory,
+        digest,
+        move |bytes: &[u8]| {
+          remexec::Directory::decode(bytes).map_err(|e| {
+            format!(
+              "LMDB corruption: Directory bytes for {:?} were not valid: {:?}",
+              digest, e
+            )
+          })
+        },
+        move |bytes: Bytes| {
+          remexec::Directory::decode(bytes).map_err(|e| {
+            format!(
+              "CAS returned Directory proto for {:?} which was not valid: {:?}",
+              digest, e
+            )
+          })
+        },
+      )
+      .await
+  }
+
   ///
   /// Loads bytes from remote cas if required and possible (i.e. if remote is configured). Takes
   /// two functions f_local and f_remote. These functions are any validation or transformations you
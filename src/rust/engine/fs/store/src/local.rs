@@ -246,6 +246,14 @@ impl ByteStore {
     dbs?.remove(digest.hash).await
   }
 
+  ///
+  /// Flushes all files and directories to disk.
+  ///
+  pub async fn sync(&self) -> Result<(), String> {
+    self.inner.file_dbs.clone()?.sync().await?;
+    self.inner.directory_dbs.clone()?.sync().await
+  }
+
   pub async fn store_bytes(
     &self,
     entry_type: EntryType,
@@ -375,6 +375,33 @@ async fn non_canonical_remote_directory_is_error() {
   );
 }
 
+#[tokio::test]
+async fn non_canonical_local_directory_loads_unverified() {
+  let mut non_canonical_directory = TestDirectory::containing_roland().directory();
+  non_canonical_directory.files.insert(
+    0,
+    remexec::FileNode {
+      name: "simba".to_string(),
+      digest: Some((&TestData::catnip().digest()).into()),
+      ..Default::default()
+    },
+  );
+
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+  let directory_digest = store
+    .record_directory(&non_canonical_directory, false)
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .load_directory_unverified(directory_digest)
+      .await
+      .unwrap(),
+    Some(non_canonical_directory)
+  );
+}
+
 #[tokio::test]
 async fn wrong_remote_file_bytes_is_error() {
   let dir = TempDir::new().unwrap();
@@ -0,0 +1,168 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be copied and pasted across crates, but there doesn't appear to be a way to include inner attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::unseparated_literal_suffix,
+  // TODO: Falsely triggers for async/await:
+  //   see https://github.com/rust-lang/rust-clippy/issues/5360
+  // clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(
+  clippy::len_without_is_empty,
+  clippy::redundant_field_names,
+  clippy::too_many_arguments
+)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default, clippy::new_ret_no_self)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bytes::Bytes;
+use hashing::{Digest, EMPTY_DIGEST};
+use process_execution::cache::{CommandRunner, LocalCacheOptions, MaterializePolicy};
+use process_execution::{
+  FallibleProcessResultWithPlatform, NamedCaches, Platform, ProcessMetadata, ProcessResultMetadata,
+  ProcessResultSource,
+};
+use sharded_lmdb::{ShardedLmdb, DEFAULT_LEASE_TIME};
+use store::Store;
+use task_executor::Executor;
+use tempfile::TempDir;
+
+///
+/// The (file count, file size) of the output trees of the benchmarked results.
+///
+const OUTPUT_TREES: &[(usize, usize)] = &[(1, 100), (100, 1_000), (1_000, 10_000)];
+
+pub fn criterion_benchmark_store(c: &mut Criterion) {
+  let executor = Executor::global(num_cpus::get(), num_cpus::get() * 4).unwrap();
+  let mut cgroup = c.benchmark_group("local_cache_store");
+
+  for &(count, size) in OUTPUT_TREES {
+    let (caching, _tempdir, result) = cached_runner_and_result(&executor, count, size);
+    let key = Digest::of_bytes(b"key").hash;
+    cgroup
+      .sample_size(50)
+      .measurement_time(Duration::from_secs(10))
+      .bench_function(format!("store({}, {})", count, size), |b| {
+        b.iter(|| executor.block_on(caching.store(key, &result)).unwrap())
+      });
+  }
+}
+
+pub fn criterion_benchmark_lookup(c: &mut Criterion) {
+  let executor = Executor::global(num_cpus::get(), num_cpus::get() * 4).unwrap();
+  let mut cgroup = c.benchmark_group("local_cache_lookup");
+
+  for &(count, size) in OUTPUT_TREES {
+    let (caching, _tempdir, result) = cached_runner_and_result(&executor, count, size);
+    let key = Digest::of_bytes(b"key").hash;
+    executor.block_on(caching.store(key, &result)).unwrap();
+    // `All` includes the round trip to the file Store to ensure the outputs, while `Lazy`
+    // measures the cache alone.
+    for &policy in &[MaterializePolicy::All, MaterializePolicy::Lazy] {
+      cgroup
+        .sample_size(50)
+        .measurement_time(Duration::from_secs(10))
+        .bench_function(format!("lookup({}, {}, {:?})", count, size, policy), |b| {
+          b.iter(|| {
+            executor
+              .block_on(caching.lookup(key, policy))
+              .unwrap()
+              .unwrap()
+          })
+        });
+    }
+  }
+}
+
+criterion_group!(
+  benches,
+  criterion_benchmark_store,
+  criterion_benchmark_lookup
+);
+criterion_main!(benches);
+
+///
+/// Creates a caching CommandRunner (whose stores live in the returned TempDir), and a result
+/// whose output directory contains `count` files of `size` bytes each.
+///
+fn cached_runner_and_result(
+  executor: &Executor,
+  count: usize,
+  size: usize,
+) -> (CommandRunner, TempDir, FallibleProcessResultWithPlatform) {
+  let tempdir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), tempdir.path().join("store")).unwrap();
+  let local = process_execution::local::CommandRunner::new(
+    store.clone(),
+    executor.clone(),
+    tempdir.path().to_owned(),
+    NamedCaches::new(tempdir.path().join("named_caches")),
+    true,
+  );
+  let process_execution_store = ShardedLmdb::new(
+    tempdir.path().join("cache"),
+    1024 * 1024 * 1024,
+    executor.clone(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    Arc::new(local),
+    process_execution_store,
+    store.clone(),
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+
+  let output_directory = executor.block_on(async move {
+    let mut files = Vec::with_capacity(count);
+    for i in 0..count {
+      // Prefix each file with its index, so that every file has a distinct digest.
+      let mut content = format!("{}:", i).into_bytes();
+      content.resize(size.max(content.len()), b'.');
+      let digest = store
+        .store_file_bytes(Bytes::from(content), false)
+        .await
+        .unwrap();
+      files.push(remexec::FileNode {
+        name: format!("file-{:05}", i),
+        digest: Some((&digest).into()),
+        ..remexec::FileNode::default()
+      });
+    }
+    let directory = remexec::Directory {
+      files,
+      ..remexec::Directory::default()
+    };
+    store.record_directory(&directory, false).await.unwrap()
+  });
+
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  (caching, tempdir, result)
+}
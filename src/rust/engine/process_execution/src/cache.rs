@@ -1,54 +1,3908 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bazel_protos::require_digest;
 use bytes::Bytes;
-use futures::{future, FutureExt};
-use hashing::Fingerprint;
-use log::{debug, warn};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compress, Compression, FlushCompress, Status};
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use hashing::{Digest, Fingerprint, EMPTY_DIGEST, FINGERPRINT_SIZE};
+use log::{debug, info, trace, warn};
+use parking_lot::Mutex;
 use prost::Message;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use sharded_lmdb::ShardedLmdb;
-use store::Store;
+use sharded_lmdb::{EntryMetadata, ShardedLmdb};
+use store::{EntryType, Store};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use workunit_store::{
-  in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, WorkunitMetadata,
+  in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, UserMetadataItem,
+  WorkunitMetadata,
 };
 
+use crate::cache_store::ProcessExecutionStore;
 use crate::{
-  Context, FallibleProcessResultWithPlatform, MultiPlatformProcess, Platform, Process,
+  CacheTier, Context, FallibleProcessResultWithPlatform, MultiPlatformProcess, Platform, Process,
   ProcessCacheScope, ProcessMetadata, ProcessResultSource,
 };
 
-#[allow(dead_code)]
+///
+/// The version of the format of entries stored in the cache. Each entry is prefixed with this
+/// byte (or, in the framed format, with `FRAMED_ENTRY_FORMAT_VERSION`), and it is mixed into each
+/// cache key so that entries written in an incompatible format are never read.
+///
+const ENTRY_FORMAT_VERSION: u8 = 8;
+
+///
+/// The version byte of entries in the framed format (see `LocalCacheOptions::framed_entries`),
+/// which is derived from `ENTRY_FORMAT_VERSION` so that the two are always bumped together: the
+/// keys of entries in either format are the same.
+///
+const FRAMED_ENTRY_FORMAT_VERSION: u8 = 0x80 | ENTRY_FORMAT_VERSION;
+
+///
+/// The size of the fixed header of a framed entry: the version, platform and flags bytes, the
+/// creation and expiration times, the response dictionary and input checksum fingerprints, the
+/// response digest (fingerprint and size), and the length of the ExecuteResponse. The
+/// ExecuteResponse follows it, and then the worker id (if any).
+///
+const FRAMED_HEADER_SIZE: usize = 3 + 8 + 8 + 3 * FINGERPRINT_SIZE + 8 + 4;
+
+const FRAMED_FLAG_RESPONSE_COMPRESSED: u8 = 1;
+const FRAMED_FLAG_RESPONSE_DICTIONARY: u8 = 1 << 1;
+const FRAMED_FLAG_INPUT_CHECKSUM: u8 = 1 << 2;
+const FRAMED_FLAG_EXPIRES_AT: u8 = 1 << 3;
+const FRAMED_FLAG_WORKER_ID: u8 = 1 << 4;
+const FRAMED_FLAG_RESPONSE_DIGEST: u8 = 1 << 5;
+
+///
+/// The number of leading bytes of a cache key which are determined by its namespace.
+///
+const NAMESPACE_PREFIX_SIZE: usize = 4;
+
+///
+/// Returns the bytes which begin the cache key of every Process in the given namespace.
+///
+pub fn namespace_key_prefix(namespace: &str) -> [u8; NAMESPACE_PREFIX_SIZE] {
+  let mut prefix = [0; NAMESPACE_PREFIX_SIZE];
+  prefix.copy_from_slice(&Digest::of_bytes(namespace.as_bytes()).hash.0[..NAMESPACE_PREFIX_SIZE]);
+  prefix
+}
+
+///
+/// Entries store the digest of the merged output Directory of a result in the `tree_digest` of
+/// a single `output_directories` entry (rather than the digest of a Tree proto, as the REAPI
+/// specifies), so it must be extracted as-is when a hit is populated. See
+/// `crate::remote::populate_fallible_execution_result`.
+///
+const TREE_DIGEST_IS_OUTPUT_DIRECTORY: bool = true;
+
+///
+/// The maximum number of lines which may be waiting to be written to a LineLog (such as the
+/// access log) before further lines are dropped.
+///
+const LINE_LOG_CAPACITY: usize = 64 * 1024;
+
+///
+/// The fraction of `max_total_bytes` (or `max_entries`) which the cache is shrunk to once it
+/// exceeds it.
+///
+const GC_TARGET_FRACTION: f64 = 0.9;
+
+///
+/// The size of the window of a deflate stream, and so the maximum useful size of a
+/// CompressionDictionary: a longer dictionary is truncated to its last this-many bytes.
+///
+pub const MAX_COMPRESSION_DICTIONARY_BYTES: usize = 32 * 1024;
+
+///
+/// The length of the substrings which `train_compression_dictionary` looks for in its samples:
+/// shorter common substrings are unlikely to be worth a back-reference.
+///
+const DICTIONARY_SUBSTRING_BYTES: usize = 16;
+
+///
+/// The prefix of the file Store blobs which record cache entries for `rebuild_index`: see
+/// `LocalCacheOptions::index_sidecars`. It is followed by the fingerprint of the entry, and then
+/// by the entry itself.
+///
+const INDEX_SIDECAR_MAGIC: &[u8] = b"pants-local-process-cache-entry\0";
+
 #[derive(Serialize, Deserialize)]
-struct PlatformAndResponseBytes {
-  platform: Platform,
-  response_bytes: Vec<u8>,
+pub(crate) struct CacheEntry {
+  /// The Platform which the result was produced on, or None if it was not recorded (which
+  /// `store` never does, but which a legacy or malformed entry might).
+  pub(crate) platform: Option<Platform>,
+  /// The encoded ExecuteResponse, which is deflate-compressed if `response_compressed` is set.
+  pub(crate) response_bytes: Vec<u8>,
+  pub(crate) response_compressed: bool,
+  /// When this entry was stored, in seconds since the unix epoch.
+  pub(crate) created_at_secs: u64,
+  /// The `worker_id` of the CommandRunner which stored this entry, if one was configured.
+  pub(crate) worker_id: Option<String>,
+  /// The fingerprint of the CompressionDictionary which `response_bytes` were compressed with, if
+  /// any.
+  pub(crate) response_dictionary: Option<Fingerprint>,
+  /// The `input_checksum` of the request which this entry was stored for, if it was stored by
+  /// `run`.
+  pub(crate) input_checksum: Option<Fingerprint>,
+  /// The `Process::expires_at` of the request which this entry was stored for, in seconds since
+  /// the unix epoch.
+  pub(crate) expires_at_secs: Option<u64>,
+  /// If set, the ExecuteResponse is shared with other entries: it is stored (exactly as
+  /// `response_bytes` would be) in the file Store under this digest, and `response_bytes` is
+  /// empty until `CommandRunner::resolve_response` loads it. See
+  /// `LocalCacheOptions::dedupe_responses`.
+  pub(crate) response_digest: Option<Digest>,
+}
+
+impl CacheEntry {
+  pub(crate) fn encode(&self) -> Result<Bytes, String> {
+    let mut bytes = vec![ENTRY_FORMAT_VERSION];
+    bincode::serialize_into(&mut bytes, self)
+      .map_err(|err| format!("Error serializing cache entry: {}", err))?;
+    Ok(Bytes::from(bytes))
+  }
+
+  ///
+  /// Encodes the entry in the framed format: a fixed-size header (see `FRAMED_HEADER_SIZE`)
+  /// followed by the ExecuteResponse, written into a single buffer without a bincode round trip.
+  ///
+  pub(crate) fn encode_framed(&self) -> Result<Bytes, String> {
+    let worker_id = self.worker_id.as_deref().unwrap_or_default().as_bytes();
+    let response_len: u32 = self.response_bytes.len().try_into().map_err(|_| {
+      format!(
+        "ExecuteResponse of {} bytes is too large for a framed cache entry.",
+        self.response_bytes.len()
+      )
+    })?;
+    let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+    let flags = flag(self.response_compressed, FRAMED_FLAG_RESPONSE_COMPRESSED)
+      | flag(
+        self.response_dictionary.is_some(),
+        FRAMED_FLAG_RESPONSE_DICTIONARY,
+      )
+      | flag(self.input_checksum.is_some(), FRAMED_FLAG_INPUT_CHECKSUM)
+      | flag(self.expires_at_secs.is_some(), FRAMED_FLAG_EXPIRES_AT)
+      | flag(self.worker_id.is_some(), FRAMED_FLAG_WORKER_ID)
+      | flag(self.response_digest.is_some(), FRAMED_FLAG_RESPONSE_DIGEST);
+
+    let mut bytes =
+      Vec::with_capacity(FRAMED_HEADER_SIZE + self.response_bytes.len() + worker_id.len());
+    bytes.push(FRAMED_ENTRY_FORMAT_VERSION);
+    bytes.push(encode_platform(self.platform));
+    bytes.push(flags);
+    bytes.extend_from_slice(&self.created_at_secs.to_be_bytes());
+    bytes.extend_from_slice(&self.expires_at_secs.unwrap_or_default().to_be_bytes());
+    bytes.extend_from_slice(
+      &self
+        .response_dictionary
+        .map_or([0; FINGERPRINT_SIZE], |fingerprint| fingerprint.0),
+    );
+    bytes.extend_from_slice(
+      &self
+        .input_checksum
+        .map_or([0; FINGERPRINT_SIZE], |fingerprint| fingerprint.0),
+    );
+    let response_digest = self.response_digest.unwrap_or(EMPTY_DIGEST);
+    bytes.extend_from_slice(&response_digest.hash.0);
+    bytes.extend_from_slice(&(response_digest.size_bytes as u64).to_be_bytes());
+    bytes.extend_from_slice(&response_len.to_be_bytes());
+    bytes.extend_from_slice(&self.response_bytes);
+    bytes.extend_from_slice(worker_id);
+    Ok(Bytes::from(bytes))
+  }
+
+  ///
+  /// Re-encodes the entry in the same format as the given stored bytes of an entry.
+  ///
+  fn encode_like(&self, bytes: &[u8]) -> Result<Bytes, String> {
+    if bytes.first() == Some(&FRAMED_ENTRY_FORMAT_VERSION) {
+      self.encode_framed()
+    } else {
+      self.encode()
+    }
+  }
+
+  fn decode(bytes: &[u8]) -> Result<CacheEntry, String> {
+    match bytes.split_first() {
+      Some((&ENTRY_FORMAT_VERSION, entry_bytes)) => {
+        bincode::deserialize(entry_bytes).map_err(|err| {
+          format!(
+            "Could not deserialize platform and response: {} ({})",
+            err,
+            Self::describe_bytes(bytes)
+          )
+        })
+      }
+      Some((&FRAMED_ENTRY_FORMAT_VERSION, _)) => Self::decode_framed(bytes).map_err(|err| {
+        format!(
+          "Could not decode framed entry: {} ({})",
+          err,
+          Self::describe_bytes(bytes)
+        )
+      }),
+      Some((version, _)) => Err(format!(
+        "Unsupported cache entry format version: {} (expected {} or {}; {})",
+        version,
+        ENTRY_FORMAT_VERSION,
+        FRAMED_ENTRY_FORMAT_VERSION,
+        Self::describe_bytes(bytes)
+      )),
+      None => Err("Cache entry was empty.".to_owned()),
+    }
+  }
+
+  fn decode_framed(bytes: &[u8]) -> Result<CacheEntry, String> {
+    if bytes.len() < FRAMED_HEADER_SIZE {
+      return Err("header is truncated".to_owned());
+    }
+    let (header, rest) = bytes.split_at(FRAMED_HEADER_SIZE);
+    let platform = decode_platform(header[1])?;
+    let flags = header[2];
+    let u64_at = |offset: usize| {
+      let mut be_bytes = [0; 8];
+      be_bytes.copy_from_slice(&header[offset..offset + 8]);
+      u64::from_be_bytes(be_bytes)
+    };
+    let fingerprint_at =
+      |offset: usize| Fingerprint::from_bytes_unsafe(&header[offset..offset + FINGERPRINT_SIZE]);
+    let created_at_secs = u64_at(3);
+    let expires_at_secs = u64_at(11);
+    let response_dictionary = fingerprint_at(19);
+    let input_checksum = fingerprint_at(19 + FINGERPRINT_SIZE);
+    let response_digest = Digest::new(
+      fingerprint_at(19 + 2 * FINGERPRINT_SIZE),
+      u64_at(19 + 3 * FINGERPRINT_SIZE) as usize,
+    );
+    let mut response_len = [0; 4];
+    response_len.copy_from_slice(&header[FRAMED_HEADER_SIZE - 4..]);
+    let response_len = u32::from_be_bytes(response_len) as usize;
+    if rest.len() < response_len {
+      return Err(format!(
+        "ExecuteResponse is truncated: expected {} bytes, but found {}",
+        response_len,
+        rest.len()
+      ));
+    }
+    let (response_bytes, worker_id) = rest.split_at(response_len);
+    let worker_id = if flags & FRAMED_FLAG_WORKER_ID != 0 {
+      Some(
+        String::from_utf8(worker_id.to_vec())
+          .map_err(|err| format!("worker id is not UTF-8: {}", err))?,
+      )
+    } else {
+      None
+    };
+    Ok(CacheEntry {
+      platform,
+      response_bytes: response_bytes.to_vec(),
+      response_compressed: flags & FRAMED_FLAG_RESPONSE_COMPRESSED != 0,
+      created_at_secs,
+      worker_id,
+      response_dictionary: Some(response_dictionary)
+        .filter(|_| flags & FRAMED_FLAG_RESPONSE_DICTIONARY != 0),
+      input_checksum: Some(input_checksum).filter(|_| flags & FRAMED_FLAG_INPUT_CHECKSUM != 0),
+      expires_at_secs: Some(expires_at_secs).filter(|_| flags & FRAMED_FLAG_EXPIRES_AT != 0),
+      response_digest: Some(response_digest).filter(|_| flags & FRAMED_FLAG_RESPONSE_DIGEST != 0),
+    })
+  }
+
+  ///
+  /// Describes the given stored bytes of an entry for an error message: their length, format
+  /// version byte, and a hex preview of their start. This is generally enough to tell a format
+  /// version mismatch from truncation or corruption.
+  ///
+  fn describe_bytes(bytes: &[u8]) -> String {
+    const PREVIEW_BYTES: usize = 16;
+    let preview = bytes
+      .iter()
+      .take(PREVIEW_BYTES)
+      .map(|byte| format!("{:02x}", byte))
+      .collect::<String>();
+    let version = bytes
+      .first()
+      .map_or_else(|| "none".to_owned(), |version| version.to_string());
+    format!(
+      "entry is {} bytes, with format version {}, starting with {}{}",
+      bytes.len(),
+      version,
+      preview,
+      if bytes.len() > PREVIEW_BYTES {
+        "..."
+      } else {
+        ""
+      }
+    )
+  }
+
+  fn decompressed_response_bytes(
+    &self,
+    dictionary: Option<&CompressionDictionary>,
+  ) -> Result<Vec<u8>, String> {
+    match (self.response_dictionary, dictionary) {
+      (None, _) => {
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(&self.response_bytes[..])
+          .read_to_end(&mut decompressed)
+          .map_err(|err| format!("Could not decompress ExecuteResponse: {}", err))?;
+        Ok(decompressed)
+      }
+      (Some(fingerprint), Some(dictionary)) if fingerprint == dictionary.fingerprint => {
+        dictionary.decompress(&self.response_bytes)
+      }
+      (Some(fingerprint), _) => Err(format!(
+        "ExecuteResponse was compressed with dictionary {}, which is not configured.",
+        fingerprint
+      )),
+    }
+  }
+
+  fn execute_response(
+    &self,
+    dictionary: Option<&CompressionDictionary>,
+  ) -> Result<remexec::ExecuteResponse, String> {
+    if let (Some(response_digest), true) = (self.response_digest, self.response_bytes.is_empty()) {
+      return Err(format!(
+        "ExecuteResponse {:?} is stored separately, and was not loaded.",
+        response_digest
+      ));
+    }
+    let decoded = if self.response_compressed {
+      remexec::ExecuteResponse::decode(&self.decompressed_response_bytes(dictionary)?[..])
+    } else {
+      remexec::ExecuteResponse::decode(&self.response_bytes[..])
+    };
+    decoded.map_err(|e| format!("Invalid ExecuteResponse: {:?}", e))
+  }
+
+  fn created_at(&self) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(self.created_at_secs)
+  }
+
+  fn expires_at(&self) -> Option<SystemTime> {
+    self
+      .expires_at_secs
+      .map(|expires_at_secs| UNIX_EPOCH + Duration::from_secs(expires_at_secs))
+  }
+
+  fn into_stored_entry(
+    self,
+    fingerprint: Fingerprint,
+    dictionary: Option<&CompressionDictionary>,
+  ) -> Result<StoredEntry, String> {
+    Ok(StoredEntry {
+      fingerprint,
+      platform: self.platform,
+      execute_response: self.execute_response(dictionary)?,
+      created_at: self.created_at(),
+      expires_at: self.expires_at(),
+      worker_id: self.worker_id,
+      response_digest: self.response_digest,
+    })
+  }
+}
+
+///
+/// A dictionary which primes the deflate compression of ExecuteResponses (see
+/// `LocalCacheOptions::compression_dictionary`), so that content which is common to many entries
+/// is compressed as back-references into the dictionary.
+///
+/// Deflate itself has no notion of a dictionary, so one is emulated: the dictionary is compressed
+/// and flushed to a byte boundary at the start of each stream, and only the remainder of the
+/// stream is stored. To decompress, the (deterministic) compressed dictionary is prepended again,
+/// and the dictionary is stripped from the output.
+///
+struct CompressionDictionary {
+  fingerprint: Fingerprint,
+  bytes: Vec<u8>,
+  compressed_prefix: Vec<u8>,
+}
+
+impl CompressionDictionary {
+  fn new(dictionary: &[u8]) -> Result<CompressionDictionary, String> {
+    let bytes = dictionary[dictionary
+      .len()
+      .saturating_sub(MAX_COMPRESSION_DICTIONARY_BYTES)..]
+      .to_vec();
+    let mut compressed_prefix = Vec::new();
+    Self::deflate(
+      &mut Compress::new(Compression::default(), false),
+      &bytes,
+      FlushCompress::Sync,
+      &mut compressed_prefix,
+    )?;
+    Ok(CompressionDictionary {
+      fingerprint: Digest::of_bytes(&bytes).hash,
+      bytes,
+      compressed_prefix,
+    })
+  }
+
+  ///
+  /// Feeds all of the input to the compressor, appending its output to the given Vec.
+  ///
+  fn deflate(
+    compress: &mut Compress,
+    input: &[u8],
+    flush: FlushCompress,
+    output: &mut Vec<u8>,
+  ) -> Result<(), String> {
+    let mut consumed = 0;
+    loop {
+      output.reserve(std::cmp::max(1024, input.len() - consumed));
+      let total_in = compress.total_in();
+      let status = compress
+        .compress_vec(&input[consumed..], output, flush)
+        .map_err(|err| format!("Error compressing execute process result: {}", err))?;
+      consumed += (compress.total_in() - total_in) as usize;
+      match status {
+        Status::StreamEnd => return Ok(()),
+        // If the output was not filled, then the compressor has flushed everything it had.
+        _ if consumed == input.len()
+          && output.len() < output.capacity()
+          && !matches!(flush, FlushCompress::Finish) =>
+        {
+          return Ok(())
+        }
+        _ => {}
+      }
+    }
+  }
+
+  ///
+  /// NB: This re-compresses the dictionary for each input (since a primed compressor cannot be
+  /// cloned), so its cost is proportional to the size of the dictionary plus the input.
+  ///
+  fn compress(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut output = Vec::new();
+    Self::deflate(&mut compress, &self.bytes, FlushCompress::Sync, &mut output)?;
+    if !output.starts_with(&self.compressed_prefix) {
+      return Err("Compression dictionary did not compress deterministically.".to_owned());
+    }
+    Self::deflate(&mut compress, input, FlushCompress::Finish, &mut output)?;
+    Ok(output.split_off(self.compressed_prefix.len()))
+  }
+
+  fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new((&self.compressed_prefix[..]).chain(compressed))
+      .read_to_end(&mut decompressed)
+      .map_err(|err| format!("Could not decompress ExecuteResponse: {}", err))?;
+    if !decompressed.starts_with(&self.bytes) {
+      return Err("ExecuteResponse was not compressed with the configured dictionary.".to_owned());
+    }
+    Ok(decompressed.split_off(self.bytes.len()))
+  }
+}
+
+///
+/// Builds a compression dictionary (see `LocalCacheOptions::compression_dictionary`) of at most
+/// `max_bytes` from the given samples, which should be representative of the data to be
+/// compressed.
+///
+/// The dictionary is composed of the runs of each sample which are covered by substrings that
+/// occur in more than one sample. The runs which occur in the most samples are preferred, and are
+/// placed last, since deflate encodes nearer back-references more cheaply.
+///
+pub fn train_compression_dictionary(samples: &[Vec<u8>], max_bytes: usize) -> Vec<u8> {
+  let max_bytes = std::cmp::min(max_bytes, MAX_COMPRESSION_DICTIONARY_BYTES);
+
+  // Count the samples which contain each substring.
+  let mut substring_counts: HashMap<&[u8], usize> = HashMap::new();
+  for sample in samples {
+    let substrings: HashSet<&[u8]> = sample.windows(DICTIONARY_SUBSTRING_BYTES).collect();
+    for substring in substrings {
+      *substring_counts.entry(substring).or_insert(0) += 1;
+    }
+  }
+
+  // Then count the samples which contain each maximal run of common substrings.
+  let mut run_counts: HashMap<&[u8], usize> = HashMap::new();
+  for sample in samples {
+    let mut runs = HashSet::new();
+    let mut run: Option<(usize, usize)> = None;
+    for (offset, substring) in sample.windows(DICTIONARY_SUBSTRING_BYTES).enumerate() {
+      if substring_counts[substring] < 2 {
+        continue;
+      }
+      run = match run {
+        Some((start, end)) if offset <= end => Some((start, offset + substring.len())),
+        Some((start, end)) => {
+          runs.insert(&sample[start..end]);
+          Some((offset, offset + substring.len()))
+        }
+        None => Some((offset, offset + substring.len())),
+      };
+    }
+    if let Some((start, end)) = run {
+      runs.insert(&sample[start..end]);
+    }
+    for run in runs {
+      *run_counts.entry(run).or_insert(0) += 1;
+    }
+  }
+
+  // Prefer the most common runs, and then the longest, breaking ties by content so that training
+  // is deterministic.
+  let mut runs: Vec<(&[u8], usize)> = run_counts.into_iter().collect();
+  runs.sort_by(|(a, a_count), (b, b_count)| {
+    b_count
+      .cmp(a_count)
+      .then_with(|| b.len().cmp(&a.len()))
+      .then_with(|| a.cmp(b))
+  });
+  let mut selected: Vec<&[u8]> = Vec::new();
+  let mut selected_bytes = 0;
+  for (run, _) in runs {
+    if selected_bytes + run.len() > max_bytes
+      || selected
+        .iter()
+        .any(|s| s.windows(run.len()).any(|window| window == run))
+    {
+      continue;
+    }
+    selected_bytes += run.len();
+    selected.push(run);
+  }
+  selected.into_iter().rev().flatten().copied().collect()
+}
+
+///
+/// A decoded view of an entry stored in the cache.
+///
+#[derive(Clone, Debug)]
+pub struct StoredEntry {
+  pub fingerprint: Fingerprint,
+  /// The Platform which the result was produced on, if it was recorded.
+  pub platform: Option<Platform>,
+  pub execute_response: remexec::ExecuteResponse,
+  pub created_at: SystemTime,
+  /// The time after which the entry expires regardless of the TTL, if its process set one.
+  pub expires_at: Option<SystemTime>,
+  /// The worker which stored the entry, if it was recorded.
+  pub worker_id: Option<String>,
+  /// The digest of the ExecuteResponse in the file Store, if it is shared with other entries.
+  pub response_digest: Option<Digest>,
+}
+
+impl StoredEntry {
+  ///
+  /// The digests (and types) of the stdout, stderr, and output directory (and the shared
+  /// ExecuteResponse, if any) which the entry references in the file Store.
+  ///
+  pub fn referenced_digests(&self) -> Result<Vec<(Digest, EntryType)>, String> {
+    let action_result = self
+      .execute_response
+      .result
+      .as_ref()
+      .ok_or_else(|| "action result missing from ExecuteResponse".to_owned())?;
+    let mut digests = vec![
+      (
+        require_digest(action_result.stdout_digest.as_ref())?,
+        EntryType::File,
+      ),
+      (
+        require_digest(action_result.stderr_digest.as_ref())?,
+        EntryType::File,
+      ),
+    ];
+    for output_directory in &action_result.output_directories {
+      digests.push((
+        require_digest(output_directory.tree_digest.as_ref())?,
+        EntryType::Directory,
+      ));
+    }
+    if let Some(response_digest) = self.response_digest {
+      digests.push((response_digest, EntryType::File));
+    }
+    Ok(digests)
+  }
+
+  ///
+  /// Returns the ExecuteResponse of the entry for export to other consumers (such as a remote
+  /// Action Cache), with its `cached_result` flag set as given.
+  ///
+  /// Entries are always stored with `cached_result: true`, because every read of them is a cache
+  /// hit, but consumers of an export may expect the response to look freshly executed.
+  ///
+  pub fn export_response(&self, cached_result: bool) -> remexec::ExecuteResponse {
+    remexec::ExecuteResponse {
+      cached_result,
+      ..self.execute_response.clone()
+    }
+  }
+}
+
+///
+/// The storage consumed by a single cache entry, as returned by `CommandRunner::entry_footprint`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EntryFootprint {
+  /// The size of the entry itself.
+  pub entry_bytes: usize,
+  /// The total size of the distinct file Store blobs (stdout, stderr, output files, and output
+  /// Directory protos) which the entry references.
+  pub referenced_blob_bytes: usize,
+}
+
+impl EntryFootprint {
+  pub fn total_bytes(&self) -> usize {
+    self.entry_bytes + self.referenced_blob_bytes
+  }
+}
+
+///
+/// The result of `CommandRunner::audit` or `CommandRunner::verify_sample`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AuditReport {
+  /// The number of entries which were checked: for `verify_sample`, the number sampled.
+  pub entries_checked: usize,
+  /// Entries which reference blobs that are missing from the file Store, with the digests of the
+  /// missing blobs. These entries will fail on lookup, and are candidates for eviction.
+  pub dangling: Vec<(Fingerprint, Vec<Digest>)>,
+  /// Entries which could not be decoded.
+  pub undecodable: Vec<Fingerprint>,
+}
+
+///
+/// The result of `CommandRunner::coverage`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoverageReport {
+  /// The number of requests which could be cached.
+  pub total: usize,
+  /// The number of those requests which have an entry.
+  pub cached: usize,
+  /// The fingerprints of the requests which could be cached but have no entry, in request order.
+  pub uncached: Vec<Fingerprint>,
+  /// The number of requests which are never cached (because they are not hermetic, or their
+  /// scope is `Never`), and so are excluded from the other counts.
+  pub skipped: usize,
+}
+
+impl CoverageReport {
+  ///
+  /// The fraction of the requests which could be cached that have an entry: 1.0 if there are
+  /// none.
+  ///
+  pub fn fraction(&self) -> f64 {
+    if self.total == 0 {
+      1.0
+    } else {
+      self.cached as f64 / self.total as f64
+    }
+  }
+}
+
+///
+/// The upper bounds of the buckets of `CacheSummary::age_histogram`. The final bucket holds the
+/// entries which are older than the last bound.
+///
+pub const AGE_HISTOGRAM_BOUNDS: [Duration; 4] = [
+  Duration::from_secs(60 * 60),
+  Duration::from_secs(24 * 60 * 60),
+  Duration::from_secs(7 * 24 * 60 * 60),
+  Duration::from_secs(30 * 24 * 60 * 60),
+];
+
+///
+/// The result of `CommandRunner::describe_all_summary`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheSummary {
+  /// The number of entries, including undecodable entries.
+  pub entries: u64,
+  /// The total size of the entries, excluding the blobs that they reference.
+  pub total_bytes: u64,
+  /// The number of decodable entries with each exit code: non-zero codes are cached failures.
+  pub exit_codes: BTreeMap<i32, u64>,
+  /// The number of decodable entries for each Platform, or for None if no Platform was recorded.
+  pub platforms: BTreeMap<Option<Platform>, u64>,
+  /// The number of decodable entries in each age bucket: see `AGE_HISTOGRAM_BOUNDS`.
+  pub age_histogram: [u64; AGE_HISTOGRAM_BOUNDS.len() + 1],
+  /// The number of entries which could not be decoded.
+  pub undecodable: u64,
+}
+
+///
+/// An entry loaded by `CommandRunner::load_stored_entry`.
+///
+enum LoadedEntry {
+  Decoded(StoredEntry),
+  Undecodable(String),
+  /// The entry was decodable, but its shared ExecuteResponse (with the given digest) is missing
+  /// from the file Store.
+  ResponseMissing(Digest),
+}
+
+///
+/// The result of `CommandRunner::validate`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValidationStatus {
+  /// The entry would be hit, and all of the blobs that it references are present.
+  Usable,
+  /// There is no entry for the fingerprint.
+  Missing,
+  /// The entry has outlived the configured `ttl`.
+  Expired,
+  /// The entry is for a platform which is not compatible with the current one (or for an unknown
+  /// platform, with `strict_platform`).
+  PlatformMismatch,
+  /// The entry could not be decoded.
+  Undecodable(String),
+  /// The entry references blobs which are missing from the file Store: see `AuditReport`.
+  Dangling(Vec<Digest>),
+}
+
+///
+/// Controls which of the digests referenced by a cache hit are ensured to be locally loadable
+/// before the hit is returned.
+///
+/// Regardless of the policy, the returned result always carries the output directory digest, so
+/// a caller that skipped materialization can ensure it later on demand.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MaterializePolicy {
+  /// Ensure that stdout, stderr, and the entire output directory are loadable.
+  All,
+  /// Ensure that stdout and stderr are loadable, but skip the (potentially expensive) recursive
+  /// ensure of the output directory.
+  StdoutStderrOnly,
+  /// Ensure that stdout, stderr, and the Directory protos of the output directory are loadable
+  /// (possibly by fetching them from a remote Store), but leave the contents of output files to
+  /// be fetched on demand when the output directory is materialized.
+  ///
+  /// This is appropriate for thin clients with a remote Store, where downloading large outputs
+  /// would otherwise dominate the cost of a hit.
+  OutputTreeOnly,
+  /// Ensure nothing: the caller is responsible for materializing whatever it reads.
+  Lazy,
+}
+
+impl MaterializePolicy {
+  ///
+  /// Returns this policy, without any of the requirements which it places on the output
+  /// directory.
+  ///
+  pub fn without_outputs(self) -> MaterializePolicy {
+    match self {
+      MaterializePolicy::All
+      | MaterializePolicy::StdoutStderrOnly
+      | MaterializePolicy::OutputTreeOnly => MaterializePolicy::StdoutStderrOnly,
+      MaterializePolicy::Lazy => MaterializePolicy::Lazy,
+    }
+  }
+}
+
+///
+/// Decides the order in which `gc` evicts entries. In all cases, pinned entries are only evicted
+/// once all unpinned entries have been, and remaining ties are broken by fingerprint so that
+/// eviction is deterministic.
+///
+/// NB: Recency is measured by entry leases, which have a granularity of one second, so many
+/// entries may be equally recent.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+  /// Evict the least recently used entries first.
+  Lru,
+  /// Evict the least recently used entries first, and the smallest of equally recent entries
+  /// first (since they are the cheapest to recompute).
+  LruSmallestFirst,
+  /// Evict the entries which have been hit least frequently first, and the least recently used
+  /// of equally frequent entries first. Frequency is a count of hits which decays with
+  /// `LocalCacheOptions::lfu_half_life`, and is persisted in the cache (see
+  /// `CommandRunner::persist_hit_frequencies`).
+  Lfu,
+}
+
+impl EvictionPolicy {
+  ///
+  /// Sorts the given entries in the order in which they should be evicted. The hit counts are
+  /// only used by `Lfu`, and need only be comparable with one another.
+  ///
+  pub(crate) fn sort_for_eviction(
+    self,
+    entries: &mut [EntryMetadata],
+    pins: &BTreeSet<Fingerprint>,
+    hit_counts: &HashMap<Fingerprint, u64>,
+  ) {
+    match self {
+      EvictionPolicy::Lru => entries.sort_by_key(|entry| {
+        (
+          pins.contains(&entry.fingerprint),
+          entry.leased_until_secs,
+          entry.fingerprint,
+        )
+      }),
+      EvictionPolicy::LruSmallestFirst => entries.sort_by_key(|entry| {
+        (
+          pins.contains(&entry.fingerprint),
+          entry.leased_until_secs,
+          entry.size_bytes,
+          entry.fingerprint,
+        )
+      }),
+      EvictionPolicy::Lfu => entries.sort_by_key(|entry| {
+        (
+          pins.contains(&entry.fingerprint),
+          hit_counts.get(&entry.fingerprint).copied().unwrap_or(0),
+          entry.leased_until_secs,
+          entry.fingerprint,
+        )
+      }),
+    }
+  }
+}
+
+///
+/// A count of the hits of an entry which decays exponentially over time, so that entries which
+/// were hit often long ago are eventually outranked by entries which are hit often now.
+///
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct HitFrequency {
+  hits: f64,
+  updated_at_secs: u64,
+}
+
+impl HitFrequency {
+  ///
+  /// Frequencies which have decayed below this many hits are forgotten when they are persisted.
+  ///
+  const MIN_HITS: f64 = 1.0 / 1024.0;
+
+  fn decayed(self, now_secs: u64, half_life: Duration) -> f64 {
+    let elapsed_secs = now_secs.saturating_sub(self.updated_at_secs) as f64;
+    let half_life_secs = half_life.as_secs_f64().max(1.0);
+    self.hits * 0.5_f64.powf(elapsed_secs / half_life_secs)
+  }
+
+  fn record_hit(&mut self, now_secs: u64, half_life: Duration) {
+    self.hits = self.decayed(now_secs, half_life) + 1.0;
+    self.updated_at_secs = now_secs;
+  }
+
+  fn merge(self, other: HitFrequency, now_secs: u64, half_life: Duration) -> HitFrequency {
+    HitFrequency {
+      hits: self.decayed(now_secs, half_life) + other.decayed(now_secs, half_life),
+      updated_at_secs: now_secs,
+    }
+  }
+}
+
+///
+/// What `store` does when storing a result would exceed
+/// `LocalCacheOptions::max_in_flight_store_bytes`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemoryPressurePolicy {
+  /// Wait for concurrent stores to complete until the result fits in the budget.
+  Wait,
+  /// Skip caching the result, and count it with `Metric::LocalCacheSkippedMemoryPressure`.
+  Skip,
+}
+
+///
+/// Decides whether a result stored for the first Platform may be used on the second (current)
+/// Platform.
+///
+pub type PlatformCompatibilityFn = Arc<dyn Fn(Platform, Platform) -> bool + Send + Sync>;
+
+///
+/// Returns the current time. See `LocalCacheOptions::clock`.
+///
+pub type ClockFn = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+///
+/// Computes the namespace (if any) of the cache key of a Process.
+///
+pub type KeyNamespaceFn = Arc<dyn Fn(&Process) -> Option<String> + Send + Sync>;
+
+///
+/// Normalizes a Process before its "semantic" cache key is computed. See
+/// `LocalCacheOptions::semantic_key_fn`.
+///
+pub type SemanticKeyFn = Arc<dyn Fn(&Process) -> Process + Send + Sync>;
+
+///
+/// Redacts secrets from the stdout or stderr of a process before it is stored in the cache,
+/// returning None if there is nothing to redact. See `LocalCacheOptions::redact_fn`.
+///
+pub type RedactFn = Arc<dyn Fn(&[u8]) -> Option<Bytes> + Send + Sync>;
+
+///
+/// Transforms a result before it is stored in the cache. See
+/// `LocalCacheOptions::store_transform_fn`.
+///
+pub type StoreTransformFn = Arc<dyn Fn(&mut FallibleProcessResultWithPlatform) + Send + Sync>;
+
+///
+/// Returns a RedactFn which replaces each match of any of the given patterns with `[REDACTED]`.
+///
+pub fn redact_patterns(patterns: Vec<regex::bytes::Regex>) -> RedactFn {
+  Arc::new(move |output| {
+    let mut redacted: Option<Vec<u8>> = None;
+    for pattern in &patterns {
+      let current = redacted.as_deref().unwrap_or(output);
+      let replaced = match pattern.replace_all(current, regex::bytes::NoExpand(b"[REDACTED]")) {
+        Cow::Owned(replaced) => Some(replaced),
+        Cow::Borrowed(_) => None,
+      };
+      if replaced.is_some() {
+        redacted = replaced;
+      }
+    }
+    redacted.map(Bytes::from)
+  })
+}
+
+///
+/// Makes the outputs stored in the file Store so far durable. See
+/// `LocalCacheOptions::sync_outputs_fn`.
+///
+pub type SyncOutputsFn =
+  Arc<dyn Fn(&Store) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+///
+/// A SyncOutputsFn which flushes the local file Store to disk.
+///
+pub fn sync_local_store(store: &Store) -> BoxFuture<'static, Result<(), String>> {
+  let store = store.clone();
+  async move { store.sync_local().await }.boxed()
+}
+
+///
+/// The default SemanticKeyFn, which ignores the timeout of a Process, and reduces each absolute
+/// path in its arguments and environment variables to its final component (so that, for example,
+/// `/home/alice/bin/tool` and `/usr/local/bin/tool` are treated as the same tool).
+///
+pub fn relativize_absolute_paths(process: &Process) -> Process {
+  let relativize = |value: &str| {
+    if value.starts_with('/') {
+      value
+        .rsplit('/')
+        .find(|c| !c.is_empty())
+        .unwrap_or("")
+        .to_owned()
+    } else {
+      value.to_owned()
+    }
+  };
+  Process {
+    argv: process.argv.iter().map(|arg| relativize(arg)).collect(),
+    env: process
+      .env
+      .iter()
+      .map(|(name, value)| (name.clone(), relativize(value)))
+      .collect(),
+    timeout: None,
+    ..process.clone()
+  }
+}
+
+///
+/// Selects the name of the store (if any) in `LocalCacheOptions::named_stores` which caches the
+/// results of a Process.
+///
+pub type StoreSelectorFn = Arc<dyn Fn(&Process) -> Option<String> + Send + Sync>;
+
+///
+/// An additional store for the local process cache, with its own limits.
+///
+/// All other options of a named store are inherited from the LocalCacheOptions which contain it.
+///
+#[derive(Clone)]
+pub struct NamedStoreOptions {
+  pub store: ShardedLmdb,
+  pub ttl: Option<Duration>,
+  pub max_total_bytes: Option<usize>,
+  pub max_entries: Option<u64>,
+}
+
+///
+/// Options for the local process cache.
+///
+/// NB: The defaults preserve the historical behavior of the cache, and are what production code
+/// uses unless it has a reason to tune a particular knob.
+///
+#[derive(Clone)]
+pub struct LocalCacheOptions {
+  /// If set, the cache will be bypassed for a cooldown period after repeated backend errors.
+  /// Disabled by default, so that (as historically) the cache is never bypassed because of errors.
+  pub circuit_breaker: Option<CircuitBreakerOptions>,
+  /// If set, entries older than this are treated as misses.
+  pub ttl: Option<Duration>,
+  /// If set, and re-executing the process of an expired entry fails with an error (rather than
+  /// merely exiting non-zero), the expired entry is served instead, provided that it expired
+  /// less than this long ago ("stale-if-error"). If None, expired entries are never served.
+  pub serve_expired_on_error_within: Option<Duration>,
+  /// Whether to deflate-compress the ExecuteResponse proto portion of each entry.
+  ///
+  /// NB: stdout, stderr and outputs are stored by digest in the file Store rather than inline in
+  /// the entry, so they are never (re-)compressed by this option.
+  pub compress_response: bool,
+  /// If set (along with `compress_response`), a dictionary of content which is common to many
+  /// ExecuteResponses, which improves their compression ratio: see
+  /// `train_compression_dictionary`. Only the last `MAX_COMPRESSION_DICTIONARY_BYTES` are used.
+  ///
+  /// NB: Entries which were compressed with a different dictionary (or none) cannot be read,
+  /// and so are treated as read errors until they are replaced.
+  pub compression_dictionary: Option<Bytes>,
+  /// If set, entries stored for a Platform which this function deems incompatible with the
+  /// current Platform are treated as misses. If unset, the stored Platform is not consulted.
+  pub platform_compatibility_fn: Option<PlatformCompatibilityFn>,
+  /// If set, the least recently used entries are evicted once the entries in the cache total
+  /// more than this many bytes.
+  ///
+  /// NB: Only the entries themselves count toward this limit: the outputs that they reference
+  /// live in the file Store, which is garbage collected separately.
+  pub max_total_bytes: Option<usize>,
+  /// If set, the least recently used entries are evicted once the cache contains more than this
+  /// many entries. This bounds per-entry costs (such as LMDB page churn) for workloads with very
+  /// many tiny entries, for which `max_total_bytes` behaves poorly. Eviction is triggered when
+  /// either limit is exceeded.
+  pub max_entries: Option<u64>,
+  /// If set, entries are evicted (in the usual order) once the distinct file Store blobs which
+  /// the entries reference total more than this many bytes, so that the GC of the file Store can
+  /// then reclaim the blobs which are no longer referenced. Unlike `max_total_bytes`, this bounds
+  /// the real disk cost of the cache. It only applies to the entries of this store (and not to
+  /// those of the named stores).
+  ///
+  /// NB: The referenced bytes are measured by scanning every entry (and the Directories that
+  /// they reference) when they are first needed and after each eviction, and estimated in
+  /// between, counting the outputs of each stored result in full (even when they are shared with
+  /// other entries), so evictions may happen somewhat before the budget is actually exceeded.
+  pub max_referenced_blob_bytes: Option<u64>,
+  /// The order in which entries are evicted when a limit is exceeded.
+  pub eviction_policy: EvictionPolicy,
+  /// With `EvictionPolicy::Lfu`, the time after which the hits of an entry count half as much
+  /// toward its frequency.
+  pub lfu_half_life: Duration,
+  /// The MaterializePolicy that `run` uses when looking up results.
+  pub materialize_policy: MaterializePolicy,
+  /// Whether `run` ensures that the output directory of a hit is loadable before returning it,
+  /// as the `materialize_policy` requires. If false, only the parts of the policy which apply to
+  /// stdout and stderr are applied, and materializing the output directory is left to the
+  /// caller. Processes may override this with `Process::materialize_cached_outputs`.
+  pub default_materialize_outputs: bool,
+  /// Whether to re-read each entry after storing it, to detect silent corruption at write time
+  /// rather than on a later read. Off by default, because it doubles the I/O of each write.
+  pub verify_on_write: bool,
+  /// Whether to ensure that the stdout, stderr and output directory of each result are loadable
+  /// from the file Store before storing it, so that an entry which would always fail `lookup` is
+  /// never written. Off by default, because the runner which produced a result will have stored
+  /// its outputs.
+  pub verify_digests_on_write: bool,
+  /// Whether a hit raises the level of the lookup workunit to Debug and marks its description as
+  /// a hit. When false, hits are left at the Trace level to keep logs quiet.
+  pub promote_hit_workunit_level: bool,
+  /// The maximum number of shards which are scanned concurrently by operations which visit every
+  /// entry (such as `gc` and `migrate`).
+  pub scan_parallelism: usize,
+  /// The source with which hits are labeled. A runner which wraps this one may use a different
+  /// source to distinguish its hits in downstream reporting.
+  pub hit_source: ProcessResultSource,
+  /// Whether a failure to store a result fails the run of the process, rather than just being
+  /// logged. This is appropriate for jobs whose purpose is to populate the cache (which should
+  /// generally also disable the circuit breaker, so that writes are never skipped).
+  pub fail_on_write_error: bool,
+  /// If set, computes a namespace (such as the name of the tool a process runs) for a Process,
+  /// which becomes a stable prefix of its cache key. See `CommandRunner::purge_prefix`.
+  ///
+  /// NB: Entries are sharded by the first byte of their key, so all entries in a namespace will
+  /// be stored in the same shard.
+  pub key_namespace_fn: Option<KeyNamespaceFn>,
+  /// If set, a hit whose output directory references more than this many distinct files is
+  /// served as if the MaterializePolicy were `OutputTreeOnly`: the Directory protos are ensured,
+  /// but the files are left to be fetched when the output directory is materialized. This bounds
+  /// the cost of a hit on filesystems where per-file overhead dominates.
+  pub max_materialize_files: Option<usize>,
+  /// If true, a hit is returned as soon as its entry is decoded, and the outputs which the
+  /// MaterializePolicy requires are ensured in the background. If they turn out to be missing, a
+  /// warning is logged and the entry is removed, but the hit has already been served.
+  ///
+  /// NB: This trades a small window of incorrectness (a hit whose outputs cannot be
+  /// materialized) for lower hit latency, and so is only appropriate for interactive use.
+  pub fast_unsafe_reads: bool,
+  /// If set, a callback which is fired when the fill fraction of the cache crosses a watermark.
+  /// Has no effect unless `max_total_bytes` is also set.
+  pub fill_watermark: Option<FillWatermarkOptions>,
+  /// The clock used to timestamp entries and to decide whether they have expired. Tests may
+  /// provide a fake clock to exercise expiry deterministically.
+  ///
+  /// NB: Leases (which decide the order of eviction) are recorded by the store using the system
+  /// clock.
+  pub clock: ClockFn,
+  /// Whether entries with no recorded Platform are treated as misses. Either way, they are
+  /// logged and counted, so that operators can decide whether to purge them.
+  pub strict_platform: bool,
+  /// Additional stores (such as a small store with a short TTL for flaky test retries, separate
+  /// from the main store), keyed by name.
+  pub named_stores: HashMap<String, NamedStoreOptions>,
+  /// If set, selects which of the `named_stores` caches the results of a Process. Processes for
+  /// which it returns None use the main store.
+  pub store_selector_fn: Option<StoreSelectorFn>,
+  /// If set, each hit and miss of `run` is appended to the file at this path as a line of
+  /// tab-separated `timestamp_secs fingerprint hit|miss entry_bytes`, for offline analysis of
+  /// the hit rate under hypothetical limits. For a miss, `entry_bytes` is the size of the entry
+  /// which was stored for the result (or 0 if none was).
+  pub access_log_path: Option<PathBuf>,
+  /// If set, each hit and miss of `run` is appended to the file at this path as a line of JSON
+  /// with the fields `fingerprint`, `decision` ("hit" or "miss"), `exit_code`, `entry_bytes`,
+  /// `time_saved_ms` (for a hit, if known) and `source`, for ingestion by external tools. Like
+  /// the access log, it never blocks a run: lines which cannot be queued are dropped, and counted
+  /// by `Metric::LocalCacheDecisionLogDropped`.
+  pub decision_log_path: Option<PathBuf>,
+  /// If set, an identifier for this host or worker which is recorded in each stored entry (see
+  /// `StoredEntry::worker_id`), to help correlate nondeterminism in a shared cache with the
+  /// machine which produced it.
+  pub worker_id: Option<String>,
+  /// Normalizes Processes which set `semantic_cache_key` before their "semantic" cache key is
+  /// computed. The results of such Processes are stored under both their exact key and their
+  /// semantic key, and a miss for the exact key falls back to the semantic key.
+  ///
+  /// NB: This is a correctness tradeoff which the user is responsible for: a hit for the semantic
+  /// key may return the result of a Process which differs (in the ways that this function
+  /// normalizes away) from the one which was requested.
+  pub semantic_key_fn: SemanticKeyFn,
+  /// If set, applied to the stdout and stderr of each result before it is stored, so that
+  /// secrets (see `redact_patterns`) never enter the cache. The redacted outputs are stored in
+  /// the file Store, and the entry references their digests.
+  ///
+  /// NB: This is a storage-only transform. The result returned by the run which stores an entry
+  /// is not redacted, while hits for it are, so their stdout and stderr digests differ. Since
+  /// redaction is deterministic, re-executing and storing the same output produces the same
+  /// entry. The unredacted outputs remain in the file Store (where the process wrote them), but
+  /// are not referenced by the cache.
+  pub redact_fn: Option<RedactFn>,
+  /// If set, applied to a copy of each result before it is stored (and before `redact_fn`), for
+  /// example to replace outputs which embed ephemeral absolute paths with canonicalized outputs,
+  /// so that equivalent results are stored identically and can be reused across machines.
+  ///
+  /// NB: This is an advanced option, and the user is responsible for it: the transform must be
+  /// deterministic, and consumers of hits must understand the transformed result (which, as with
+  /// `redact_fn`, differs from the result returned by the run which stored it). Any outputs which
+  /// the transformed result references must already be in the file Store.
+  pub store_transform_fn: Option<StoreTransformFn>,
+  /// If set, called by `store` to make the outputs of a result durable before the entry which
+  /// references them is written, so that a crash can never leave an entry visible without its
+  /// outputs. The file Store does not sync its writes, so `sync_local_store` provides this, at
+  /// the cost of syncing every shard of the file Store for each write: it is unset by default.
+  pub sync_outputs_fn: Option<SyncOutputsFn>,
+  /// If set, bounds the total size of the entries which concurrent calls to `store` may buffer,
+  /// to protect memory-constrained workers from bursts of large results. An entry which is
+  /// larger than the budget uses all of it.
+  pub max_in_flight_store_bytes: Option<usize>,
+  /// What to do when a store would exceed `max_in_flight_store_bytes`.
+  pub memory_pressure_policy: MemoryPressurePolicy,
+  /// Entries which never expire (regardless of `ttl`) and are never evicted (regardless of
+  /// `max_total_bytes` and `max_entries`), such as golden base results. This is stricter than
+  /// pinning, which only defers eviction until all unpinned entries have been evicted: immortal
+  /// entries are only ever removed explicitly.
+  ///
+  /// NB: Immortal entries still count toward the limits, so making too many entries immortal
+  /// forces the remaining entries to be evicted sooner, and may leave the cache permanently over
+  /// its limits (in which case every store triggers an eviction pass).
+  pub immortal_fingerprints: HashSet<Fingerprint>,
+  /// If set, `store` buffers entries in memory rather than writing them immediately, so that
+  /// repeated writes of the same fingerprint within a session are coalesced, and the buffered
+  /// entries are written in a single transaction per shard. The buffer is flushed by
+  /// `flush_deferred_writes` (which `shutdown` calls), or as soon as it holds more than this
+  /// many bytes.
+  ///
+  /// Deferred entries are visible to `lookup` (and so to `run`) before they are flushed, but not
+  /// to methods which scan or inspect the underlying store. `verify_on_write` does not apply to
+  /// them, and buffered entries are lost if the process exits without a graceful shutdown.
+  pub deferred_write_max_bytes: Option<usize>,
+  /// If set, `run` remembers how the keys of this many recently run processes were composed
+  /// (identifying each process by its description), and on a miss for a process which was
+  /// previously run under a different key, logs (at trace) which of its inputs, environment
+  /// variables, arguments, etc. changed. The changes which most often cause misses are reported
+  /// by `CommandRunner::miss_causes`.
+  pub miss_diagnostics_capacity: Option<usize>,
+  /// If set, bounds the number of concurrent lookups (each of which reads the store, and may
+  /// ensure that the outputs of the entry are local), to bound the I/O of the cache on
+  /// constrained hardware. Lookups over the limit wait rather than fail. Shared by the named
+  /// stores.
+  pub max_concurrent_lookups: Option<usize>,
+  /// If set, once `validate` has found an entry to be usable, lookups of it skip checking that
+  /// its outputs are present for this long (or until it is replaced or removed). This caches the
+  /// verification in memory, to reduce the cost of frequently hit entries which are known to be
+  /// good, at the risk of serving an entry whose outputs have since been removed from the Store.
+  pub verification_lifetime: Option<Duration>,
+  /// If set, `store` also records each entry (with its fingerprint) as a "sidecar" blob in the
+  /// file Store, from which `rebuild_index` can recover the cache if it is lost while the file
+  /// Store survives. Sidecars are never removed by the cache: they expire with the rest of the
+  /// unused blobs of the file Store.
+  pub index_sidecars: bool,
+  /// If set, `store` skips results whose total output size (stdout, stderr, and output files and
+  /// directories) is smaller than this, since they are cheaper to regenerate than to cache.
+  pub min_output_bytes_to_cache: Option<u64>,
+  /// If set, `store` skips results whose total output size is larger than this.
+  pub max_output_bytes_to_cache: Option<u64>,
+  /// If set, `run` starts running the process concurrently with looking it up (rather than only
+  /// after a miss), and uses whichever completes first, cancelling the other. This saves latency
+  /// when lookups are slow, at the cost of sometimes running processes which would have hit.
+  ///
+  /// If the process completes first, it is stored without replacing any existing entry, since
+  /// whether there was a usable entry is unknown.
+  pub speculate: bool,
+  /// If set, `store` writes entries in a framed format (a fixed-size header followed by the
+  /// encoded ExecuteResponse) rather than serializing them with bincode, which is cheaper to
+  /// encode and decode. Entries in either format are always readable, so this may be toggled
+  /// without invalidating the cache.
+  pub framed_entries: bool,
+  /// If set, `store` stores each ExecuteResponse in the file Store (which is content-addressed),
+  /// and only a reference to it in the entry, so that entries with identical responses (such as
+  /// the many successes without any outputs) share one copy. This costs an extra read per lookup.
+  /// Entries stored either way are always readable.
+  pub dedupe_responses: bool,
+}
+
+impl Default for LocalCacheOptions {
+  fn default() -> Self {
+    Self {
+      circuit_breaker: None,
+      ttl: None,
+      serve_expired_on_error_within: None,
+      compress_response: false,
+      compression_dictionary: None,
+      platform_compatibility_fn: None,
+      max_total_bytes: None,
+      max_entries: None,
+      max_referenced_blob_bytes: None,
+      eviction_policy: EvictionPolicy::Lru,
+      lfu_half_life: Duration::from_secs(7 * 24 * 60 * 60),
+      materialize_policy: MaterializePolicy::All,
+      default_materialize_outputs: true,
+      verify_on_write: false,
+      verify_digests_on_write: false,
+      promote_hit_workunit_level: true,
+      scan_parallelism: 4,
+      hit_source: ProcessResultSource::HitLocally,
+      fail_on_write_error: false,
+      key_namespace_fn: None,
+      max_materialize_files: None,
+      fast_unsafe_reads: false,
+      fill_watermark: None,
+      clock: Arc::new(SystemTime::now),
+      strict_platform: false,
+      named_stores: HashMap::new(),
+      store_selector_fn: None,
+      access_log_path: None,
+      decision_log_path: None,
+      worker_id: None,
+      semantic_key_fn: Arc::new(relativize_absolute_paths),
+      redact_fn: None,
+      store_transform_fn: None,
+      sync_outputs_fn: None,
+      max_in_flight_store_bytes: None,
+      memory_pressure_policy: MemoryPressurePolicy::Wait,
+      immortal_fingerprints: HashSet::new(),
+      deferred_write_max_bytes: None,
+      miss_diagnostics_capacity: None,
+      max_concurrent_lookups: None,
+      verification_lifetime: None,
+      index_sidecars: false,
+      min_output_bytes_to_cache: None,
+      max_output_bytes_to_cache: None,
+      speculate: false,
+      framed_entries: false,
+      dedupe_responses: false,
+    }
+  }
+}
+
+///
+/// Why the cache could not be used for a request. Each reason is counted by its own `Metric`, in
+/// addition to `Metric::LocalCacheRequestsUncached`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum UncachedReason {
+  /// There was no entry for the request.
+  Miss,
+  /// There was an entry, but it had outlived the configured `ttl`.
+  Expired,
+  /// There was an entry, but for a platform which is not compatible with the current one (or
+  /// for an unknown platform, with `strict_platform`).
+  PlatformMismatch,
+  /// There was an entry, but for a failed process whose failures are not cached.
+  CachedFailure,
+  /// There was an entry, but some of the outputs that it references could not be loaded. This
+  /// is recoverable (by fetching the outputs, or by re-executing), so the entry is kept.
+  OutputsUnavailable(String),
+  /// There was an entry, but its response was structurally broken (for example, its output
+  /// directory reference was malformed), so it can never be used, and is evicted.
+  Malformed(String),
+  /// There was an entry, but it was stored for a request with different inputs which has the
+  /// same fingerprint, so using it would be silently incorrect.
+  FingerprintCollision,
+  /// There was an entry, but its ExecuteResponse carried a non-OK status, so it does not
+  /// describe a servable result. Unlike a `Malformed` entry, it is not evicted.
+  NonOkStatus(String),
+}
+
+impl UncachedReason {
+  pub(crate) fn metric(&self) -> Metric {
+    match self {
+      UncachedReason::Miss => Metric::LocalCacheUncachedMiss,
+      UncachedReason::Expired => Metric::LocalCacheUncachedExpired,
+      UncachedReason::PlatformMismatch => Metric::LocalCacheUncachedPlatformMismatch,
+      UncachedReason::CachedFailure => Metric::LocalCacheUncachedCachedFailure,
+      UncachedReason::OutputsUnavailable(_) => Metric::LocalCacheUncachedOutputsUnavailable,
+      UncachedReason::Malformed(_) => Metric::LocalCacheUncachedMalformed,
+      UncachedReason::FingerprintCollision => Metric::LocalCacheFingerprintCollision,
+      UncachedReason::NonOkStatus(_) => Metric::LocalCacheNonOkStatus,
+    }
+  }
+}
+
+///
+/// Statistics about the size of the local process cache.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct LocalCacheStats {
+  /// The approximate total size of the entries in the cache.
+  pub used_bytes: u64,
+  pub max_total_bytes: Option<usize>,
+  /// The approximate fraction of `max_total_bytes` which is in use, if a limit is configured.
+  pub fill_fraction: Option<f64>,
+  /// The approximate number of entries in the cache.
+  pub entries: u64,
+  pub max_entries: Option<u64>,
+  /// The requests served by `run` since the runner was created (or since `reset_metrics`).
+  pub counters: LocalCacheCounters,
+}
+
+///
+/// Counts of the outcomes of requests to `run`. A request which reads an error is also counted as
+/// a miss.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LocalCacheCounters {
+  pub hits: u64,
+  pub misses: u64,
+  pub read_errors: u64,
+  pub write_errors: u64,
+}
+
+///
+/// The composition of the keys of recently run processes: see
+/// `LocalCacheOptions::miss_diagnostics_capacity`.
+///
+struct MissDiagnostics {
+  capacity: usize,
+  /// The key most recently computed for each process identity, with a digest of each of its
+  /// components (see `key_components`).
+  recent: HashMap<String, (Fingerprint, BTreeMap<String, Digest>)>,
+  /// The identities in `recent`, in the order in which they were first seen.
+  order: VecDeque<String>,
+  /// How many misses each component has caused.
+  miss_causes: HashMap<String, u64>,
+}
+
+impl MissDiagnostics {
+  fn new(capacity: usize) -> MissDiagnostics {
+    MissDiagnostics {
+      capacity,
+      recent: HashMap::new(),
+      order: VecDeque::new(),
+      miss_causes: HashMap::new(),
+    }
+  }
+
+  ///
+  /// Records the key of a process, and if it missed under a key which differs from the one it
+  /// previously had, returns the previous key and a description of each component that changed.
+  ///
+  fn observe(
+    &mut self,
+    identity: String,
+    key: Fingerprint,
+    components: BTreeMap<String, Digest>,
+    missed: bool,
+  ) -> Option<(Fingerprint, Vec<String>)> {
+    let changed = match self.recent.get(&identity) {
+      Some((previous_key, previous_components)) if missed && *previous_key != key => {
+        let mut changed = Vec::new();
+        for (name, digest) in &components {
+          match previous_components.get(name) {
+            Some(previous_digest) if previous_digest == digest => {}
+            Some(_) => changed.push((name.clone(), name.clone())),
+            None => changed.push((name.clone(), format!("{} (added)", name))),
+          }
+        }
+        for name in previous_components.keys() {
+          if !components.contains_key(name) {
+            changed.push((name.clone(), format!("{} (removed)", name)));
+          }
+        }
+        for (name, _) in &changed {
+          *self.miss_causes.entry(name.clone()).or_insert(0) += 1;
+        }
+        Some((
+          *previous_key,
+          changed.into_iter().map(|(_, change)| change).collect(),
+        ))
+      }
+      _ => None,
+    };
+
+    if self
+      .recent
+      .insert(identity.clone(), (key, components))
+      .is_none()
+    {
+      self.order.push_back(identity);
+      if self.order.len() > self.capacity {
+        if let Some(oldest) = self.order.pop_front() {
+          self.recent.remove(&oldest);
+        }
+      }
+    }
+    changed
+  }
+}
+
+///
+/// The live (atomic) counterpart of LocalCacheCounters.
+///
+#[derive(Default)]
+struct CacheCounters {
+  hits: AtomicU64,
+  misses: AtomicU64,
+  read_errors: AtomicU64,
+  write_errors: AtomicU64,
+}
+
+impl CacheCounters {
+  fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> LocalCacheCounters {
+    LocalCacheCounters {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      read_errors: self.read_errors.load(Ordering::Relaxed),
+      write_errors: self.write_errors.load(Ordering::Relaxed),
+    }
+  }
+
+  ///
+  /// Zeroes the counters, returning their prior values. Each counter is swapped individually, so
+  /// under concurrency the snapshot is not atomic across counters, but no increment is lost.
+  ///
+  fn reset(&self) -> LocalCacheCounters {
+    LocalCacheCounters {
+      hits: self.hits.swap(0, Ordering::Relaxed),
+      misses: self.misses.swap(0, Ordering::Relaxed),
+      read_errors: self.read_errors.swap(0, Ordering::Relaxed),
+      write_errors: self.write_errors.swap(0, Ordering::Relaxed),
+    }
+  }
+}
+
+///
+/// The approximate size of the cache, which is maintained incrementally between scans.
+///
+#[derive(Clone, Copy, Debug, Default)]
+struct CacheUsage {
+  bytes: u64,
+  entries: u64,
+}
+
+///
+/// Options for the circuit breaker which bypasses the cache while its backend is unhealthy.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreakerOptions {
+  /// The number of consecutive cache read or write errors which trip the breaker.
+  pub failure_threshold: usize,
+  /// The window within which `failure_threshold` consecutive errors must occur to trip the
+  /// breaker.
+  pub failure_window: Duration,
+  /// How long the cache is bypassed once the breaker has tripped, before a single trial request
+  /// is allowed through to test the backend.
+  pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerOptions {
+  fn default() -> Self {
+    Self {
+      failure_threshold: 5,
+      failure_window: Duration::from_secs(60),
+      cooldown: Duration::from_secs(5 * 60),
+    }
+  }
+}
+
+enum CircuitBreakerState {
+  /// The cache is in use: tracks the current run of consecutive failures.
+  Closed {
+    failures: usize,
+    first_failure: Option<Instant>,
+  },
+  /// The cache is bypassed until the given deadline.
+  Open { until: Instant },
+  /// A single trial request has been allowed through to test the backend.
+  HalfOpen,
+}
+
+///
+/// Tracks consecutive cache backend errors, and decides whether requests should use the cache.
+///
+/// If no options are configured, the breaker never trips.
+///
+pub(crate) struct CircuitBreaker {
+  options: Option<CircuitBreakerOptions>,
+  state: Mutex<CircuitBreakerState>,
+}
+
+impl CircuitBreaker {
+  pub(crate) fn new(options: Option<CircuitBreakerOptions>) -> CircuitBreaker {
+    CircuitBreaker {
+      options,
+      state: Mutex::new(CircuitBreakerState::Closed {
+        failures: 0,
+        first_failure: None,
+      }),
+    }
+  }
+
+  ///
+  /// Returns a permit if a request should use the cache, transitioning from open to half-open if
+  /// the cooldown has elapsed. The outcome of the request should be recorded on the permit.
+  ///
+  pub(crate) fn allow_request(&self) -> Option<CircuitBreakerPermit> {
+    if self.options.is_none() {
+      return Some(CircuitBreakerPermit {
+        breaker: self,
+        trial: AtomicBool::new(false),
+      });
+    }
+    let mut state = self.state.lock();
+    let trial = match *state {
+      CircuitBreakerState::Closed { .. } => false,
+      CircuitBreakerState::Open { until } if Instant::now() >= until => {
+        info!("Local process cache circuit breaker is half-open: trying a single request.");
+        *state = CircuitBreakerState::HalfOpen;
+        true
+      }
+      // Either still cooling down, or a trial request is already in flight.
+      CircuitBreakerState::Open { .. } | CircuitBreakerState::HalfOpen => return None,
+    };
+    Some(CircuitBreakerPermit {
+      breaker: self,
+      trial: AtomicBool::new(trial),
+    })
+  }
+
+  ///
+  /// Called when a trial request is cancelled before its outcome is known: the breaker reopens
+  /// with an elapsed cooldown, so that the next request becomes the trial instead.
+  ///
+  fn release_trial(&self) {
+    let mut state = self.state.lock();
+    if let CircuitBreakerState::HalfOpen = *state {
+      debug!("Local process cache circuit breaker trial request was cancelled.");
+      *state = CircuitBreakerState::Open {
+        until: Instant::now(),
+      };
+    }
+  }
+
+  fn record_success(&self) {
+    if self.options.is_none() {
+      return;
+    }
+    let mut state = self.state.lock();
+    match *state {
+      // A request which started before the breaker tripped does not close it.
+      CircuitBreakerState::Open { .. } => {}
+      CircuitBreakerState::HalfOpen => {
+        info!("Local process cache circuit breaker closed: the cache is re-enabled.");
+        *state = CircuitBreakerState::Closed {
+          failures: 0,
+          first_failure: None,
+        };
+      }
+      CircuitBreakerState::Closed { .. } => {
+        *state = CircuitBreakerState::Closed {
+          failures: 0,
+          first_failure: None,
+        };
+      }
+    }
+  }
+
+  fn record_failure(&self) {
+    let options = if let Some(options) = self.options {
+      options
+    } else {
+      return;
+    };
+    let now = Instant::now();
+    let mut state = self.state.lock();
+    let tripped = match &mut *state {
+      CircuitBreakerState::Closed {
+        failures,
+        first_failure,
+      } => {
+        match *first_failure {
+          Some(first) if now.duration_since(first) <= options.failure_window => *failures += 1,
+          _ => {
+            *failures = 1;
+            *first_failure = Some(now);
+          }
+        }
+        *failures >= options.failure_threshold
+      }
+      CircuitBreakerState::HalfOpen => true,
+      CircuitBreakerState::Open { .. } => false,
+    };
+    if tripped {
+      warn!(
+        "Local process cache circuit breaker tripped due to repeated errors: bypassing the cache for {:?}.",
+        options.cooldown
+      );
+      *state = CircuitBreakerState::Open {
+        until: now + options.cooldown,
+      };
+    }
+  }
+}
+
+///
+/// A request which was allowed to use the cache by a CircuitBreaker.
+///
+/// If the request is the trial of a half-open breaker, and the request is cancelled before an
+/// outcome is recorded, the trial is released (explicitly, or when the permit is dropped) rather
+/// than leaving the breaker half-open forever.
+///
+pub(crate) struct CircuitBreakerPermit<'a> {
+  breaker: &'a CircuitBreaker,
+  trial: AtomicBool,
+}
+
+impl CircuitBreakerPermit<'_> {
+  pub(crate) fn record_success(&self) {
+    self.trial.store(false, Ordering::SeqCst);
+    self.breaker.record_success();
+  }
+
+  pub(crate) fn record_failure(&self) {
+    self.trial.store(false, Ordering::SeqCst);
+    self.breaker.record_failure();
+  }
+
+  ///
+  /// Releases the trial (if this permit is for one) without recording an outcome.
+  ///
+  pub(crate) fn release(&self) {
+    if self.trial.swap(false, Ordering::SeqCst) {
+      self.breaker.release_trial();
+    }
+  }
+}
+
+impl Drop for CircuitBreakerPermit<'_> {
+  fn drop(&mut self) {
+    self.release();
+  }
+}
+
+///
+/// Called when the fill fraction of the cache crosses a FillWatermarkOptions::fraction.
+///
+pub type FillWatermarkFn = Arc<dyn Fn() + Send + Sync>;
+
+///
+/// Options for a notification when the cache is nearly full, which allows automation to react
+/// (by collecting garbage or growing the disk) before writes start to fail.
+///
+#[derive(Clone)]
+pub struct FillWatermarkOptions {
+  /// The fraction of `max_total_bytes` at or above which the callback fires.
+  pub fraction: f64,
+  /// The minimum time between two firings of the callback.
+  pub min_interval: Duration,
+  /// Called (synchronously, by the write which crossed the watermark) when the fill fraction
+  /// rises to the watermark. It should be cheap: for example, sending on a channel.
+  pub callback: FillWatermarkFn,
+}
+
+///
+/// Fires the callback of a FillWatermarkOptions when the fill fraction rises to the watermark.
+///
+/// Firing is edge-triggered: the fill fraction must fall back below the watermark before the
+/// callback can fire again. It is also debounced by `min_interval`, so that a cache which
+/// oscillates around the watermark (as it will when `gc` shrinks it) does not cause a storm of
+/// callbacks.
+///
+struct FillWatermark {
+  options: Option<FillWatermarkOptions>,
+  state: Mutex<FillWatermarkState>,
+}
+
+#[derive(Default)]
+struct FillWatermarkState {
+  above: bool,
+  last_fired: Option<Instant>,
+}
+
+impl FillWatermark {
+  fn new(options: Option<FillWatermarkOptions>) -> FillWatermark {
+    FillWatermark {
+      options,
+      state: Mutex::default(),
+    }
+  }
+
+  fn observe(&self, fill_fraction: f64) {
+    let options = if let Some(ref options) = self.options {
+      options
+    } else {
+      return;
+    };
+    let fire = {
+      let mut state = self.state.lock();
+      let was_above = state.above;
+      state.above = fill_fraction >= options.fraction;
+      let debounced = state.last_fired.map_or(false, |last_fired| {
+        last_fired.elapsed() < options.min_interval
+      });
+      if state.above && !was_above && !debounced {
+        state.last_fired = Some(Instant::now());
+        true
+      } else {
+        false
+      }
+    };
+    // Fire outside of the lock, in case the callback observes the cache.
+    if fire {
+      info!(
+        "The local process cache has reached {:.0}% of its maximum size.",
+        fill_fraction * 100.0
+      );
+      (options.callback)();
+    }
+  }
+}
+
+///
+/// An append-only file of lines, such as the access log (see `LocalCacheOptions::access_log_path`)
+/// or the decision log (see `LocalCacheOptions::decision_log_path`).
+///
+/// Lines are written by a dedicated thread, so that logging never blocks a run. If the thread
+/// falls behind by more than LINE_LOG_CAPACITY lines, further lines are dropped.
+///
+struct LineLog {
+  sender: SyncSender<String>,
+}
+
+impl LineLog {
+  ///
+  /// Opens the file at the given path for appending. The name describes the log in errors, and
+  /// names its writer thread.
+  ///
+  fn open(path: &Path, name: &'static str) -> Result<LineLog, String> {
+    let file = std::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .map_err(|err| format!("Could not open {} {}: {}", name, path.display(), err))?;
+    let (sender, receiver) = sync_channel::<String>(LINE_LOG_CAPACITY);
+    let path = path.to_owned();
+    std::thread::Builder::new()
+      .name(format!("local_cache_{}", name.replace(' ', "_")))
+      .spawn(move || {
+        let mut writer = BufWriter::new(file);
+        // Exits once all senders have been dropped.
+        for line in receiver.iter() {
+          let written = writer.write_all(line.as_bytes()).and_then(|()| {
+            // Write any backlog before flushing, so that writes are batched under load.
+            for line in receiver.try_iter() {
+              writer.write_all(line.as_bytes())?;
+            }
+            writer.flush()
+          });
+          if let Err(err) = written {
+            warn!(
+              "Error writing {} {}: {} - disabling it",
+              name,
+              path.display(),
+              err
+            );
+            return;
+          }
+        }
+      })
+      .map_err(|err| format!("Could not start {} writer: {}", name, err))?;
+    Ok(LineLog { sender })
+  }
+
+  ///
+  /// Queues the given line (which should end with a newline) to be written. Never blocks: if the
+  /// writer is behind (or has failed), the line is dropped, and false is returned.
+  ///
+  fn write(&self, line: String) -> bool {
+    self.sender.try_send(line).is_ok()
+  }
+}
+
+///
+/// A line of the decision log: see `LocalCacheOptions::decision_log_path`.
+///
+#[derive(Serialize)]
+struct DecisionRecord {
+  fingerprint: String,
+  /// "hit" or "miss".
+  decision: &'static str,
+  exit_code: i32,
+  entry_bytes: u64,
+  time_saved_ms: Option<u64>,
+  source: &'static str,
+}
+
+///
+/// Entries which have been stored but not yet written: see
+/// `LocalCacheOptions::deferred_write_max_bytes`.
+///
+#[derive(Default)]
+struct DeferredWrites {
+  entries: HashMap<Fingerprint, Bytes>,
+  /// The total size of `entries`.
+  bytes: usize,
+}
+
+///
+/// Bounds the number of concurrent lookups: see `LocalCacheOptions::max_concurrent_lookups`.
+///
+struct LookupLimit {
+  permits: Semaphore,
+  /// The number of lookups waiting for a permit.
+  waiting: AtomicUsize,
+}
+
+impl LookupLimit {
+  fn new(max_concurrent_lookups: usize) -> LookupLimit {
+    LookupLimit {
+      permits: Semaphore::new(max_concurrent_lookups),
+      waiting: AtomicUsize::new(0),
+    }
+  }
+
+  ///
+  /// Waits for a permit to look up an entry, recording how many lookups were already waiting as
+  /// `ObservationMetric::LocalCacheReadQueueDepth`.
+  ///
+  async fn acquire(&self) -> Result<SemaphorePermit<'_>, String> {
+    let depth = self.waiting.fetch_add(1, Ordering::SeqCst);
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .record_observation(ObservationMetric::LocalCacheReadQueueDepth, depth as u64);
+    }
+    // Stop counting this lookup as waiting even if it is cancelled while waiting.
+    struct Waiting<'a>(&'a AtomicUsize);
+    impl Drop for Waiting<'_> {
+      fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+      }
+    }
+    let waiting = Waiting(&self.waiting);
+    let permit = self.permits.acquire().await;
+    std::mem::drop(waiting);
+    permit.map_err(|err| {
+      format!(
+        "Could not acquire a permit to look up a local cache entry: {}",
+        err
+      )
+    })
+  }
+}
+
+#[derive(Clone)]
+pub struct CommandRunner {
+  underlying: Arc<dyn crate::CommandRunner>,
+  process_execution_store: Arc<dyn ProcessExecutionStore>,
+  file_store: Store,
+  metadata: ProcessMetadata,
+  circuit_breaker: Arc<CircuitBreaker>,
+  ttl: Option<Duration>,
+  serve_expired_on_error_within: Option<Duration>,
+  compress_response: bool,
+  compression_dictionary: Option<Arc<CompressionDictionary>>,
+  platform_compatibility_fn: Option<PlatformCompatibilityFn>,
+  max_total_bytes: Option<usize>,
+  max_entries: Option<u64>,
+  max_referenced_blob_bytes: Option<u64>,
+  /// An estimate of the total size of the distinct blobs referenced by the entries, which is
+  /// computed by a scan when it is needed, and then maintained incrementally by `store`. Only
+  /// used when `max_referenced_blob_bytes` is set.
+  referenced_blob_usage: Arc<Mutex<Option<u64>>>,
+  eviction_policy: EvictionPolicy,
+  /// The hits of each entry which have not yet been persisted, if the EvictionPolicy uses them.
+  /// See `persist_hit_frequencies`.
+  hit_counts: Arc<Mutex<HashMap<Fingerprint, HitFrequency>>>,
+  lfu_half_life: Duration,
+  hit_frequencies_lock: Arc<tokio::sync::Mutex<()>>,
+  materialize_policy: MaterializePolicy,
+  default_materialize_outputs: bool,
+  verify_on_write: bool,
+  verify_digests_on_write: bool,
+  promote_hit_workunit_level: bool,
+  scan_parallelism: usize,
+  hit_source: ProcessResultSource,
+  fail_on_write_error: bool,
+  key_namespace_fn: Option<KeyNamespaceFn>,
+  max_materialize_files: Option<usize>,
+  fast_unsafe_reads: bool,
+  fill_watermark: Arc<FillWatermark>,
+  clock: ClockFn,
+  strict_platform: bool,
+  /// A CommandRunner for each of the named stores, which share everything but their store (and
+  /// its limits) with this one.
+  named_stores: Arc<HashMap<String, CommandRunner>>,
+  store_selector_fn: Option<StoreSelectorFn>,
+  access_log: Option<Arc<LineLog>>,
+  decision_log: Option<Arc<LineLog>>,
+  worker_id: Option<String>,
+  semantic_key_fn: SemanticKeyFn,
+  redact_fn: Option<RedactFn>,
+  store_transform_fn: Option<StoreTransformFn>,
+  sync_outputs_fn: Option<SyncOutputsFn>,
+  /// Permits for each byte of `LocalCacheOptions::max_in_flight_store_bytes`, with the size of the
+  /// budget.
+  store_memory_budget: Option<(Arc<Semaphore>, u32)>,
+  memory_pressure_policy: MemoryPressurePolicy,
+  immortal_fingerprints: Arc<HashSet<Fingerprint>>,
+  deferred_write_max_bytes: Option<usize>,
+  deferred_writes: Arc<Mutex<DeferredWrites>>,
+  miss_diagnostics: Option<Arc<Mutex<MissDiagnostics>>>,
+  lookup_limit: Option<Arc<LookupLimit>>,
+  verification_lifetime: Option<Duration>,
+  /// The time until which each entry that `validate` found to be usable is considered verified.
+  /// See `LocalCacheOptions::verification_lifetime`.
+  verified_until: Arc<Mutex<HashMap<Fingerprint, SystemTime>>>,
+  index_sidecars: bool,
+  min_output_bytes_to_cache: Option<u64>,
+  max_output_bytes_to_cache: Option<u64>,
+  speculate: bool,
+  framed_entries: bool,
+  dedupe_responses: bool,
+  /// An estimate of the total size and count of the entries in the cache, which is computed by a
+  /// scan the first time it is needed, and then maintained incrementally by `store` and `gc`.
+  usage: Arc<Mutex<Option<CacheUsage>>>,
+  /// The total time (in microseconds) spent waiting to begin write transactions, per shard.
+  shard_wait_micros: Arc<Vec<AtomicU64>>,
+  counters: Arc<CacheCounters>,
+  pins_lock: Arc<tokio::sync::Mutex<()>>,
+  /// Whether the underlying store was detected to be read-only at construction.
+  read_only: bool,
+}
+
+impl CommandRunner {
+  pub fn new(
+    underlying: Arc<dyn crate::CommandRunner>,
+    process_execution_store: ShardedLmdb,
+    file_store: Store,
+    metadata: ProcessMetadata,
+    options: LocalCacheOptions,
+  ) -> CommandRunner {
+    let shard_wait_micros: Arc<Vec<AtomicU64>> = Arc::new(
+      (0..process_execution_store.shard_count())
+        .map(|_| AtomicU64::new(0))
+        .collect(),
+    );
+    let process_execution_store = process_execution_store.with_txn_wait_observer({
+      let shard_wait_micros = shard_wait_micros.clone();
+      Arc::new(move |shard, wait| {
+        let wait_micros = wait.as_micros() as u64;
+        shard_wait_micros[shard as usize].fetch_add(wait_micros, Ordering::Relaxed);
+        if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+          workunit_store_handle
+            .store
+            .record_observation(ObservationMetric::LocalCacheShardWaitUs, wait_micros);
+        }
+      })
+    });
+    CommandRunner::from_parts(
+      underlying,
+      Arc::new(process_execution_store),
+      shard_wait_micros,
+      file_store,
+      metadata,
+      options,
+    )
+  }
+
+  ///
+  /// Like `new`, but caches into the given ProcessExecutionStore (such as a
+  /// `crate::cache_store::DirectoryStore`) rather than into a ShardedLmdb.
+  ///
+  pub fn new_with_store(
+    underlying: Arc<dyn crate::CommandRunner>,
+    process_execution_store: Arc<dyn ProcessExecutionStore>,
+    file_store: Store,
+    metadata: ProcessMetadata,
+    options: LocalCacheOptions,
+  ) -> CommandRunner {
+    CommandRunner::from_parts(
+      underlying,
+      process_execution_store,
+      Arc::new(Vec::new()),
+      file_store,
+      metadata,
+      options,
+    )
+  }
+
+  fn from_parts(
+    underlying: Arc<dyn crate::CommandRunner>,
+    process_execution_store: Arc<dyn ProcessExecutionStore>,
+    shard_wait_micros: Arc<Vec<AtomicU64>>,
+    file_store: Store,
+    metadata: ProcessMetadata,
+    options: LocalCacheOptions,
+  ) -> CommandRunner {
+    // A read-only store (such as a shared base cache) can still serve hits, so rather than
+    // failing every write, we disable writes up front.
+    let read_only = process_execution_store.is_read_only();
+    if read_only {
+      info!("The local process cache is read-only: results will not be written to it.");
+    }
+    let compression_dictionary = options
+      .compression_dictionary
+      .as_ref()
+      .and_then(|dictionary| {
+        CompressionDictionary::new(dictionary)
+          .map(Arc::new)
+          .map_err(|err| warn!("{} - the compression dictionary is disabled.", err))
+          .ok()
+      });
+    let open_log = |path: &Option<PathBuf>, name: &'static str| {
+      path.as_ref().and_then(|path| {
+        LineLog::open(path, name)
+          .map(Arc::new)
+          .map_err(|err| warn!("{} - the {} is disabled.", err, name))
+          .ok()
+      })
+    };
+    let store_memory_budget = options.max_in_flight_store_bytes.map(|max_bytes| {
+      let max_bytes = std::cmp::min(max_bytes, u32::MAX as usize);
+      (Arc::new(Semaphore::new(max_bytes)), max_bytes as u32)
+    });
+    let lookup_limit = options
+      .max_concurrent_lookups
+      .map(|max_concurrent_lookups| Arc::new(LookupLimit::new(max_concurrent_lookups)));
+    let access_log = open_log(&options.access_log_path, "access log");
+    let decision_log = open_log(&options.decision_log_path, "decision log");
+    let named_stores = options
+      .named_stores
+      .iter()
+      .map(|(name, named_store)| {
+        let named_options = LocalCacheOptions {
+          ttl: named_store.ttl,
+          max_total_bytes: named_store.max_total_bytes,
+          max_entries: named_store.max_entries,
+          max_referenced_blob_bytes: None,
+          named_stores: HashMap::new(),
+          store_selector_fn: None,
+          access_log_path: None,
+          decision_log_path: None,
+          ..options.clone()
+        };
+        let mut runner = CommandRunner::new(
+          underlying.clone(),
+          named_store.store.clone(),
+          file_store.clone(),
+          metadata.clone(),
+          named_options,
+        );
+        // All stores share one access log, one decision log, one memory budget, and one lookup
+        // limit.
+        runner.access_log = access_log.clone();
+        runner.decision_log = decision_log.clone();
+        runner.store_memory_budget = store_memory_budget.clone();
+        runner.lookup_limit = lookup_limit.clone();
+        (name.clone(), runner)
+      })
+      .collect();
+    CommandRunner {
+      underlying,
+      process_execution_store,
+      file_store,
+      metadata,
+      circuit_breaker: Arc::new(CircuitBreaker::new(options.circuit_breaker)),
+      ttl: options.ttl,
+      serve_expired_on_error_within: options.serve_expired_on_error_within,
+      compress_response: options.compress_response,
+      compression_dictionary,
+      platform_compatibility_fn: options.platform_compatibility_fn,
+      max_total_bytes: options.max_total_bytes,
+      max_entries: options.max_entries,
+      max_referenced_blob_bytes: options.max_referenced_blob_bytes,
+      referenced_blob_usage: Arc::default(),
+      eviction_policy: options.eviction_policy,
+      hit_counts: Arc::new(Mutex::new(HashMap::new())),
+      lfu_half_life: options.lfu_half_life,
+      hit_frequencies_lock: Arc::new(tokio::sync::Mutex::new(())),
+      materialize_policy: options.materialize_policy,
+      default_materialize_outputs: options.default_materialize_outputs,
+      verify_on_write: options.verify_on_write,
+      verify_digests_on_write: options.verify_digests_on_write,
+      promote_hit_workunit_level: options.promote_hit_workunit_level,
+      scan_parallelism: options.scan_parallelism,
+      hit_source: options.hit_source,
+      fail_on_write_error: options.fail_on_write_error,
+      key_namespace_fn: options.key_namespace_fn,
+      max_materialize_files: options.max_materialize_files,
+      fast_unsafe_reads: options.fast_unsafe_reads,
+      fill_watermark: Arc::new(FillWatermark::new(options.fill_watermark)),
+      clock: options.clock,
+      strict_platform: options.strict_platform,
+      named_stores: Arc::new(named_stores),
+      store_selector_fn: options.store_selector_fn,
+      access_log,
+      decision_log,
+      worker_id: options.worker_id,
+      semantic_key_fn: options.semantic_key_fn,
+      redact_fn: options.redact_fn,
+      store_transform_fn: options.store_transform_fn,
+      sync_outputs_fn: options.sync_outputs_fn,
+      store_memory_budget,
+      memory_pressure_policy: options.memory_pressure_policy,
+      immortal_fingerprints: Arc::new(options.immortal_fingerprints),
+      deferred_write_max_bytes: options.deferred_write_max_bytes,
+      deferred_writes: Arc::default(),
+      miss_diagnostics: options
+        .miss_diagnostics_capacity
+        .map(|capacity| Arc::new(Mutex::new(MissDiagnostics::new(capacity)))),
+      lookup_limit,
+      verification_lifetime: options.verification_lifetime,
+      verified_until: Arc::default(),
+      index_sidecars: options.index_sidecars,
+      min_output_bytes_to_cache: options.min_output_bytes_to_cache,
+      max_output_bytes_to_cache: options.max_output_bytes_to_cache,
+      speculate: options.speculate,
+      framed_entries: options.framed_entries,
+      dedupe_responses: options.dedupe_responses,
+      usage: Arc::new(Mutex::new(None)),
+      shard_wait_micros,
+      counters: Arc::default(),
+      pins_lock: Arc::new(tokio::sync::Mutex::new(())),
+      read_only,
+    }
+  }
+
+  ///
+  /// The CommandRunner which this cache wraps, for callers which need to run a process without
+  /// the cache.
+  ///
+  pub fn underlying(&self) -> &Arc<dyn crate::CommandRunner> {
+    &self.underlying
+  }
+
+  ///
+  /// Computes the fingerprint under which the given request is cached.
+  ///
+  /// If a `key_namespace_fn` is configured and returns a namespace for the request, the key
+  /// begins with `namespace_key_prefix(namespace)`, so that all entries for the namespace can be
+  /// removed with `purge_prefix`.
+  ///
+  /// A request with variants for multiple platforms is keyed on only the variant which would
+  /// run here (see `narrowed`), so that its result is shared with requests for that variant alone.
+  ///
+  pub fn fingerprint(&self, req: &MultiPlatformProcess) -> Fingerprint {
+    self.fingerprint_with_suffix(req, &[])
+  }
+
+  ///
+  /// Returns the key components which have most often changed to cause a miss for a process
+  /// which was previously run under a different key, with the number of misses they caused, most
+  /// frequent first. Empty unless `LocalCacheOptions::miss_diagnostics_capacity` is set.
+  ///
+  pub fn miss_causes(&self) -> Vec<(String, u64)> {
+    let mut miss_causes = match self.miss_diagnostics {
+      Some(ref miss_diagnostics) => miss_diagnostics
+        .lock()
+        .miss_causes
+        .iter()
+        .map(|(name, count)| (name.clone(), *count))
+        .collect::<Vec<_>>(),
+      None => vec![],
+    };
+    miss_causes.sort_by(|(a_name, a_count), (b_name, b_count)| {
+      b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    miss_causes
+  }
+
+  ///
+  /// Returns the "semantic" cache key for the given request, if any of its Processes set
+  /// `semantic_cache_key`. See `LocalCacheOptions::semantic_key_fn`.
+  ///
+  pub fn semantic_fingerprint(&self, req: &MultiPlatformProcess) -> Option<Fingerprint> {
+    if !req.0.values().any(|process| process.semantic_cache_key) {
+      return None;
+    }
+    let normalized = MultiPlatformProcess(
+      req
+        .0
+        .iter()
+        .map(|(constraint, process)| {
+          let process = if process.semantic_cache_key {
+            (self.semantic_key_fn)(process)
+          } else {
+            process.clone()
+          };
+          (*constraint, process)
+        })
+        .collect(),
+    );
+    // Mixed into the key so that a semantic key never collides with an exact key.
+    Some(self.fingerprint_with_suffix(&normalized, b"semantic"))
+  }
+
+  ///
+  /// Returns the cache key of the single-platform request whose REAPI Action has the given
+  /// digest, as computed by `crate::remote::make_execute_request` with this CommandRunner's
+  /// ProcessMetadata.
+  ///
+  /// The key of a request whose Process is namespaced by `LocalCacheOptions::key_namespace_fn`
+  /// cannot be recovered from its Action digest, so such requests will not be found by it.
+  ///
+  pub fn action_digest_fingerprint(&self, action_digest: Digest) -> Fingerprint {
+    // See `crate::digest`: the digest of a single-platform request is the digest of the hex
+    // hash of its Action digest.
+    let request_digest = Digest::of_bytes(action_digest.hash.to_hex().as_bytes());
+    Self::key_for_request_digest(request_digest, &[])
+  }
+
+  fn key_for_request_digest(request_digest: Digest, suffix: &[u8]) -> Fingerprint {
+    let mut key_bytes = request_digest.hash.as_bytes().to_vec();
+    key_bytes.push(ENTRY_FORMAT_VERSION);
+    key_bytes.extend_from_slice(suffix);
+    Digest::of_bytes(&key_bytes).hash
+  }
+
+  fn fingerprint_with_suffix(&self, req: &MultiPlatformProcess, suffix: &[u8]) -> Fingerprint {
+    let req = self.narrowed(req);
+    let digest = crate::digest(req.clone().into_owned(), &self.metadata);
+    let mut fingerprint = Self::key_for_request_digest(digest, suffix);
+    let namespace = self
+      .key_namespace_fn
+      .as_ref()
+      .and_then(|namespace_fn| req.0.values().find_map(|process| namespace_fn(process)));
+    if let Some(namespace) = namespace {
+      fingerprint.0[..NAMESPACE_PREFIX_SIZE].copy_from_slice(&namespace_key_prefix(&namespace));
+    }
+    fingerprint
+  }
+
+  ///
+  /// Returns the CommandRunner for the store which caches the results of the given request:
+  /// either one of the named stores, or this one. `lookup`, `store` and the other methods of the
+  /// returned CommandRunner operate on that store.
+  ///
+  pub fn select_store(&self, req: &MultiPlatformProcess) -> &CommandRunner {
+    let name = if let Some(ref store_selector_fn) = self.store_selector_fn {
+      req
+        .0
+        .values()
+        .find_map(|process| store_selector_fn(process))
+    } else {
+      None
+    };
+    match name {
+      Some(name) => self.named_stores.get(&name).unwrap_or_else(|| {
+        warn!(
+          "No local process cache store is named {:?}: using the main store.",
+          name
+        );
+        self
+      }),
+      None => self,
+    }
+  }
+
+  ///
+  /// Returns the CommandRunner for the named store with the given name, if any.
+  ///
+  pub fn named_store(&self, name: &str) -> Option<&CommandRunner> {
+    self.named_stores.get(name)
+  }
+
+  ///
+  /// Removes all entries whose fingerprints start with the given bytes, and returns how many
+  /// were removed. Combined with a `key_namespace_fn`, this allows for removing all entries for
+  /// a namespace without wiping the entire cache.
+  ///
+  pub async fn purge_prefix(&self, prefix: &[u8]) -> Result<u64, String> {
+    let mut purged = 0;
+    for fingerprint in self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+    {
+      if !is_reserved_key(fingerprint)
+        && fingerprint.as_ref().starts_with(prefix)
+        && self.remove(fingerprint).await?
+      {
+        purged += 1;
+      }
+    }
+    Ok(purged)
+  }
+
+  ///
+  /// Returns the fingerprints of the entries which were created or accessed at or after `since`,
+  /// so that (for example) a job which replicates the cache can transfer only what has changed
+  /// since its last run.
+  ///
+  /// NB: Entries are leased (and thus recorded as accessed) when they are stored, and when they
+  /// are hit if eviction is enabled. Leases have a granularity of one second.
+  ///
+  pub async fn iter_fingerprints_since(
+    &self,
+    since: SystemTime,
+  ) -> Result<Vec<Fingerprint>, String> {
+    let since_secs = since
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    let lease_secs = self.process_execution_store.lease_time().as_secs();
+    let mut fingerprints = Vec::new();
+    for entry in self
+      .process_execution_store
+      .all_entry_metadata(self.scan_parallelism)
+      .await?
+    {
+      if is_reserved_key(entry.fingerprint) {
+        continue;
+      }
+      let accessed = entry.leased_until_secs > 0
+        && entry.leased_until_secs.saturating_sub(lease_secs) >= since_secs;
+      let created = if accessed {
+        false
+      } else {
+        // An entry whose creation time cannot be decoded is not considered to be new.
+        self
+          .process_execution_store
+          .load_bytes_with(entry.fingerprint, |bytes| {
+            Ok(
+              CacheEntry::decode(bytes)
+                .ok()
+                .map(|entry| entry.created_at_secs),
+            )
+          })
+          .await?
+          .flatten()
+          .map_or(false, |created_at_secs| created_at_secs >= since_secs)
+      };
+      if accessed || created {
+        fingerprints.push(entry.fingerprint);
+      }
+    }
+    fingerprints.sort();
+    Ok(fingerprints)
+  }
+
+  ///
+  /// Loads and decodes the entry for the given fingerprint, regardless of whether it has expired.
+  ///
+  pub async fn load_entry(&self, fingerprint: Fingerprint) -> Result<Option<StoredEntry>, String> {
+    match self.load_stored_entry(fingerprint).await? {
+      None => Ok(None),
+      Some(LoadedEntry::Decoded(entry)) => Ok(Some(entry)),
+      Some(LoadedEntry::Undecodable(err)) => Err(err),
+      Some(LoadedEntry::ResponseMissing(response_digest)) => Err(format!(
+        "The ExecuteResponse {:?} of local cache entry {} is missing from the file Store.",
+        response_digest, fingerprint
+      )),
+    }
+  }
+
+  ///
+  /// Loads and decodes the entry for the given fingerprint (following any indirection to a
+  /// shared ExecuteResponse), distinguishing the ways in which it might be unusable.
+  ///
+  async fn load_stored_entry(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<LoadedEntry>, String> {
+    let entry = match self
+      .process_execution_store
+      .load_bytes_with(fingerprint, |bytes| Ok(CacheEntry::decode(bytes)))
+      .await?
+    {
+      None => return Ok(None),
+      Some(Err(err)) => return Ok(Some(LoadedEntry::Undecodable(err))),
+      Some(Ok(entry)) => entry,
+    };
+    let entry = match self.resolve_response(entry).await? {
+      Ok(entry) => entry,
+      Err(response_digest) => return Ok(Some(LoadedEntry::ResponseMissing(response_digest))),
+    };
+    Ok(Some(
+      match entry.into_stored_entry(fingerprint, self.compression_dictionary.as_deref()) {
+        Ok(entry) => LoadedEntry::Decoded(entry),
+        Err(err) => LoadedEntry::Undecodable(err),
+      },
+    ))
+  }
+
+  ///
+  /// Loads the shared ExecuteResponse of the given entry (see
+  /// `LocalCacheOptions::dedupe_responses`) from the file Store, if it has one. Returns the
+  /// digest of the response if it is missing.
+  ///
+  async fn resolve_response(
+    &self,
+    entry: CacheEntry,
+  ) -> Result<Result<CacheEntry, Digest>, String> {
+    let response_digest = match entry.response_digest {
+      Some(response_digest) if entry.response_bytes.is_empty() => response_digest,
+      _ => return Ok(Ok(entry)),
+    };
+    match self
+      .file_store
+      .load_file_bytes_with(response_digest, |bytes| bytes.to_vec())
+      .await?
+    {
+      Some(response_bytes) => Ok(Ok(CacheEntry {
+        response_bytes,
+        ..entry
+      })),
+      None => Ok(Err(response_digest)),
+    }
+  }
+
+  ///
+  /// Returns the bytes stored for the given fingerprint without decoding them, as a last resort
+  /// for diagnosing entries which cannot be decoded.
+  ///
+  /// If `decompress` is set and the response of the entry is compressed, the entry is re-encoded
+  /// with its response decompressed (which requires that the envelope of the entry is decodable).
+  /// Otherwise, the bytes are returned exactly as stored.
+  ///
+  pub async fn raw_entry(
+    &self,
+    fingerprint: Fingerprint,
+    decompress: bool,
+  ) -> Result<Option<Bytes>, String> {
+    let dictionary = self.compression_dictionary.clone();
+    self
+      .process_execution_store
+      .load_bytes_with(fingerprint, move |bytes| {
+        if !decompress {
+          return Ok(Bytes::copy_from_slice(bytes));
+        }
+        let entry = CacheEntry::decode(bytes)
+          .map_err(|err| format!("{} (request the raw bytes to inspect the entry)", err))?;
+        // Shared responses are not stored in the entry, so are not decompressed.
+        if !entry.response_compressed || entry.response_digest.is_some() {
+          return Ok(Bytes::copy_from_slice(bytes));
+        }
+        CacheEntry {
+          response_bytes: entry.decompressed_response_bytes(dictionary.as_deref())?,
+          response_compressed: false,
+          response_dictionary: None,
+          ..entry
+        }
+        .encode_like(bytes)
+      })
+      .await
+  }
+
+  ///
+  /// Trains a compression dictionary (see `train_compression_dictionary`) of at most `max_bytes`
+  /// from the ExecuteResponses of up to `max_samples` of the entries in the cache, for use as
+  /// `LocalCacheOptions::compression_dictionary`. Entries which cannot be decoded (or whose
+  /// responses are shared with other entries) are skipped.
+  ///
+  pub async fn train_dictionary(
+    &self,
+    max_samples: usize,
+    max_bytes: usize,
+  ) -> Result<Vec<u8>, String> {
+    let fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint))
+      .take(max_samples);
+    let samples = futures::stream::iter(fingerprints.map(|fingerprint| {
+      let dictionary = self.compression_dictionary.clone();
+      self
+        .process_execution_store
+        .load_bytes_with(fingerprint, move |bytes| {
+          let entry = match CacheEntry::decode(bytes) {
+            Ok(entry) if entry.response_digest.is_none() => entry,
+            _ => return Ok(None),
+          };
+          if entry.response_compressed {
+            Ok(
+              entry
+                .decompressed_response_bytes(dictionary.as_deref())
+                .ok(),
+            )
+          } else {
+            Ok(Some(entry.response_bytes))
+          }
+        })
+    }))
+    .buffer_unordered(self.scan_parallelism)
+    .try_collect::<Vec<_>>()
+    .await?
+    .into_iter()
+    .flatten()
+    .flatten()
+    .collect::<Vec<_>>();
+    Ok(train_compression_dictionary(&samples, max_bytes))
+  }
+
+  ///
+  /// Returns how long the entry for the given fingerprint has before it expires: the sooner of
+  /// its TTL deadline and its own `expires_at`. Returns None if the entry has neither, is
+  /// immortal, or there is no such entry.
+  ///
+  pub async fn ttl_remaining(&self, fingerprint: Fingerprint) -> Result<Option<Duration>, String> {
+    if self.immortal_fingerprints.contains(&fingerprint) {
+      return Ok(None);
+    }
+    let entry = self
+      .process_execution_store
+      .load_bytes_with(fingerprint, CacheEntry::decode)
+      .await?;
+    let ttl = self.ttl;
+    Ok(entry.and_then(|entry| {
+      let ttl_deadline = ttl.map(|ttl| entry.created_at() + ttl);
+      let deadline = match (ttl_deadline, entry.expires_at()) {
+        (Some(ttl_deadline), Some(expires_at)) => Some(ttl_deadline.min(expires_at)),
+        (ttl_deadline, expires_at) => ttl_deadline.or(expires_at),
+      }?;
+      Some(deadline.duration_since(self.now()).unwrap_or_default())
+    }))
+  }
+
+  ///
+  /// Returns whether an entry exists for each of the given fingerprints, in order.
+  ///
+  /// All keys are probed concurrently. Entries which have outlived the TTL or are past their
+  /// `expires_at` are reported as absent (since any entry may have an `expires_at`, each entry is
+  /// decoded), but the outputs which entries reference are not checked: a `true` here may still
+  /// miss in `lookup` if those outputs have since been garbage collected.
+  ///
+  pub async fn contains_many(&self, fingerprints: &[Fingerprint]) -> Result<Vec<bool>, String> {
+    future::try_join_all(fingerprints.iter().map(|fingerprint| async move {
+      Ok(
+        self
+          .process_execution_store
+          .load_bytes_with(*fingerprint, CacheEntry::decode)
+          .await?
+          .map_or(false, |entry| {
+            !self.is_expired(*fingerprint, entry.created_at(), entry.expires_at())
+          }),
+      )
+    }))
+    .await
+  }
+
+  ///
+  /// Computes how many of the given requests already have entries (as `contains_many` decides),
+  /// for example so that CI can check that a prewarmed cache is complete before starting a long
+  /// build. Requests for named stores are probed in those stores. Requests which would not be
+  /// cached if run with the given Context (see `Context::cache_scope`) are skipped.
+  ///
+  pub async fn coverage(
+    &self,
+    context: &Context,
+    requests: &[MultiPlatformProcess],
+  ) -> Result<CoverageReport, String> {
+    let mut report = CoverageReport::default();
+    // The indexes in `fingerprints` of the requests for each store.
+    let mut fingerprints = Vec::new();
+    let mut by_store: Vec<(&CommandRunner, Vec<usize>)> = Vec::new();
+    for req in requests {
+      let never_cached = req.0.values().any(|process| {
+        !process.hermetic || context.cache_scope(process) == ProcessCacheScope::Never
+      });
+      if never_cached {
+        report.skipped += 1;
+        continue;
+      }
+      let store = self.select_store(req);
+      fingerprints.push(store.fingerprint(req));
+      let index = fingerprints.len() - 1;
+      match by_store
+        .iter_mut()
+        .find(|(existing, _)| std::ptr::eq(*existing, store))
+      {
+        Some((_, indexes)) => indexes.push(index),
+        None => by_store.push((store, vec![index])),
+      }
+    }
+
+    let mut present = vec![false; fingerprints.len()];
+    for (store, indexes) in by_store {
+      let store_fingerprints = indexes
+        .iter()
+        .map(|index| fingerprints[*index])
+        .collect::<Vec<_>>();
+      let store_present = store.contains_many(&store_fingerprints).await?;
+      for (index, is_present) in indexes.into_iter().zip(store_present) {
+        present[index] = is_present;
+      }
+    }
+
+    report.total = fingerprints.len();
+    for (fingerprint, is_present) in fingerprints.into_iter().zip(present) {
+      if is_present {
+        report.cached += 1;
+      } else {
+        report.uncached.push(fingerprint);
+      }
+    }
+    Ok(report)
+  }
+
+  ///
+  /// Checks that the cache is functional (rather than just openable) by storing a sentinel entry
+  /// under a reserved key, reading it back, and removing it. Workers may gate their readiness on
+  /// this.
+  ///
+  /// If the store is read-only, only checks that it can be read.
+  ///
+  pub async fn health_check(&self) -> Result<(), String> {
+    let key = health_check_key();
+    let read = |expected: Option<Bytes>| async move {
+      let actual = self
+        .process_execution_store
+        .load_bytes(key)
+        .await
+        .map_err(|err| format!("Local process cache health check failed to read: {}", err))?;
+      if actual != expected {
+        return Err(format!(
+          "Local process cache health check read {:?}, but expected {:?}.",
+          actual, expected
+        ));
+      }
+      Ok(())
+    };
+    if self.read_only {
+      // Don't insist that the sentinel is absent: a writer may be mid-check.
+      return self
+        .process_execution_store
+        .load_bytes_with(key, |_| Ok(()))
+        .await
+        .map(|_| ())
+        .map_err(|err| format!("Local process cache health check failed to read: {}", err));
+    }
+
+    // Use a fresh value, so that a stale sentinel from an interrupted check can't pass.
+    let sentinel = Bytes::from(format!(
+      "health check at {:?}",
+      SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+    ));
+    self
+      .process_execution_store
+      .replace_bytes(key, sentinel.clone(), false)
+      .await
+      .map_err(|err| format!("Local process cache health check failed to write: {}", err))?;
+    read(Some(sentinel)).await?;
+    self
+      .process_execution_store
+      .remove(key)
+      .await
+      .map_err(|err| format!("Local process cache health check failed to remove: {}", err))?;
+    read(None).await
+  }
+
+  ///
+  /// Returns the total time (in microseconds) spent waiting to begin write transactions on each
+  /// shard of the store, indexed by shard. Waits which are concentrated on a few shards suggest
+  /// that the store would benefit from more shards. Each wait is also recorded as
+  /// `ObservationMetric::LocalCacheShardWaitUs`.
+  ///
+  /// Only a ShardedLmdb store reports its waits: for other stores, this is empty.
+  ///
+  pub fn shard_wait_micros(&self) -> Vec<u64> {
+    self
+      .shard_wait_micros
+      .iter()
+      .map(|micros| micros.load(Ordering::Relaxed))
+      .collect()
+  }
+
+  ///
+  /// Zeroes the `counters` reported by `stats`, and returns their prior values, so that the
+  /// behavior of the cache can be measured for a particular window (such as a single build).
+  ///
+  pub fn reset_metrics(&self) -> LocalCacheCounters {
+    self.counters.reset()
+  }
+
+  ///
+  /// Returns statistics about the size of the cache.
+  ///
+  pub async fn stats(&self) -> Result<LocalCacheStats, String> {
+    let usage = self.usage().await?;
+    Ok(LocalCacheStats {
+      used_bytes: usage.bytes,
+      max_total_bytes: self.max_total_bytes,
+      fill_fraction: self
+        .max_total_bytes
+        .map(|max_total_bytes| usage.bytes as f64 / max_total_bytes as f64),
+      entries: usage.entries,
+      max_entries: self.max_entries,
+      counters: self.counters.snapshot(),
+    })
+  }
+
+  ///
+  /// Evicts entries (in the order decided by the EvictionPolicy) until the entries in the cache
+  /// total at most `target_bytes`, and returns their new total size.
+  ///
+  /// Pinned entries are only evicted once all unpinned entries have been. Immortal entries are
+  /// never evicted, so the returned size may exceed the target.
+  ///
+  pub async fn gc(&self, target_bytes: usize) -> Result<u64, String> {
+    Ok(self.gc_to(Some(target_bytes as u64), None).await?.bytes)
+  }
+
+  ///
+  /// Evicts entries (in the order decided by the EvictionPolicy) until the cache contains at most
+  /// `target_entries` entries, and returns the new number of entries.
+  ///
+  /// Pinned entries are only evicted once all unpinned entries have been. Immortal entries are
+  /// never evicted, so the returned number may exceed the target.
+  ///
+  pub async fn gc_entries(&self, target_entries: u64) -> Result<u64, String> {
+    Ok(self.gc_to(None, Some(target_entries)).await?.entries)
+  }
+
+  async fn gc_to(
+    &self,
+    target_bytes: Option<u64>,
+    target_entries: Option<u64>,
+  ) -> Result<CacheUsage, String> {
+    let pins = self.pinned().await?;
+    let mut entries = self
+      .process_execution_store
+      .all_entry_metadata(self.scan_parallelism)
+      .await?;
+    entries.retain(|entry| !is_reserved_key(entry.fingerprint));
+    let mut usage = CacheUsage {
+      bytes: entries.iter().map(|entry| entry.size_bytes as u64).sum(),
+      entries: entries.len() as u64,
+    };
+    // Immortal entries count toward the limits, but are never evicted.
+    entries.retain(|entry| !self.immortal_fingerprints.contains(&entry.fingerprint));
+    let hit_counts = if self.eviction_policy == EvictionPolicy::Lfu {
+      // Compare frequencies in fractions of a hit, so that decayed frequencies remain distinct.
+      self
+        .hit_frequencies()
+        .await?
+        .into_iter()
+        .map(|(fingerprint, hits)| (fingerprint, (hits * 1024.0) as u64))
+        .collect()
+    } else {
+      HashMap::new()
+    };
+    // Entries are leased when they are stored and when they are hit, so the entry with the
+    // earliest lease is the least recently used.
+    self
+      .eviction_policy
+      .sort_for_eviction(&mut entries, &pins, &hit_counts);
+    let mut evicted = Vec::new();
+    for entry in entries {
+      if target_bytes.map_or(true, |target| usage.bytes <= target)
+        && target_entries.map_or(true, |target| usage.entries <= target)
+      {
+        break;
+      }
+      self
+        .process_execution_store
+        .remove(entry.fingerprint)
+        .await?;
+      self.hit_counts.lock().remove(&entry.fingerprint);
+      self.verified_until.lock().remove(&entry.fingerprint);
+      usage.bytes -= entry.size_bytes as u64;
+      usage.entries -= 1;
+      evicted.push(entry.fingerprint);
+    }
+    if self.eviction_policy == EvictionPolicy::Lfu && !evicted.is_empty() {
+      self
+        .update_hit_frequencies(|frequencies| {
+          for fingerprint in &evicted {
+            frequencies.remove(fingerprint);
+          }
+        })
+        .await?;
+    }
+    let evicted = evicted.len();
+    debug!(
+      "Evicted {} entries from the local process cache, which now contains {} entries totalling {} bytes.",
+      evicted, usage.entries, usage.bytes
+    );
+    *self.usage.lock() = Some(usage);
+    self.observe_usage(usage);
+    Ok(usage)
+  }
+
+  ///
+  /// Computes the storage consumed by the entry for the given fingerprint, including the file
+  /// Store blobs which it references. Blobs which are referenced more than once (by the entry)
+  /// are only counted once, but blobs which are shared with other entries are counted in full.
+  ///
+  pub async fn entry_footprint(&self, fingerprint: Fingerprint) -> Result<EntryFootprint, String> {
+    let (entry_bytes, referenced) = self.referenced_blobs(fingerprint).await?;
+    Ok(EntryFootprint {
+      entry_bytes,
+      referenced_blob_bytes: referenced.iter().map(|digest| digest.size_bytes).sum(),
+    })
+  }
+
+  ///
+  /// Returns the total size of the distinct file Store blobs (stdout, stderr, output files, and
+  /// output Directory protos) which are referenced by the entries in the cache: that is, the
+  /// disk space which the GC of the file Store could reclaim if every entry were evicted (and
+  /// nothing else referenced the blobs). Entries whose blobs cannot be loaded are skipped.
+  ///
+  pub async fn referenced_blob_bytes(&self) -> Result<u64, String> {
+    let mut referenced = HashSet::new();
+    for (_, entry_referenced) in self.all_referenced_blobs().await? {
+      referenced.extend(entry_referenced);
+    }
+    let referenced_blob_bytes = referenced
+      .iter()
+      .map(|digest| digest.size_bytes as u64)
+      .sum();
+    *self.referenced_blob_usage.lock() = Some(referenced_blob_bytes);
+    Ok(referenced_blob_bytes)
+  }
+
+  ///
+  /// Evicts entries (in the order of the EvictionPolicy) until the distinct file Store blobs
+  /// which the remaining entries reference total at most `target_bytes`, and returns the number
+  /// of entries which were evicted. Pinned entries are evicted last, and immortal entries never.
+  ///
+  /// The blobs themselves are not removed: they are reclaimed by the GC of the file Store once
+  /// nothing references them.
+  ///
+  pub async fn gc_referenced_blobs(&self, target_bytes: u64) -> Result<u64, String> {
+    let all_referenced = self.all_referenced_blobs().await?;
+    let mut references: HashMap<Digest, usize> = HashMap::new();
+    for entry_referenced in all_referenced.values() {
+      for digest in entry_referenced {
+        *references.entry(*digest).or_insert(0) += 1;
+      }
+    }
+    let mut referenced_blob_bytes: u64 = references
+      .keys()
+      .map(|digest| digest.size_bytes as u64)
+      .sum();
+
+    let pins = self.pinned().await?;
+    let mut entries = self
+      .process_execution_store
+      .all_entry_metadata(self.scan_parallelism)
+      .await?;
+    entries.retain(|entry| {
+      all_referenced.contains_key(&entry.fingerprint)
+        && !self.immortal_fingerprints.contains(&entry.fingerprint)
+    });
+    let hit_counts = if self.eviction_policy == EvictionPolicy::Lfu {
+      self
+        .hit_frequencies()
+        .await?
+        .into_iter()
+        .map(|(fingerprint, hits)| (fingerprint, (hits * 1024.0) as u64))
+        .collect()
+    } else {
+      HashMap::new()
+    };
+    self
+      .eviction_policy
+      .sort_for_eviction(&mut entries, &pins, &hit_counts);
+    let mut evicted = 0;
+    for entry in entries {
+      if referenced_blob_bytes <= target_bytes {
+        break;
+      }
+      self.remove(entry.fingerprint).await?;
+      evicted += 1;
+      for digest in &all_referenced[&entry.fingerprint] {
+        let count = references.get_mut(digest).unwrap();
+        *count -= 1;
+        if *count == 0 {
+          referenced_blob_bytes -= digest.size_bytes as u64;
+        }
+      }
+    }
+    debug!(
+      "Evicted {} entries from the local process cache, which now references {} bytes of blobs.",
+      evicted, referenced_blob_bytes
+    );
+    *self.referenced_blob_usage.lock() = Some(referenced_blob_bytes);
+    Ok(evicted)
+  }
+
+  ///
+  /// Returns the blobs referenced by each entry in the cache (see `referenced_blobs`), skipping
+  /// entries whose blobs cannot be loaded.
+  ///
+  async fn all_referenced_blobs(&self) -> Result<HashMap<Fingerprint, HashSet<Digest>>, String> {
+    let fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint));
+    let mut all_referenced = HashMap::new();
+    for fingerprint in fingerprints {
+      match self.referenced_blobs(fingerprint).await {
+        Ok((_, referenced)) => {
+          all_referenced.insert(fingerprint, referenced);
+        }
+        Err(err) => debug!(
+          "Could not compute the blobs referenced by local cache entry {}: {}",
+          fingerprint, err
+        ),
+      }
+    }
+    Ok(all_referenced)
+  }
+
+  ///
+  /// Returns the size of the entry for the given fingerprint, and the distinct file Store blobs
+  /// which it references.
+  ///
+  async fn referenced_blobs(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Result<(usize, HashSet<Digest>), String> {
+    let (entry_bytes, entry) = self
+      .process_execution_store
+      .load_bytes_with(fingerprint, move |bytes| {
+        Ok((bytes.len(), CacheEntry::decode(bytes)?))
+      })
+      .await?
+      .ok_or_else(|| format!("No local cache entry exists for {}", fingerprint))?;
+    let entry = match self.resolve_response(entry).await? {
+      Ok(entry) => entry.into_stored_entry(fingerprint, self.compression_dictionary.as_deref())?,
+      // Without its response, the only blob which the entry is known to reference is the response.
+      Err(response_digest) => {
+        return Ok((entry_bytes, vec![response_digest].into_iter().collect()));
+      }
+    };
+
+    let mut referenced = HashSet::new();
+    for (digest, entry_type) in entry.referenced_digests()? {
+      if digest == EMPTY_DIGEST {
+        continue;
+      }
+      match entry_type {
+        EntryType::File => {
+          referenced.insert(digest);
+        }
+        EntryType::Directory => referenced.extend(
+          self
+            .file_store
+            .expand_directory(digest)
+            .await?
+            .keys()
+            .copied(),
+        ),
+      }
+    }
+    Ok((entry_bytes, referenced))
+  }
+
+  ///
+  /// Returns the (at most) `n` entries with the largest `entry_footprint`s, largest first, along
+  /// with their total footprints in bytes. Entries whose footprint cannot be computed (for
+  /// example, because they reference missing blobs) are skipped.
+  ///
+  /// Only `n` footprints are held in memory at once, regardless of the size of the cache.
+  ///
+  pub async fn largest_entries(&self, n: usize) -> Result<Vec<(Fingerprint, u64)>, String> {
+    let fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint));
+    let mut footprints = futures::stream::iter(
+      fingerprints
+        .map(|fingerprint| async move { (fingerprint, self.entry_footprint(fingerprint).await) }),
+    )
+    .buffer_unordered(self.scan_parallelism);
+
+    // A min-heap of the largest footprints seen so far.
+    let mut largest = BinaryHeap::with_capacity(n + 1);
+    while let Some((fingerprint, footprint)) = footprints.next().await {
+      let total_bytes = match footprint {
+        Ok(footprint) => footprint.total_bytes() as u64,
+        Err(err) => {
+          debug!("Skipping local cache entry {}: {}", fingerprint, err);
+          continue;
+        }
+      };
+      largest.push(Reverse((total_bytes, fingerprint)));
+      if largest.len() > n {
+        largest.pop();
+      }
+    }
+    Ok(
+      largest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((total_bytes, fingerprint))| (fingerprint, total_bytes))
+        .collect(),
+    )
+  }
+
+  ///
+  /// Checks every entry in the cache for references to blobs which are missing from the file
+  /// Store (for example, after an aggressive garbage collection of the file Store).
+  ///
+  /// NB: If the file Store has a remote, missing blobs are fetched from it where possible, and
+  /// are only reported if they are missing from both.
+  ///
+  pub async fn audit(&self) -> Result<AuditReport, String> {
+    let fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint))
+      .collect::<Vec<_>>();
+    self.audit_fingerprints(fingerprints).await
+  }
+
+  ///
+  /// Like `audit`, but checks only a random `fraction` (between 0.0 and 1.0) of the entries, so
+  /// that a scheduled job can cover a large cache over many invocations at a bounded cost per
+  /// invocation. Each entry is sampled independently, so the number checked (reported as
+  /// `entries_checked`) varies around `fraction` of the entries.
+  ///
+  /// If a `seed` is given, the same entries are sampled from the same cache contents.
+  ///
+  pub async fn verify_sample(
+    &self,
+    fraction: f64,
+    seed: Option<u64>,
+  ) -> Result<AuditReport, String> {
+    if !(0.0..=1.0).contains(&fraction) {
+      return Err(format!(
+        "The fraction of local cache entries to verify must be between 0 and 1: got {}",
+        fraction
+      ));
+    }
+    let mut fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint))
+      .collect::<Vec<_>>();
+    // Sampling is only reproducible if the entries are visited in a stable order.
+    fingerprints.sort();
+    let mut rng = match seed {
+      Some(seed) => StdRng::seed_from_u64(seed),
+      None => StdRng::from_rng(thread_rng()).map_err(|err| err.to_string())?,
+    };
+    fingerprints.retain(|_| rng.gen_bool(fraction));
+    self.audit_fingerprints(fingerprints).await
+  }
+
+  async fn audit_fingerprints(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<AuditReport, String> {
+    let results = futures::stream::iter(fingerprints.into_iter().map(|fingerprint| async move {
+      let missing = match self.load_stored_entry(fingerprint).await? {
+        // The entry was removed concurrently.
+        None => return Ok(None),
+        Some(LoadedEntry::Decoded(entry)) => Some(self.missing_digests(&entry).await?),
+        Some(LoadedEntry::ResponseMissing(response_digest)) => Some(vec![response_digest]),
+        Some(LoadedEntry::Undecodable(err)) => {
+          debug!("Local cache entry {} is undecodable: {}", fingerprint, err);
+          None
+        }
+      };
+      Ok::<_, String>(Some((fingerprint, missing)))
+    }))
+    .buffer_unordered(self.scan_parallelism)
+    .try_collect::<Vec<Option<(Fingerprint, Option<Vec<Digest>>)>>>()
+    .await?;
+
+    let mut report = AuditReport::default();
+    for (fingerprint, missing) in results.into_iter().flatten() {
+      report.entries_checked += 1;
+      match missing {
+        Some(missing) if missing.is_empty() => {}
+        Some(missing) => report.dangling.push((fingerprint, missing)),
+        None => report.undecodable.push(fingerprint),
+      }
+    }
+    report.dangling.sort_by_key(|(fingerprint, _)| *fingerprint);
+    report.undecodable.sort();
+    Ok(report)
+  }
+
+  ///
+  /// Scans the cache and summarizes its composition: the number and size of its entries, and
+  /// their exit codes, platforms and ages.
+  ///
+  /// Unlike `audit`, this only decodes each entry (loading its ExecuteResponse if it is shared),
+  /// and does not check the blobs it references.
+  ///
+  pub async fn describe_all_summary(&self) -> Result<CacheSummary, String> {
+    let entries = self
+      .process_execution_store
+      .all_entry_metadata(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|entry| !is_reserved_key(entry.fingerprint));
+    let results = futures::stream::iter(entries.map(|metadata| async move {
+      let maybe_decoded = match self.load_stored_entry(metadata.fingerprint).await? {
+        None => None,
+        Some(LoadedEntry::Decoded(entry)) => {
+          let (platform, created_at) = (entry.platform, entry.created_at);
+          Some(
+            entry
+              .execute_response
+              .result
+              .ok_or_else(|| "Cache entry has no ActionResult".to_owned())
+              .map(|action_result| (action_result.exit_code, platform, created_at)),
+          )
+        }
+        Some(LoadedEntry::Undecodable(err)) => Some(Err(err)),
+        Some(LoadedEntry::ResponseMissing(response_digest)) => Some(Err(format!(
+          "ExecuteResponse {:?} is missing from the file Store",
+          response_digest
+        ))),
+      };
+      // If the entry was removed concurrently, it is not counted.
+      Ok::<_, String>(maybe_decoded.map(|decoded| (metadata.size_bytes, decoded)))
+    }))
+    .buffer_unordered(self.scan_parallelism)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+    let now = self.now();
+    let mut summary = CacheSummary::default();
+    for (size_bytes, decoded) in results.into_iter().flatten() {
+      summary.entries += 1;
+      summary.total_bytes += size_bytes as u64;
+      let (exit_code, platform, created_at) = match decoded {
+        Ok(decoded) => decoded,
+        Err(err) => {
+          debug!("Local cache entry is undecodable: {}", err);
+          summary.undecodable += 1;
+          continue;
+        }
+      };
+      *summary.exit_codes.entry(exit_code).or_insert(0) += 1;
+      *summary.platforms.entry(platform).or_insert(0) += 1;
+      // Entries created "in the future" (due to clock skew) are counted as new.
+      let age = now.duration_since(created_at).unwrap_or_default();
+      let bucket = AGE_HISTOGRAM_BOUNDS
+        .iter()
+        .position(|bound| age < *bound)
+        .unwrap_or(AGE_HISTOGRAM_BOUNDS.len());
+      summary.age_histogram[bucket] += 1;
+    }
+    Ok(summary)
+  }
+
+  ///
+  /// Decodes the entry for the given fingerprint and checks that all of the blobs that it
+  /// references are present, without populating a result from it. This is a cheaper way than
+  /// `lookup` to tell whether an entry is a usable hit, rather than merely present (as
+  /// `contains_many` does).
+  ///
+  /// NB: As with `audit`, if the file Store has a remote, missing blobs are fetched from it where
+  /// possible.
+  ///
+  pub async fn validate(&self, fingerprint: Fingerprint) -> Result<ValidationStatus, String> {
+    let entry = match self.load_stored_entry(fingerprint).await? {
+      None => return Ok(ValidationStatus::Missing),
+      Some(LoadedEntry::Undecodable(err)) => return Ok(ValidationStatus::Undecodable(err)),
+      Some(LoadedEntry::ResponseMissing(response_digest)) => {
+        return Ok(ValidationStatus::Dangling(vec![response_digest]))
+      }
+      Some(LoadedEntry::Decoded(entry)) => entry,
+    };
+    if self.is_expired(fingerprint, entry.created_at, entry.expires_at) {
+      return Ok(ValidationStatus::Expired);
+    }
+    let compatible = match entry.platform {
+      Some(platform) => self.is_compatible(platform)?,
+      None => !self.strict_platform,
+    };
+    if !compatible {
+      return Ok(ValidationStatus::PlatformMismatch);
+    }
+    let missing = self.missing_digests(&entry).await?;
+    if missing.is_empty() {
+      if let Some(verification_lifetime) = self.verification_lifetime {
+        self
+          .verified_until
+          .lock()
+          .insert(fingerprint, self.now() + verification_lifetime);
+      }
+      Ok(ValidationStatus::Usable)
+    } else {
+      Ok(ValidationStatus::Dangling(missing))
+    }
+  }
+
+  ///
+  /// Returns the digests of all blobs referenced by the given entry which are missing from the
+  /// file Store. The contents of missing Directories cannot be known, so are not reported.
+  ///
+  async fn missing_digests(&self, entry: &StoredEntry) -> Result<Vec<Digest>, String> {
+    let mut missing = Vec::new();
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    for (digest, entry_type) in entry.referenced_digests()? {
+      match entry_type {
+        EntryType::File => files.push(digest),
+        EntryType::Directory => directories.push(digest),
+      }
+    }
+    while let Some(digest) = directories.pop() {
+      if let Some(directory) = self.file_store.load_directory(digest).await? {
+        for file in &directory.files {
+          files.push(require_digest(file.digest.as_ref())?);
+        }
+        for subdirectory in &directory.directories {
+          directories.push(require_digest(subdirectory.digest.as_ref())?);
+        }
+      } else {
+        missing.push(digest);
+      }
+    }
+    for digest in non_empty_digests(&files) {
+      if self
+        .file_store
+        .load_file_bytes_with(digest, |_| ())
+        .await?
+        .is_none()
+      {
+        missing.push(digest);
+      }
+    }
+    missing.sort_by_key(|digest| digest.hash);
+    missing.dedup();
+    Ok(missing)
+  }
+
+  ///
+  /// Removes the entry for the given fingerprint, returning whether it existed.
+  ///
+  pub async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    let entry_bytes = self
+      .process_execution_store
+      .load_bytes_with(fingerprint, |bytes| Ok(bytes.len() as u64))
+      .await?;
+    let removed = self.process_execution_store.remove(fingerprint).await?;
+    self.hit_counts.lock().remove(&fingerprint);
+    self.verified_until.lock().remove(&fingerprint);
+    if let (true, Some(entry_bytes)) = (removed, entry_bytes) {
+      if let Some(ref mut usage) = *self.usage.lock() {
+        usage.bytes = usage.bytes.saturating_sub(entry_bytes);
+        usage.entries = usage.entries.saturating_sub(1);
+      }
+    }
+    Ok(removed)
+  }
+
+  ///
+  /// Pins the entry for the given fingerprint, so that `gc` will only evict it as a last resort.
+  ///
+  /// Pins are persisted alongside the entries, and may be added before the entry itself exists.
+  ///
+  pub async fn pin(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    self
+      .update_pins(|pins| {
+        pins.insert(fingerprint);
+      })
+      .await
+  }
+
+  ///
+  /// Removes the pin (if any) for the given fingerprint.
+  ///
+  pub async fn unpin(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    self
+      .update_pins(|pins| {
+        pins.remove(&fingerprint);
+      })
+      .await
+  }
+
+  ///
+  /// Returns the set of pinned fingerprints.
+  ///
+  pub async fn pinned(&self) -> Result<BTreeSet<Fingerprint>, String> {
+    Ok(
+      self
+        .process_execution_store
+        .load_bytes_with(pinned_fingerprints_key(), |bytes| {
+          bincode::deserialize(bytes)
+            .map_err(|err| format!("Could not deserialize pinned fingerprints: {}", err))
+        })
+        .await?
+        .unwrap_or_default(),
+    )
+  }
+
+  ///
+  /// Returns the decayed hit frequency of each entry which has been hit (see
+  /// `EvictionPolicy::Lfu`), including hits which have not yet been persisted.
+  ///
+  pub async fn hit_frequencies(&self) -> Result<HashMap<Fingerprint, f64>, String> {
+    let now_secs = self.now_secs();
+    let frequencies = self.update_hit_frequencies(|_| {}).await?;
+    Ok(
+      frequencies
+        .into_iter()
+        .map(|(fingerprint, frequency)| {
+          (fingerprint, frequency.decayed(now_secs, self.lfu_half_life))
+        })
+        .collect(),
+    )
+  }
+
+  ///
+  /// Merges the hit frequencies recorded by this CommandRunner into those persisted in the cache,
+  /// so that they survive restarts and are shared with other CommandRunners for the same store.
+  /// This happens whenever the cache is garbage collected, but callers may also persist them
+  /// explicitly (before shutting down, for example).
+  ///
+  pub async fn persist_hit_frequencies(&self) -> Result<(), String> {
+    self.update_hit_frequencies(|_| {}).await.map(|_| ())
+  }
+
+  async fn update_hit_frequencies<F: FnOnce(&mut HashMap<Fingerprint, HitFrequency>)>(
+    &self,
+    f: F,
+  ) -> Result<HashMap<Fingerprint, HitFrequency>, String> {
+    // Serialize read-modify-write cycles so that concurrent updates are not lost.
+    let _guard = self.hit_frequencies_lock.lock().await;
+    let mut frequencies: HashMap<Fingerprint, HitFrequency> = self
+      .process_execution_store
+      .load_bytes_with(hit_frequencies_key(), |bytes| {
+        bincode::deserialize(bytes)
+          .map_err(|err| format!("Could not deserialize hit frequencies: {}", err))
+      })
+      .await?
+      .unwrap_or_default();
+    let now_secs = self.now_secs();
+    // A read-only cache cannot persist its hits, so it retains them.
+    let unpersisted = if self.read_only {
+      self.hit_counts.lock().clone()
+    } else {
+      std::mem::take(&mut *self.hit_counts.lock())
+    };
+    for (fingerprint, frequency) in unpersisted {
+      let persisted = frequencies.entry(fingerprint).or_default();
+      *persisted = persisted.merge(frequency, now_secs, self.lfu_half_life);
+    }
+    f(&mut frequencies);
+    frequencies.retain(|_, frequency| {
+      frequency.decayed(now_secs, self.lfu_half_life) >= HitFrequency::MIN_HITS
+    });
+    if !self.read_only {
+      let bytes = bincode::serialize(&frequencies)
+        .map_err(|err| format!("Error serializing hit frequencies: {}", err))?;
+      self
+        .process_execution_store
+        .replace_bytes(hit_frequencies_key(), Bytes::from(bytes), false)
+        .await?;
+    }
+    Ok(frequencies)
+  }
+
+  async fn update_pins<F: FnOnce(&mut BTreeSet<Fingerprint>)>(&self, f: F) -> Result<(), String> {
+    // Serialize read-modify-write cycles so that concurrent updates are not lost.
+    let _guard = self.pins_lock.lock().await;
+    let mut pins = self.pinned().await?;
+    f(&mut pins);
+    let bytes = bincode::serialize(&pins)
+      .map_err(|err| format!("Error serializing pinned fingerprints: {}", err))?;
+    self
+      .process_execution_store
+      .replace_bytes(pinned_fingerprints_key(), Bytes::from(bytes), false)
+      .await
+  }
+
+  fn observe_usage(&self, usage: CacheUsage) {
+    if let Some(max_total_bytes) = self.max_total_bytes {
+      self
+        .fill_watermark
+        .observe(usage.bytes as f64 / max_total_bytes as f64);
+    }
+  }
+
+  async fn usage(&self) -> Result<CacheUsage, String> {
+    if let Some(usage) = *self.usage.lock() {
+      return Ok(usage);
+    }
+    // Like the entry count, the byte count excludes the reserved keys (pins, hit frequencies and
+    // the health check), which are never evicted.
+    let entries = self
+      .process_execution_store
+      .all_entry_metadata(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|entry| !is_reserved_key(entry.fingerprint))
+      .collect::<Vec<_>>();
+    let usage = CacheUsage {
+      bytes: entries.iter().map(|entry| entry.size_bytes as u64).sum(),
+      entries: entries.len() as u64,
+    };
+    *self.usage.lock() = Some(usage);
+    Ok(usage)
+  }
+
+  ///
+  /// Re-keys the entries in the cache after a change to the scheme used to compute fingerprints
+  /// (e.g. a change to `crate::digest`), rather than leaving the entire cache unreachable.
+  ///
+  /// Streams all entries, calling `f` to compute the new fingerprint for each. Entries for which
+  /// `f` returns a new fingerprint are moved to it, preserving their creation time. Entries for
+  /// which `f` returns None (or which cannot be decoded) are left in place to be garbage
+  /// collected. Returns the number of entries which were moved.
+  ///
+  pub async fn migrate<F: Fn(&StoredEntry) -> Option<Fingerprint>>(
+    &self,
+    f: F,
+  ) -> Result<usize, String> {
+    let mut migrated = 0;
+    let mut moved_pins = Vec::new();
+    let pins = self.pinned().await?;
+    for fingerprint in self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+    {
+      if is_reserved_key(fingerprint) {
+        continue;
+      }
+      let bytes = match self.process_execution_store.load_bytes(fingerprint).await? {
+        Some(bytes) => bytes,
+        // The entry was removed concurrently.
+        None => continue,
+      };
+      let entry = match CacheEntry::decode(&bytes) {
+        Ok(entry) => self
+          .resolve_response(entry)
+          .await?
+          .map_err(|response_digest| {
+            format!(
+              "ExecuteResponse {:?} is missing from the file Store",
+              response_digest
+            )
+          }),
+        Err(err) => Err(err),
+      }
+      .and_then(|e| e.into_stored_entry(fingerprint, self.compression_dictionary.as_deref()));
+      let entry = match entry {
+        Ok(entry) => entry,
+        Err(err) => {
+          debug!("Not migrating local cache entry {}: {}", fingerprint, err);
+          continue;
+        }
+      };
+      match f(&entry) {
+        Some(new_fingerprint) if new_fingerprint != fingerprint => {
+          self
+            .process_execution_store
+            .store_bytes_if_absent(new_fingerprint, bytes, true)
+            .await?;
+          self.process_execution_store.remove(fingerprint).await?;
+          if pins.contains(&fingerprint) {
+            moved_pins.push((fingerprint, new_fingerprint));
+          }
+          migrated += 1;
+        }
+        _ => {}
+      }
+    }
+    if !moved_pins.is_empty() {
+      self
+        .update_pins(|pins| {
+          for (old, new) in moved_pins {
+            pins.remove(&old);
+            pins.insert(new);
+          }
+        })
+        .await?;
+    }
+    Ok(migrated)
+  }
+
+  ///
+  /// Recovers entries which are missing from the cache from the sidecars that `store` recorded in
+  /// the file Store (see `LocalCacheOptions::index_sidecars`), for example after the cache was lost
+  /// but the file Store survived. Returns the number of entries which were recovered.
+  ///
+  /// This is best-effort: sidecars which cannot be decoded are skipped, the most recently created
+  /// entry wins when there are several for a fingerprint, and since sidecars outlive the entries
+  /// that they record, entries which were removed (or evicted) may be recovered too.
+  ///
+  pub async fn rebuild_index(&self) -> Result<u64, String> {
+    if self.read_only {
+      return Err("The local process cache is read-only.".to_owned());
+    }
+    let header_len = INDEX_SIDECAR_MAGIC.len() + FINGERPRINT_SIZE;
+    let file_store = self.file_store.clone();
+    let candidates = self
+      .process_execution_store
+      .executor()
+      .spawn_blocking(move || file_store.all_local_digests(EntryType::File))
+      .await?
+      .into_iter()
+      .filter(|digest| digest.size_bytes > header_len);
+
+    let mut newest: HashMap<Fingerprint, (u64, Bytes)> = HashMap::new();
+    for digest in candidates {
+      let sidecar = self
+        .file_store
+        .load_file_bytes_with(digest, move |bytes| {
+          if !bytes.starts_with(INDEX_SIDECAR_MAGIC) {
+            return None;
+          }
+          let fingerprint =
+            Fingerprint::from_bytes_unsafe(&bytes[INDEX_SIDECAR_MAGIC.len()..header_len]);
+          let entry_bytes = &bytes[header_len..];
+          match CacheEntry::decode(entry_bytes) {
+            Ok(entry) => Some((
+              fingerprint,
+              entry.created_at_secs,
+              Bytes::copy_from_slice(entry_bytes),
+            )),
+            Err(err) => {
+              debug!(
+                "Not recovering local cache entry {} from an undecodable sidecar: {}",
+                fingerprint, err
+              );
+              None
+            }
+          }
+        })
+        .await?
+        .flatten();
+      if let Some((fingerprint, created_at_secs, entry_bytes)) = sidecar {
+        match newest.get(&fingerprint) {
+          Some((newest_created_at_secs, _)) if *newest_created_at_secs >= created_at_secs => {}
+          _ => {
+            newest.insert(fingerprint, (created_at_secs, entry_bytes));
+          }
+        }
+      }
+    }
+
+    let mut recovered = 0;
+    let mut recovered_bytes = 0;
+    for (fingerprint, (_, entry_bytes)) in newest {
+      let entry_len = entry_bytes.len() as u64;
+      if self
+        .process_execution_store
+        .store_bytes_if_absent(fingerprint, entry_bytes, true)
+        .await?
+      {
+        recovered += 1;
+        recovered_bytes += entry_len;
+      }
+    }
+    if recovered > 0 {
+      self
+        .record_stored(
+          CacheUsage {
+            bytes: recovered_bytes,
+            entries: recovered,
+          },
+          CacheUsage::default(),
+        )
+        .await?;
+    }
+    Ok(recovered)
+  }
+
+  fn record_access(&self, fingerprint: Fingerprint, hit: bool, entry_bytes: u64) {
+    if let Some(ref access_log) = self.access_log {
+      access_log.write(format!(
+        "{}\t{}\t{}\t{}\n",
+        self
+          .now()
+          .duration_since(UNIX_EPOCH)
+          .unwrap_or_default()
+          .as_secs(),
+        fingerprint,
+        if hit { "hit" } else { "miss" },
+        entry_bytes
+      ));
+    }
+  }
+
+  fn record_decision(
+    &self,
+    workunit: &mut RunningWorkunit,
+    fingerprint: Fingerprint,
+    result: &FallibleProcessResultWithPlatform,
+    entry_bytes: u64,
+    time_saved_ms: Option<u64>,
+  ) {
+    let decision_log = if let Some(ref decision_log) = self.decision_log {
+      decision_log
+    } else {
+      return;
+    };
+    let record = DecisionRecord {
+      fingerprint: fingerprint.to_hex(),
+      decision: match result.metadata.source {
+        ProcessResultSource::HitLocally | ProcessResultSource::HitRemotely => "hit",
+        ProcessResultSource::RanLocally | ProcessResultSource::RanRemotely => "miss",
+      },
+      exit_code: result.exit_code,
+      entry_bytes,
+      time_saved_ms,
+      source: match result.metadata.source {
+        ProcessResultSource::RanLocally => "ran_locally",
+        ProcessResultSource::RanRemotely => "ran_remotely",
+        ProcessResultSource::HitLocally => "hit_locally",
+        ProcessResultSource::HitRemotely => "hit_remotely",
+      },
+    };
+    let written = serde_json::to_string(&record)
+      .map(|line| decision_log.write(line + "\n"))
+      .unwrap_or(false);
+    if !written {
+      workunit.increment_counter(Metric::LocalCacheDecisionLogDropped, 1);
+    }
+  }
+
+  fn now(&self) -> SystemTime {
+    (self.clock)()
+  }
+
+  fn now_secs(&self) -> u64 {
+    self
+      .now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs()
+  }
+
+  fn is_expired(
+    &self,
+    fingerprint: Fingerprint,
+    created_at: SystemTime,
+    expires_at: Option<SystemTime>,
+  ) -> bool {
+    let now = self.now();
+    !self.immortal_fingerprints.contains(&fingerprint)
+      && (self.ttl.map_or(false, |ttl| created_at + ttl <= now)
+        || expires_at.map_or(false, |expires_at| expires_at <= now))
+  }
+
+  ///
+  /// Narrows a request with variants for multiple platforms to the variant which the underlying
+  /// CommandRunner would run, since the variants for other platforms cannot affect the result.
+  ///
+  fn narrowed<'a>(&self, req: &'a MultiPlatformProcess) -> Cow<'a, MultiPlatformProcess> {
+    if req.0.len() <= 1 {
+      return Cow::Borrowed(req);
+    }
+    match self.extract_compatible_request(req) {
+      Some(process) => Cow::Owned(process.into()),
+      None => Cow::Borrowed(req),
+    }
+  }
+
+  ///
+  /// The MaterializePolicy for a lookup of the given request: the configured policy, less its
+  /// requirements on the output directory if the request (or else the default) says not to
+  /// materialize outputs.
+  ///
+  fn materialize_policy_for(&self, req: &MultiPlatformProcess) -> MaterializePolicy {
+    match req
+      .0
+      .values()
+      .find_map(|process| process.materialize_cached_outputs)
+    {
+      Some(true) => self.materialize_policy,
+      Some(false) => self.materialize_policy.without_outputs(),
+      None => self.default_materialize_policy(),
+    }
+  }
+
+  fn default_materialize_policy(&self) -> MaterializePolicy {
+    if self.default_materialize_outputs {
+      self.materialize_policy
+    } else {
+      self.materialize_policy.without_outputs()
+    }
+  }
+
+  fn is_compatible(&self, stored_platform: Platform) -> Result<bool, String> {
+    match self.platform_compatibility_fn {
+      Some(ref is_compatible) => Ok(is_compatible(stored_platform, Platform::current()?)),
+      None => Ok(true),
+    }
+  }
 }
 
-#[derive(Clone)]
-pub struct CommandRunner {
-  underlying: Arc<dyn crate::CommandRunner>,
-  process_execution_store: ShardedLmdb,
-  file_store: Store,
-  metadata: ProcessMetadata,
+///
+/// The introspection and maintenance operations of a local process cache, for integrators to
+/// expose over whichever transport (gRPC, REST, a CLI, ...) they like.
+///
+/// This trait is object safe, so a transport can hold an `Arc<dyn CacheAdmin>` without depending
+/// on the configuration of the cache. Each method corresponds to an inherent method of
+/// `CommandRunner`, whose docs describe it in more detail.
+///
+#[async_trait]
+pub trait CacheAdmin: Send + Sync {
+  ///
+  /// Returns statistics about the size of the cache, and the outcomes of the requests it served.
+  ///
+  async fn stats(&self) -> Result<LocalCacheStats, String>;
+
+  ///
+  /// Loads and decodes the entry for the given fingerprint, regardless of whether it has expired.
+  ///
+  async fn describe(&self, fingerprint: Fingerprint) -> Result<Option<StoredEntry>, String>;
+
+  ///
+  /// Summarizes the composition of the entire cache.
+  ///
+  async fn describe_all(&self) -> Result<CacheSummary, String>;
+
+  ///
+  /// Returns the sorted fingerprints of all entries, or if `since` is given, of the entries which
+  /// were created or accessed at or after it.
+  ///
+  async fn iter_fingerprints(&self, since: Option<SystemTime>) -> Result<Vec<Fingerprint>, String>;
+
+  ///
+  /// Checks whether the entry for the given fingerprint would be a usable hit.
+  ///
+  async fn validate(&self, fingerprint: Fingerprint) -> Result<ValidationStatus, String>;
+
+  ///
+  /// Checks all entries (or, if `sample_fraction` is given, a random fraction of them) for
+  /// references to blobs which are missing from the file Store.
+  ///
+  async fn audit(&self, sample_fraction: Option<f64>) -> Result<AuditReport, String>;
+
+  ///
+  /// Removes the entry for the given fingerprint, and returns whether it existed.
+  ///
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String>;
+
+  ///
+  /// Removes all entries whose fingerprints start with the given bytes (or all entries, for an
+  /// empty prefix), and returns how many were removed.
+  ///
+  async fn purge(&self, prefix: &[u8]) -> Result<u64, String>;
+
+  ///
+  /// Evicts entries until the cache totals at most `target_bytes`, and returns its new size.
+  ///
+  async fn gc(&self, target_bytes: u64) -> Result<u64, String>;
+
+  ///
+  /// Evicts entries until the cache holds at most `target_entries`, and returns the new number.
+  ///
+  async fn gc_entries(&self, target_entries: u64) -> Result<u64, String>;
+
+  ///
+  /// Returns the names of the named stores (see `LocalCacheOptions::named_stores`), sorted.
+  ///
+  fn named_store_names(&self) -> Vec<String>;
+
+  ///
+  /// Returns the admin surface of the named store with the given name, if there is one.
+  ///
+  fn named_store_admin(&self, name: &str) -> Option<&dyn CacheAdmin>;
 }
 
-impl CommandRunner {
-  pub fn new(
-    underlying: Arc<dyn crate::CommandRunner>,
-    process_execution_store: ShardedLmdb,
-    file_store: Store,
-    metadata: ProcessMetadata,
-  ) -> CommandRunner {
-    CommandRunner {
-      underlying,
-      process_execution_store,
-      file_store,
-      metadata,
+#[async_trait]
+impl CacheAdmin for CommandRunner {
+  async fn stats(&self) -> Result<LocalCacheStats, String> {
+    CommandRunner::stats(self).await
+  }
+
+  async fn describe(&self, fingerprint: Fingerprint) -> Result<Option<StoredEntry>, String> {
+    self.load_entry(fingerprint).await
+  }
+
+  async fn describe_all(&self) -> Result<CacheSummary, String> {
+    self.describe_all_summary().await
+  }
+
+  async fn iter_fingerprints(&self, since: Option<SystemTime>) -> Result<Vec<Fingerprint>, String> {
+    if let Some(since) = since {
+      return self.iter_fingerprints_since(since).await;
+    }
+    let mut fingerprints = self
+      .process_execution_store
+      .all_fingerprints(self.scan_parallelism)
+      .await?
+      .into_iter()
+      .filter(|fingerprint| !is_reserved_key(*fingerprint))
+      .collect::<Vec<_>>();
+    fingerprints.sort();
+    Ok(fingerprints)
+  }
+
+  async fn validate(&self, fingerprint: Fingerprint) -> Result<ValidationStatus, String> {
+    CommandRunner::validate(self, fingerprint).await
+  }
+
+  async fn audit(&self, sample_fraction: Option<f64>) -> Result<AuditReport, String> {
+    match sample_fraction {
+      Some(fraction) => self.verify_sample(fraction, None).await,
+      None => CommandRunner::audit(self).await,
     }
   }
+
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    CommandRunner::remove(self, fingerprint).await
+  }
+
+  async fn purge(&self, prefix: &[u8]) -> Result<u64, String> {
+    self.purge_prefix(prefix).await
+  }
+
+  async fn gc(&self, target_bytes: u64) -> Result<u64, String> {
+    Ok(self.gc_to(Some(target_bytes), None).await?.bytes)
+  }
+
+  async fn gc_entries(&self, target_entries: u64) -> Result<u64, String> {
+    CommandRunner::gc_entries(self, target_entries).await
+  }
+
+  fn named_store_names(&self) -> Vec<String> {
+    let mut names = self.named_stores.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names
+  }
+
+  fn named_store_admin(&self, name: &str) -> Option<&dyn CacheAdmin> {
+    self
+      .named_store(name)
+      .map(|runner| runner as &dyn CacheAdmin)
+  }
 }
 
 #[async_trait]
@@ -57,209 +3911,1658 @@ impl crate::CommandRunner for CommandRunner {
     self.underlying.extract_compatible_request(req)
   }
 
+  async fn shutdown(&self) -> Result<(), String> {
+    self.flush_deferred_writes().await?;
+    self.underlying.shutdown().await
+  }
+
   async fn run(
     &self,
     context: Context,
     workunit: &mut RunningWorkunit,
     req: MultiPlatformProcess,
   ) -> Result<FallibleProcessResultWithPlatform, String> {
-    let cache_lookup_start = Instant::now();
-    let write_failures_to_cache = req
+    // Skip even computing the key of a process which must never be cached.
+    if req
       .0
       .values()
-      .any(|process| process.cache_scope == ProcessCacheScope::Always);
-    let digest = crate::digest(req.clone(), &self.metadata);
-    let key = digest.hash;
+      .any(|process| context.cache_scope(process) == ProcessCacheScope::Never)
+    {
+      return self.underlying.run(context, workunit, req).await;
+    }
+    // The results of non-hermetic processes may differ between machines, so are never cached.
+    if req.0.values().any(|process| !process.hermetic) {
+      workunit.increment_counter(Metric::LocalCacheSkippedNonHermetic, 1);
+      return self.underlying.run(context, workunit, req).await;
+    }
+    let selected = self.select_store(&req);
+    if !std::ptr::eq(selected, self) {
+      return crate::CommandRunner::run(selected, context, workunit, req).await;
+    }
+
+    // An override to a scope which is never persisted bypasses the cache entirely.
+    let bypass_for_override = matches!(
+      context.cache_scope_override,
+      Some(
+        ProcessCacheScope::PerRestartAlways
+          | ProcessCacheScope::PerRestartSuccessful
+          | ProcessCacheScope::PerSession
+      )
+    );
+    let breaker_permit = if bypass_for_override {
+      None
+    } else {
+      self.circuit_breaker.allow_request()
+    };
+    let breaker_permit = if let Some(ref breaker_permit) = breaker_permit {
+      breaker_permit
+    } else {
+      return self.underlying.run(context, workunit, req).await;
+    };
+
+    // Only the variant which will actually run is keyed, so a result for it satisfies requests
+    // which differ only in their (irrelevant) variants for other platforms.
+    let narrowed = match self.narrowed(&req) {
+      Cow::Owned(narrowed) => Some(narrowed),
+      Cow::Borrowed(_) => None,
+    };
+    let req = narrowed.unwrap_or(req);
 
+    let cache_lookup_start = Instant::now();
+    // Whether failures are cached is decided by the scope of the variant which will actually
+    // run, rather than by any variant of the request, so that an `Always` variant for another
+    // platform does not cause failures of this one to be cached.
+    let write_failures_to_cache = self
+      .extract_compatible_request(&req)
+      .map_or(false, |process| {
+        context.cache_scope(&process) == ProcessCacheScope::Always
+      });
+    let expires_at = self
+      .extract_compatible_request(&req)
+      .and_then(|process| process.expires_at);
+    let key = self.fingerprint(&req);
+    let input_checksum = input_checksum(&req);
+    let semantic_key = self.semantic_fingerprint(&req);
+    let materialize_policy = self.materialize_policy_for(&req);
+
+    // When debug logging is enabled, attach the key to the metadata of the cache workunits, so
+    // that a process can be correlated with its key in the workunit viewer.
+    let key_metadata = if log::log_enabled!(log::Level::Debug) {
+      vec![(
+        "cache_key".to_owned(),
+        UserMetadataItem::ImmediateString(key.to_hex()),
+      )]
+    } else {
+      vec![]
+    };
     let context2 = context.clone();
-    let cache_read_result = in_workunit!(
+    let cache_read = in_workunit!(
       context.workunit_store.clone(),
       "local_cache_read".to_owned(),
       WorkunitMetadata {
         level: Level::Trace,
         desc: Some(format!("Local cache lookup: {}", req.user_facing_name())),
+        user_metadata: key_metadata.clone(),
         ..WorkunitMetadata::default()
       },
       |workunit| async move {
         workunit.increment_counter(Metric::LocalCacheRequests, 1);
 
-        match self.lookup(key).await {
-          Ok(Some(result)) if result.exit_code == 0 || write_failures_to_cache => {
+        let mut lookup_result = self
+          .lookup_inner(
+            key,
+            materialize_policy,
+            Duration::ZERO,
+            Some(input_checksum),
+            Some(&mut *workunit),
+          )
+          .await;
+        let exact_miss = matches!(lookup_result, Ok(Err(UncachedReason::Miss)));
+        if let (true, Some(semantic_key)) = (exact_miss, semantic_key) {
+          lookup_result = self
+            .lookup_inner(
+              semantic_key,
+              materialize_policy,
+              Duration::ZERO,
+              None,
+              Some(&mut *workunit),
+            )
+            .await;
+        }
+        match lookup_result {
+          Ok(Ok((result, entry_bytes))) if result.exit_code == 0 || write_failures_to_cache => {
+            breaker_permit.record_success();
+            self.record_access(key, true, entry_bytes as u64);
+            CacheCounters::increment(&self.counters.hits);
             let lookup_elapsed = cache_lookup_start.elapsed();
             workunit.increment_counter(Metric::LocalCacheRequestsCached, 1);
-            if let Some(time_saved) = result.metadata.time_saved_from_cache(lookup_elapsed) {
-              let time_saved = time_saved.as_millis() as u64;
+            let time_saved_ms = result
+              .metadata
+              .time_saved_from_cache(lookup_elapsed)
+              .map(|time_saved| time_saved.as_millis() as u64);
+            if let Some(time_saved) = time_saved_ms {
               workunit.increment_counter(Metric::LocalCacheTotalTimeSavedMs, time_saved);
               context2
                 .workunit_store
                 .record_observation(ObservationMetric::LocalCacheTimeSavedMs, time_saved);
             }
-            // When we successfully use the cache, we change the description and increase the level
-            // (but not so much that it will be logged by default).
-            workunit.update_metadata(|initial| WorkunitMetadata {
-              desc: initial.desc.as_ref().map(|desc| format!("Hit: {}", desc)),
-              level: Level::Debug,
-              ..initial
-            });
-            Ok(result)
+            if let Some(ref session_cache_stats) = context2.session_cache_stats {
+              session_cache_stats.record_hit(entry_bytes as u64, time_saved_ms);
+            }
+            self.record_decision(workunit, key, &result, entry_bytes as u64, time_saved_ms);
+            // When we successfully use the cache, we change the description and increase the level
+            // (but not so much that it will be logged by default).
+            if self.promote_hit_workunit_level {
+              workunit.update_metadata(|initial| WorkunitMetadata {
+                desc: initial.desc.as_ref().map(|desc| format!("Hit: {}", desc)),
+                level: Level::Debug,
+                ..initial
+              });
+            }
+            Ok(result)
+          }
+          Err(err) => {
+            debug!(
+              "Error loading process execution result from local cache: {} - continuing to execute",
+              err
+            );
+            workunit.increment_counter(Metric::LocalCacheReadErrors, 1);
+            CacheCounters::increment(&self.counters.read_errors);
+            CacheCounters::increment(&self.counters.misses);
+            if let Some(ref session_cache_stats) = context2.session_cache_stats {
+              session_cache_stats.record_miss();
+            }
+            breaker_permit.record_failure();
+            // Falling through to re-execute.
+            Err(None)
+          }
+          Ok(hit_or_miss) => {
+            breaker_permit.record_success();
+            // Either we missed, or we hit for a failing result.
+            let reason = hit_or_miss
+              .map(|_| UncachedReason::CachedFailure)
+              .unwrap_or_else(|reason| reason);
+            workunit.increment_counter(Metric::LocalCacheRequestsUncached, 1);
+            workunit.increment_counter(reason.metric(), 1);
+            CacheCounters::increment(&self.counters.misses);
+            if let Some(ref session_cache_stats) = context2.session_cache_stats {
+              session_cache_stats.record_miss();
+            }
+            // Falling through to execute.
+            Err(Some(reason))
+          }
+        }
+      }
+      .boxed()
+    );
+
+    // The result of the lookup is None if it was cancelled because the process completed first,
+    // in which case the result of the process is returned alongside it.
+    let (cache_read_result, speculative_result) = if self.speculate {
+      let mut execution = self.underlying.run(context.clone(), workunit, req.clone());
+      tokio::select! {
+        cache_read_result = cache_read => {
+          if cache_read_result.is_ok() {
+            // The process is cancelled by dropping it.
+            (Some(cache_read_result), None)
+          } else {
+            (Some(cache_read_result), Some(execution.await))
+          }
+        }
+        execution_result = &mut execution => {
+          // The lookup is cancelled, so nothing was learned about the health of the cache.
+          breaker_permit.release();
+          (None, Some(execution_result))
+        }
+      }
+    } else {
+      (Some(cache_read.await), None)
+    };
+    match cache_read_result {
+      Some(Ok(_)) if self.speculate => {
+        workunit.increment_counter(Metric::LocalCacheSpeculationLookupCompletedFirst, 1)
+      }
+      None => workunit.increment_counter(Metric::LocalCacheSpeculationExecutionCompletedFirst, 1),
+      _ => {}
+    }
+
+    if let (Some(ref miss_diagnostics), Some(ref cache_read_result)) =
+      (&self.miss_diagnostics, &cache_read_result)
+    {
+      let identity = req.user_facing_name();
+      let changed = miss_diagnostics.lock().observe(
+        identity.clone(),
+        key,
+        key_components(&req),
+        cache_read_result.is_err(),
+      );
+      if let Some((previous_key, changed)) = changed {
+        trace!(
+          "Local cache miss for {}: its key changed from {} to {}. Changed: {}",
+          identity,
+          previous_key,
+          key,
+          if changed.is_empty() {
+            "none of its components (so the cache configuration must have)".to_owned()
+          } else {
+            changed.join(", ")
+          }
+        );
+      }
+    }
+    if let Some(Ok(result)) = cache_read_result {
+      return Ok(result);
+    }
+    // A speculative lookup does not delay the process.
+    if !self.speculate {
+      context.workunit_store.record_observation(
+        ObservationMetric::LocalCacheMissOverheadUs,
+        cache_lookup_start.elapsed().as_micros() as u64,
+      );
+    }
+
+    let expired = matches!(cache_read_result, Some(Err(Some(UncachedReason::Expired))));
+    // Unless the lookup completed (and so was unusable), an existing entry is kept.
+    let replace = cache_read_result.is_some();
+    let description = req.user_facing_name();
+    let execution_result = match speculative_result {
+      Some(execution_result) => execution_result,
+      None => self.underlying.run(context.clone(), workunit, req).await,
+    };
+    let result = match execution_result {
+      Ok(result) => result,
+      Err(err) => {
+        if let (true, Some(grace)) = (expired, self.serve_expired_on_error_within) {
+          let expired_result = self
+            .lookup_inner(
+              key,
+              materialize_policy,
+              grace,
+              Some(input_checksum),
+              Some(&mut *workunit),
+            )
+            .await;
+          match expired_result {
+            Ok(Ok((result, _))) if result.exit_code == 0 || write_failures_to_cache => {
+              warn!(
+                "Serving an expired local cache entry for {}, because re-executing it failed: {}",
+                description, err
+              );
+              workunit.increment_counter(Metric::LocalCacheServedExpiredOnError, 1);
+              return Ok(result);
+            }
+            _ => {}
+          }
+        }
+        return Err(err);
+      }
+    };
+    let mut stored_bytes = 0;
+    if !self.read_only && (result.exit_code == 0 || write_failures_to_cache) {
+      let result = result.clone();
+      let write_result = in_workunit!(
+        context.workunit_store.clone(),
+        "local_cache_write".to_owned(),
+        WorkunitMetadata {
+          level: Level::Trace,
+          user_metadata: key_metadata,
+          ..WorkunitMetadata::default()
+        },
+        |workunit| async move {
+          let store_result = async {
+            let stored_bytes = self
+              .store_inner(
+                key,
+                &result,
+                replace,
+                Some(input_checksum),
+                expires_at,
+                Some(&mut *workunit),
+              )
+              .await?;
+            if stored_bytes.is_some() {
+              // Failures are only written because of `ProcessCacheScope::Always`.
+              workunit.increment_counter(
+                if result.exit_code == 0 {
+                  Metric::LocalCacheWritesSuccess
+                } else {
+                  Metric::LocalCacheWritesAlwaysScope
+                },
+                1,
+              );
+            }
+            if let Some(semantic_key) = semantic_key {
+              self
+                .store_inner(
+                  semantic_key,
+                  &result,
+                  replace,
+                  None,
+                  expires_at,
+                  Some(&mut *workunit),
+                )
+                .await?;
+            }
+            Ok::<_, String>(stored_bytes.unwrap_or(0))
+          }
+          .await;
+          match store_result {
+            Ok(stored_bytes) => {
+              self.circuit_breaker.record_success();
+              Ok(stored_bytes)
+            }
+            Err(err) => {
+              workunit.increment_counter(Metric::LocalCacheWriteErrors, 1);
+              CacheCounters::increment(&self.counters.write_errors);
+              self.circuit_breaker.record_failure();
+              if self.fail_on_write_error {
+                return Err(format!(
+                  "Error storing process execution result to local cache: {}",
+                  err
+                ));
+              }
+              warn!(
+                "Error storing process execution result to local cache: {} - ignoring and continuing",
+                err
+              );
+              Ok(0)
+            }
+          }
+        }
+      )
+      .await;
+      stored_bytes = write_result?;
+    }
+    self.record_access(key, false, stored_bytes);
+    self.record_decision(workunit, key, &result, stored_bytes, None);
+    Ok(result)
+  }
+}
+
+impl CommandRunner {
+  ///
+  /// Looks up the result cached under the given fingerprint, ensuring that the digests it
+  /// references are loadable according to the given MaterializePolicy.
+  ///
+  pub async fn lookup(
+    &self,
+    fingerprint: Fingerprint,
+    materialize: MaterializePolicy,
+  ) -> Result<Option<FallibleProcessResultWithPlatform>, String> {
+    match self
+      .lookup_inner(fingerprint, materialize, Duration::ZERO, None, None)
+      .await?
+    {
+      Ok((result, _)) => Ok(Some(result)),
+      Err(UncachedReason::OutputsUnavailable(err)) | Err(UncachedReason::Malformed(err)) => {
+        Err(err)
+      }
+      Err(_) => Ok(None),
+    }
+  }
+
+  ///
+  /// Like `lookup`, but looks up the result of the request whose REAPI Action has the given
+  /// digest, so that REAPI clients can query the cache using the keys that they already compute.
+  /// See `action_digest_fingerprint`.
+  ///
+  pub async fn lookup_by_action_digest(
+    &self,
+    action_digest: Digest,
+    materialize: MaterializePolicy,
+  ) -> Result<Option<FallibleProcessResultWithPlatform>, String> {
+    self
+      .lookup(self.action_digest_fingerprint(action_digest), materialize)
+      .await
+  }
+
+  ///
+  /// Like `lookup`, but also returns the size of the entry for a hit, or the reason that the
+  /// cache could not be used for a miss.
+  ///
+  /// An entry which is `UncachedReason::Malformed` is evicted before returning. An entry which
+  /// expired less than `expired_grace` ago is served as though it had not expired. If an
+  /// `input_checksum` is given, an entry which was stored with a different one is a
+  /// `UncachedReason::FingerprintCollision`. An entry whose response has a non-OK status is a
+  /// `UncachedReason::NonOkStatus`.
+  ///
+  async fn lookup_inner(
+    &self,
+    fingerprint: Fingerprint,
+    materialize: MaterializePolicy,
+    expired_grace: Duration,
+    input_checksum: Option<Fingerprint>,
+    workunit: Option<&mut RunningWorkunit>,
+  ) -> Result<Result<(FallibleProcessResultWithPlatform, usize), UncachedReason>, String> {
+    use remexec::ExecuteResponse;
+
+    let _permit = match self.lookup_limit {
+      Some(ref lookup_limit) => Some(lookup_limit.acquire().await?),
+      None => None,
+    };
+
+    // See whether there is an unexpired cache entry, preferring one whose write was deferred.
+    let deferred_bytes = self
+      .deferred_writes
+      .lock()
+      .entries
+      .get(&fingerprint)
+      .cloned();
+    let loaded = if let Some(bytes) = deferred_bytes {
+      let deserialize_start = Instant::now();
+      let entry = CacheEntry::decode(&bytes)?;
+      record_micros(
+        ObservationMetric::LocalCacheEntryDeserializeUs,
+        deserialize_start.elapsed(),
+      );
+      Some((bytes.len(), entry))
+    } else {
+      // The entry is deserialized while it is being read, so the time spent deserializing it is
+      // subtracted from the time spent reading it.
+      let read_start = Instant::now();
+      let loaded = self
+        .process_execution_store
+        .load_bytes_with(fingerprint, |bytes| {
+          let deserialize_start = Instant::now();
+          let entry = CacheEntry::decode(bytes)?;
+          Ok((bytes.len(), entry, deserialize_start.elapsed()))
+        })
+        .await?;
+      let deserialize_elapsed = loaded
+        .as_ref()
+        .map_or(Duration::ZERO, |(_, _, deserialize_elapsed)| {
+          *deserialize_elapsed
+        });
+      record_micros(
+        ObservationMetric::LocalCacheStoreReadUs,
+        read_start.elapsed().saturating_sub(deserialize_elapsed),
+      );
+      loaded.map(|(entry_bytes, entry, deserialize_elapsed)| {
+        record_micros(
+          ObservationMetric::LocalCacheEntryDeserializeUs,
+          deserialize_elapsed,
+        );
+        (entry_bytes, entry)
+      })
+    };
+    let (entry_bytes, maybe_entry) = match loaded {
+      Some((entry_bytes, entry)) => (entry_bytes, Some(entry)),
+      None => (0, None),
+    };
+    let collided = match (
+      maybe_entry.as_ref().and_then(|entry| entry.input_checksum),
+      input_checksum,
+    ) {
+      (Some(stored), Some(expected)) => stored != expected,
+      _ => false,
+    };
+    let maybe_execute_response: Result<(ExecuteResponse, Platform, SystemTime), UncachedReason> =
+      match maybe_entry {
+        Some(_) if collided => {
+          warn!(
+            "Local cache entry {} was stored for a different request with the same fingerprint! \
+             Treating it as a miss: please report this as a bug.",
+            fingerprint
+          );
+          Err(UncachedReason::FingerprintCollision)
+        }
+        Some(entry)
+          if !self.is_expired(
+            fingerprint,
+            entry.created_at() + expired_grace,
+            entry
+              .expires_at()
+              .map(|expires_at| expires_at + expired_grace),
+          ) =>
+        {
+          let platform = match entry.platform {
+            Some(platform) => Some(platform),
+            None => {
+              // The entry was written before platforms were reliably recorded (or is malformed).
+              warn!(
+                "Local cache entry {} has no recorded platform{}",
+                fingerprint,
+                if self.strict_platform {
+                  ": treating it as a miss."
+                } else {
+                  "."
+                }
+              );
+              if let Some(workunit) = workunit {
+                workunit.increment_counter(Metric::LocalCacheUnknownPlatform, 1);
+              }
+              if self.strict_platform {
+                None
+              } else {
+                Some(Platform::current()?)
+              }
+            }
+          };
+          match platform {
+            Some(platform) if self.is_compatible(platform)? => {
+              match self.resolve_response(entry).await? {
+                Ok(entry) => {
+                  let decode_start = Instant::now();
+                  let execute_response =
+                    entry.execute_response(self.compression_dictionary.as_deref());
+                  record_micros(
+                    ObservationMetric::LocalCacheResponseDecodeUs,
+                    decode_start.elapsed(),
+                  );
+                  execute_response
+                    .map(|execute_response| (execute_response, platform, entry.created_at()))
+                    .map_err(|err| {
+                      UncachedReason::Malformed(format!("Could not decode response: {}", err))
+                    })
+                }
+                // Like a missing output, the shared response might be recovered, so the entry is
+                // kept.
+                Err(response_digest) => Err(UncachedReason::OutputsUnavailable(format!(
+                  "ExecuteResponse {:?} is missing from the file Store",
+                  response_digest
+                ))),
+              }
+            }
+            _ => Err(UncachedReason::PlatformMismatch),
           }
-          Err(err) => {
-            debug!(
-              "Error loading process execution result from local cache: {} - continuing to execute",
-              err
+        }
+        Some(_) => Err(UncachedReason::Expired),
+        None => Err(UncachedReason::Miss),
+      };
+
+    // If entries may be evicted, record that this one was used.
+    if maybe_execute_response.is_ok()
+      && (self.max_total_bytes.is_some() || self.max_entries.is_some())
+      && !self.read_only
+    {
+      self
+        .process_execution_store
+        .lease(fingerprint)
+        .await
+        .map_err(|err| format!("Error leasing local cache entry {}: {}", fingerprint, err))?;
+    }
+
+    // Deserialize the cache entry if it existed.
+    let populated = match maybe_execute_response {
+      Ok((execute_response, platform, created_at)) => {
+        let status = execute_response
+          .status
+          .as_ref()
+          .filter(|status| status.code != tonic::Code::Ok as i32);
+        if let Some(status) = status {
+          let status = format!(
+            "{:?}: {}",
+            tonic::Code::from_i32(status.code),
+            status.message
+          );
+          debug!(
+            "Local cache entry {} has a non-OK status ({}): treating it as a miss.",
+            fingerprint, status
+          );
+          Err(UncachedReason::NonOkStatus(status))
+        } else if let Some(ref action_result) = execute_response.result {
+          let populate_start = Instant::now();
+          let populated = crate::remote::populate_fallible_execution_result(
+            self.file_store.clone(),
+            action_result,
+            platform,
+            TREE_DIGEST_IS_OUTPUT_DIRECTORY,
+            self.hit_source,
+          )
+          .await;
+          if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+            workunit_store_handle.store.record_observation(
+              ObservationMetric::LocalCachePopulateLatencyUs,
+              populate_start.elapsed().as_micros() as u64,
             );
-            workunit.increment_counter(Metric::LocalCacheReadErrors, 1);
-            // Falling through to re-execute.
-            Err(())
           }
-          Ok(_) => {
-            // Either we missed, or we hit for a failing result.
-            workunit.increment_counter(Metric::LocalCacheRequestsUncached, 1);
-            // Falling through to execute.
-            Err(())
+          populated
+            .map(|result| (result, created_at))
+            .map_err(UncachedReason::Malformed)
+        } else {
+          Err(UncachedReason::Malformed(
+            "action result missing from ExecuteResponse".to_owned(),
+          ))
+        }
+      }
+      Err(reason) => Err(reason),
+    };
+    let (mut result, created_at) = match populated {
+      Ok(populated) => populated,
+      Err(UncachedReason::Malformed(err)) => {
+        let err = format!("Local cache entry {} is malformed: {}", fingerprint, err);
+        if !self.read_only {
+          warn!("{} - removing it.", err);
+          if let Err(remove_err) = self.remove(fingerprint).await {
+            warn!(
+              "Failed to remove local cache entry {}: {}",
+              fingerprint, remove_err
+            );
           }
         }
+        return Ok(Err(UncachedReason::Malformed(err)));
       }
-      .boxed()
-    )
-    .await;
+      Err(reason) => return Ok(Err(reason)),
+    };
+    // Regardless of how hits are labeled, they were served from this tier.
+    result.metadata.cache_tier = Some(CacheTier::LocalDisk);
 
-    if let Ok(result) = cache_read_result {
-      return Ok(result);
+    // If the output directory contains too many files to eagerly ensure, ensure only its tree.
+    let materialize = match (materialize, self.max_materialize_files) {
+      (MaterializePolicy::All, Some(max_files)) => {
+        let output_entries = match self
+          .file_store
+          .expand_directory(result.output_directory)
+          .await
+        {
+          Ok(output_entries) => output_entries,
+          Err(err) => return Ok(Err(UncachedReason::OutputsUnavailable(err))),
+        };
+        let file_count = output_entries
+          .values()
+          .filter(|entry_type| **entry_type == EntryType::File)
+          .count();
+        if file_count > max_files {
+          debug!(
+            "Deferring materialization of the {} output files of local cache entry {}.",
+            file_count, fingerprint
+          );
+          MaterializePolicy::OutputTreeOnly
+        } else {
+          materialize
+        }
+      }
+      _ => materialize,
+    };
+
+    if self.eviction_policy == EvictionPolicy::Lfu {
+      let now_secs = self.now_secs();
+      self
+        .hit_counts
+        .lock()
+        .entry(fingerprint)
+        .or_default()
+        .record_hit(now_secs, self.lfu_half_life);
     }
 
-    let result = self.underlying.run(context.clone(), workunit, req).await?;
-    if result.exit_code == 0 || write_failures_to_cache {
-      let result = result.clone();
-      in_workunit!(
-        context.workunit_store.clone(),
-        "local_cache_write".to_owned(),
-        WorkunitMetadata {
-          level: Level::Trace,
-          ..WorkunitMetadata::default()
-        },
-        |workunit| async move {
-          if let Err(err) = self.store(key, &result).await {
+    // Ensure that the digests in the result which the policy requires are loadable, erroring if
+    // any are not. Returns the number of bytes of outputs which were ensured.
+    let verification = {
+      let file_store = self.file_store.clone();
+      let (stdout_digest, stderr_digest, output_directory) = (
+        result.stdout_digest,
+        result.stderr_digest,
+        result.output_directory,
+      );
+      async move {
+        let mut ensures = Vec::new();
+        if materialize != MaterializePolicy::Lazy {
+          for digest in non_empty_digests(&[stdout_digest, stderr_digest]) {
+            ensures.push(
+              file_store
+                .ensure_local_has_file(digest)
+                .map(move |res| res.map(|()| digest.size_bytes))
+                .boxed(),
+            );
+          }
+        }
+        match materialize {
+          MaterializePolicy::All => ensures.push({
+            // Equivalent to `ensure_local_has_recursive_directory`, but retaining the digests
+            // which were ensured so that their bytes can be accounted for.
+            let file_store = file_store.clone();
+            async move {
+              let entries = file_store.expand_directory(output_directory).await?;
+              future::try_join_all(
+                entries
+                  .iter()
+                  .filter(|(_, entry_type)| **entry_type == EntryType::File)
+                  .map(|(digest, _)| file_store.ensure_local_has_file(*digest)),
+              )
+              .await?;
+              Ok(
+                entries
+                  .keys()
+                  .map(|digest| digest.size_bytes)
+                  .sum::<usize>(),
+              )
+            }
+            .boxed()
+          }),
+          MaterializePolicy::OutputTreeOnly => ensures.push(
+            file_store
+              .expand_directory(output_directory)
+              .map(|res| res.map(|_| 0))
+              .boxed(),
+          ),
+          MaterializePolicy::StdoutStderrOnly | MaterializePolicy::Lazy => {}
+        }
+        future::try_join_all(ensures)
+          .await
+          .map(|sizes| sizes.into_iter().sum::<usize>())
+      }
+    };
+    let verified = self
+      .verified_until
+      .lock()
+      .get(&fingerprint)
+      .map_or(false, |verified_until| self.now() < *verified_until);
+    // Account for the entry, and for whichever outputs verifying it read.
+    let mut bytes_read = entry_bytes;
+    if verified {
+      // `validate` recently found that all of the outputs of the entry are present.
+    } else if self.fast_unsafe_reads {
+      let cache = self.clone();
+      let _verification = self.process_execution_store.executor().spawn(async move {
+        if let Err(err) = verification.await {
+          warn!(
+            "Local cache entry {} was served before its outputs were verified, but they could \
+             not be loaded, so it will be removed: {}",
+            fingerprint, err
+          );
+          if let Err(err) = cache.remove(fingerprint).await {
             warn!(
-              "Error storing process execution result to local cache: {} - ignoring and continuing",
-              err
+              "Failed to remove local cache entry {}: {}",
+              fingerprint, err
             );
-            workunit.increment_counter(Metric::LocalCacheWriteErrors, 1);
           }
         }
-      )
-      .await;
+      });
+    } else {
+      match verification.await {
+        Ok(verified_bytes) => bytes_read += verified_bytes,
+        Err(err) => return Ok(Err(UncachedReason::OutputsUnavailable(err))),
+      }
     }
-    Ok(result)
+
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .record_observation(ObservationMetric::LocalCacheBytesRead, bytes_read as u64);
+      workunit_store_handle.store.record_observation(
+        ObservationMetric::LocalCacheHitAgeSeconds,
+        self
+          .now()
+          .duration_since(created_at)
+          .unwrap_or_default()
+          .as_secs(),
+      );
+    }
+
+    Ok(Ok((result, entry_bytes)))
   }
-}
 
-impl CommandRunner {
-  async fn lookup(
+  ///
+  /// Like `lookup` (with the configured MaterializePolicy, as adjusted by
+  /// `default_materialize_outputs`), but blocks the calling thread until
+  /// the lookup completes, for use by tools and tests which run outside of the async runtime.
+  ///
+  /// NB: This must never be called from a thread of the async runtime (including from within a
+  /// Future), where it will panic or deadlock.
+  ///
+  pub fn blocking_lookup(
     &self,
     fingerprint: Fingerprint,
   ) -> Result<Option<FallibleProcessResultWithPlatform>, String> {
-    use remexec::ExecuteResponse;
-
-    // See whether there is a cache entry.
-    let maybe_execute_response: Option<(ExecuteResponse, Platform)> = self
+    self
       .process_execution_store
-      .load_bytes_with(fingerprint, move |bytes| {
-        let decoded: PlatformAndResponseBytes = bincode::deserialize(bytes)
-          .map_err(|err| format!("Could not deserialize platform and response: {}", err))?;
-        let platform = decoded.platform;
-        let execute_response = ExecuteResponse::decode(&decoded.response_bytes[..])
-          .map_err(|e| format!("Invalid ExecuteResponse: {:?}", e))?;
-        Ok((execute_response, platform))
-      })
+      .executor()
+      .block_on(self.lookup(fingerprint, self.default_materialize_policy()))
+  }
+
+  ///
+  /// Stores the given result under the given fingerprint.
+  ///
+  /// The output directory is canonicalized before it is stored, so that equivalent output trees
+  /// are always stored under the same digest. Canonicalization preserves empty directories, so a
+  /// declared output directory which the process left empty is materialized (as an empty
+  /// directory) from a hit, just as it would be from a fresh execution.
+  ///
+  pub async fn store(
+    &self,
+    fingerprint: Fingerprint,
+    result: &FallibleProcessResultWithPlatform,
+  ) -> Result<(), String> {
+    self
+      .store_inner(fingerprint, result, true, None, None, None)
       .await?;
+    Ok(())
+  }
 
-    // Deserialize the cache entry if it existed.
-    let result = if let Some((execute_response, platform)) = maybe_execute_response {
-      if let Some(ref action_result) = execute_response.result {
-        crate::remote::populate_fallible_execution_result(
-          self.file_store.clone(),
-          action_result,
-          platform,
-          true,
-          ProcessResultSource::HitLocally,
-        )
+  ///
+  /// Like `store`, but only stores the result if there is no entry for the fingerprint (usable or
+  /// not), and returns whether it did. When two workers race to store equivalent results, this
+  /// preserves the first writer's entry (and its metadata) rather than clobbering it.
+  ///
+  pub async fn store_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    result: &FallibleProcessResultWithPlatform,
+  ) -> Result<bool, String> {
+    Ok(
+      self
+        .store_inner(fingerprint, result, false, None, None, None)
         .await?
-      } else {
-        return Err("action result missing from ExecuteResponse".into());
-      }
-    } else {
-      return Ok(None);
-    };
+        .is_some(),
+    )
+  }
 
-    // Ensure that all digests in the result are loadable, erroring if any are not.
-    let _ = future::try_join_all(vec![
-      self
-        .file_store
-        .ensure_local_has_file(result.stdout_digest)
-        .boxed(),
-      self
-        .file_store
-        .ensure_local_has_file(result.stderr_digest)
-        .boxed(),
-      self
-        .file_store
-        .ensure_local_has_recursive_directory(result.output_directory),
-    ])
-    .await?;
+  ///
+  /// Returns the total size of the outputs of the given result: its stdout and stderr, and the
+  /// files and Directory protos of its output directory.
+  ///
+  async fn output_bytes(&self, result: &FallibleProcessResultWithPlatform) -> Result<u64, String> {
+    let output_directory_bytes = self
+      .file_store
+      .expand_directory(result.output_directory)
+      .await?
+      .keys()
+      .map(|digest| digest.size_bytes as u64)
+      .sum::<u64>();
+    Ok(
+      result.stdout_digest.size_bytes as u64
+        + result.stderr_digest.size_bytes as u64
+        + output_directory_bytes,
+    )
+  }
 
-    Ok(Some(result))
+  ///
+  /// Applies the given RedactFn to the given stdout or stderr, and returns the digest of the
+  /// redacted output. Outputs which cannot be loaded cannot be redacted, so fail.
+  ///
+  async fn redact_output(&self, redact_fn: &RedactFn, digest: Digest) -> Result<Digest, String> {
+    if digest == EMPTY_DIGEST {
+      // There is nothing to redact from no output.
+      return Ok(digest);
+    }
+    let redact_fn = redact_fn.clone();
+    let redacted = self
+      .file_store
+      .load_file_bytes_with(digest, move |bytes| redact_fn(bytes))
+      .await?
+      .ok_or_else(|| {
+        format!(
+          "Could not redact output {:?}, because it is not in the Store",
+          digest
+        )
+      })?;
+    match redacted {
+      Some(redacted) => self.file_store.store_file_bytes(redacted, true).await,
+      None => Ok(digest),
+    }
   }
 
-  async fn store(
+  ///
+  /// Stores the given result, replacing any existing entry if `replace` is set. Returns the size
+  /// of the stored entry, or None if an existing entry was kept (or the result was skipped under
+  /// memory pressure). The `input_checksum` and `expires_at` of the request are recorded if they
+  /// are given.
+  ///
+  async fn store_inner(
     &self,
     fingerprint: Fingerprint,
     result: &FallibleProcessResultWithPlatform,
-  ) -> Result<(), String> {
-    let stdout_digest = result.stdout_digest;
-    let stderr_digest = result.stderr_digest;
+    replace: bool,
+    input_checksum: Option<Fingerprint>,
+    expires_at: Option<SystemTime>,
+    workunit: Option<&mut RunningWorkunit>,
+  ) -> Result<Option<u64>, String> {
+    if self.read_only {
+      return Err("The local process cache is read-only.".to_owned());
+    }
+    let transformed;
+    let result = if let Some(ref store_transform_fn) = self.store_transform_fn {
+      let mut result = result.clone();
+      store_transform_fn(&mut result);
+      transformed = result;
+      &transformed
+    } else {
+      result
+    };
+    if self.min_output_bytes_to_cache.is_some() || self.max_output_bytes_to_cache.is_some() {
+      let output_bytes = self.output_bytes(result).await?;
+      let skip = if self
+        .min_output_bytes_to_cache
+        .map_or(false, |min| output_bytes < min)
+      {
+        Some(("smaller", Metric::LocalCacheSkippedOutputTooSmall))
+      } else if self
+        .max_output_bytes_to_cache
+        .map_or(false, |max| output_bytes > max)
+      {
+        Some(("larger", Metric::LocalCacheSkippedOutputTooLarge))
+      } else {
+        None
+      };
+      if let Some((comparison, metric)) = skip {
+        debug!(
+          "Not storing local cache entry {}, because its {} bytes of outputs are {} than the \
+           configured limit.",
+          fingerprint, output_bytes, comparison
+        );
+        if let Some(workunit) = workunit {
+          workunit.increment_counter(metric, 1);
+        }
+        return Ok(None);
+      }
+    }
+    // The verification (if any) was of the entry which this one may replace.
+    self.verified_until.lock().remove(&fingerprint);
+    let (stdout_digest, stderr_digest) = if let Some(ref redact_fn) = self.redact_fn {
+      future::try_join(
+        self.redact_output(redact_fn, result.stdout_digest),
+        self.redact_output(redact_fn, result.stderr_digest),
+      )
+      .await?
+    } else {
+      (result.stdout_digest, result.stderr_digest)
+    };
+    let output_directory =
+      match canonicalize_directory(self.file_store.clone(), result.output_directory).await? {
+        Some(output_directory) => output_directory,
+        None => {
+          // As for a lookup, outputs which are not available locally are not an error of the cache.
+          debug!(
+            "Not storing local cache entry {}, because its outputs are unavailable.",
+            fingerprint
+          );
+          return Ok(None);
+        }
+      };
+
+    if self.verify_digests_on_write {
+      let mut ensures = vec![self
+        .file_store
+        .ensure_local_has_recursive_directory(output_directory)];
+      for digest in non_empty_digests(&[stdout_digest, stderr_digest]) {
+        ensures.push(self.file_store.ensure_local_has_file(digest).boxed());
+      }
+      let ensured = future::try_join_all(ensures).await;
+      if let Err(err) = ensured {
+        if let Some(workunit) = workunit {
+          workunit.increment_counter(Metric::LocalCacheWriteRejectedDangling, 1);
+        }
+        return Err(format!(
+          "Not storing local cache entry {}, because it references digests which are not in the \
+           Store: {}",
+          fingerprint, err
+        ));
+      }
+    }
 
-    let action_result = remexec::ActionResult {
+    let mut action_result = remexec::ActionResult {
       exit_code: result.exit_code,
+      // NB: See TREE_DIGEST_IS_OUTPUT_DIRECTORY.
       output_directories: vec![remexec::OutputDirectory {
         path: String::new(),
-        tree_digest: Some((&result.output_directory).into()),
+        tree_digest: Some((&output_directory).into()),
       }],
       stdout_digest: Some((&stdout_digest).into()),
       stderr_digest: Some((&stderr_digest).into()),
       execution_metadata: Some(result.metadata.clone().into()),
       ..remexec::ActionResult::default()
     };
+    if let Err(err) = dedupe_outputs(&mut action_result) {
+      if let Some(workunit) = workunit {
+        workunit.increment_counter(Metric::LocalCacheInvalidOutputs, 1);
+      }
+      return Err(err);
+    }
     let execute_response = remexec::ExecuteResponse {
       cached_result: true,
       result: Some(action_result),
       ..remexec::ExecuteResponse::default()
     };
 
-    // TODO: Should probably have a configurable lease time which is larger than default.
-    // (This isn't super urgent because we don't ever actually GC this store. So also...)
-    // TODO: GC the local process execution cache.
-
+    // Reserve the memory needed to buffer the entry (of which the response is the bulk) until it
+    // has been written.
+    let _reservation = match self.store_memory_budget {
+      Some((ref budget, max_bytes)) => {
+        let bytes = std::cmp::min(execute_response.encoded_len(), max_bytes as usize) as u32;
+        let reservation = match self.memory_pressure_policy {
+          MemoryPressurePolicy::Wait => Some(budget.acquire_many(bytes).await.map_err(|err| {
+            format!(
+              "Could not reserve memory to store local cache entry: {}",
+              err
+            )
+          })?),
+          MemoryPressurePolicy::Skip => budget.try_acquire_many(bytes).ok(),
+        };
+        if reservation.is_none() {
+          debug!(
+            "Not storing local cache entry {}, because {} bytes are already being stored.",
+            fingerprint, max_bytes
+          );
+          if let Some(workunit) = workunit {
+            workunit.increment_counter(Metric::LocalCacheSkippedMemoryPressure, 1);
+          }
+          return Ok(None);
+        }
+        reservation
+      }
+      None => None,
+    };
+    let encode_start = Instant::now();
     let mut response_bytes = Vec::with_capacity(execute_response.encoded_len());
     execute_response
       .encode(&mut response_bytes)
       .map_err(|err| format!("Error serializing execute process result to cache: {}", err))?;
+    record_micros(
+      ObservationMetric::LocalCacheResponseEncodeUs,
+      encode_start.elapsed(),
+    );
+
+    let mut response_dictionary = None;
+    if self.compress_response {
+      if let Some(ref dictionary) = self.compression_dictionary {
+        response_bytes = dictionary.compress(&response_bytes)?;
+        response_dictionary = Some(dictionary.fingerprint);
+      } else {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        response_bytes = encoder
+          .write_all(&response_bytes)
+          .and_then(|()| encoder.finish())
+          .map_err(|err| format!("Error compressing execute process result: {}", err))?;
+      }
+    }
+
+    let mut response_digest = None;
+    if self.dedupe_responses {
+      response_digest = Some(
+        self
+          .file_store
+          .store_file_bytes(Bytes::from(response_bytes), true)
+          .await?,
+      );
+      response_bytes = Vec::new();
+    }
 
-    let bytes_to_store = bincode::serialize(&PlatformAndResponseBytes {
-      platform: result.platform,
+    let created_at_secs = self
+      .now()
+      .duration_since(UNIX_EPOCH)
+      .map_err(|err| format!("System clock is before the unix epoch: {}", err))?
+      .as_secs();
+    let entry = CacheEntry {
+      platform: Some(result.platform),
       response_bytes,
-    })
-    .map(Bytes::from)
-    .map_err(|err| {
-      format!(
-        "Error serializing platform and execute process result: {}",
-        err
+      response_compressed: self.compress_response,
+      created_at_secs,
+      worker_id: self.worker_id.clone(),
+      response_dictionary,
+      input_checksum,
+      expires_at_secs: expires_at
+        .map(|expires_at| {
+          expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .map_err(|err| format!("Process expiration is before the unix epoch: {}", err))
+        })
+        .transpose()?,
+      response_digest,
+    };
+    let serialize_start = Instant::now();
+    let bytes_to_store = if self.framed_entries {
+      entry.encode_framed()?
+    } else {
+      entry.encode()?
+    };
+    record_micros(
+      ObservationMetric::LocalCacheEntrySerializeUs,
+      serialize_start.elapsed(),
+    );
+
+    if self.index_sidecars {
+      let mut sidecar =
+        Vec::with_capacity(INDEX_SIDECAR_MAGIC.len() + FINGERPRINT_SIZE + bytes_to_store.len());
+      sidecar.extend_from_slice(INDEX_SIDECAR_MAGIC);
+      sidecar.extend_from_slice(&fingerprint.0);
+      sidecar.extend_from_slice(&bytes_to_store);
+      self
+        .file_store
+        .store_file_bytes(Bytes::from(sidecar), true)
+        .await?;
+    }
+
+    let stored_bytes = bytes_to_store.len() as u64;
+    if let (true, Some(max_bytes)) = (replace, self.deferred_write_max_bytes) {
+      let (coalesced, over_max_bytes) = {
+        let mut deferred_writes = self.deferred_writes.lock();
+        deferred_writes.bytes += bytes_to_store.len();
+        let previous = deferred_writes.entries.insert(fingerprint, bytes_to_store);
+        if let Some(ref previous) = previous {
+          deferred_writes.bytes -= previous.len();
+        }
+        (previous.is_some(), deferred_writes.bytes > max_bytes)
+      };
+      if coalesced {
+        if let Some(workunit) = workunit {
+          workunit.increment_counter(Metric::LocalCacheDeferredWritesCoalesced, 1);
+        }
+      }
+      if over_max_bytes {
+        self.flush_own_deferred_writes().await?;
+      }
+      return Ok(Some(stored_bytes));
+    }
+    // Commit the outputs before the entry which references them, so that the entry is never
+    // visible without them.
+    if let Some(ref sync_outputs_fn) = self.sync_outputs_fn {
+      sync_outputs_fn(&self.file_store).await.map_err(|err| {
+        format!(
+          "Not storing local cache entry {}, because its outputs could not be made durable: {}",
+          fingerprint, err
+        )
+      })?;
+    }
+    // When called from `run`, any existing entry is replaced, since it was not usable (or we would
+    // not have re-run the process). The lease records when the entry was last used, for the
+    // benefit of `gc`.
+    let replaced = if replace {
+      self.replaced_usage(&[fingerprint]).await?
+    } else {
+      CacheUsage::default()
+    };
+    let write_start = Instant::now();
+    let written = if replace {
+      self
+        .process_execution_store
+        .replace_bytes(fingerprint, bytes_to_store.clone(), true)
+        .await?;
+      true
+    } else {
+      self
+        .process_execution_store
+        .store_bytes_if_absent(fingerprint, bytes_to_store.clone(), true)
+        .await?
+    };
+    record_micros(
+      ObservationMetric::LocalCacheStoreWriteUs,
+      write_start.elapsed(),
+    );
+    if !written {
+      return Ok(None);
+    }
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .record_observation(ObservationMetric::LocalCacheBytesWritten, stored_bytes);
+    }
+
+    if self.verify_on_write {
+      let read_back = self.process_execution_store.load_bytes(fingerprint).await?;
+      let verified = match read_back {
+        Some(ref bytes) => *bytes == bytes_to_store && CacheEntry::decode(bytes).is_ok(),
+        None => false,
+      };
+      if !verified {
+        if let Some(workunit) = workunit {
+          workunit.increment_counter(Metric::LocalCacheWriteVerifyFailures, 1);
+        }
+        // Don't leave a corrupt entry to be read later.
+        self.process_execution_store.remove(fingerprint).await?;
+        return Err(format!(
+          "Local cache entry {} did not read back as it was written.",
+          fingerprint
+        ));
+      }
+    }
+
+    self
+      .record_stored(
+        CacheUsage {
+          bytes: stored_bytes,
+          entries: 1,
+        },
+        replaced,
       )
-    })?;
+      .await?;
+    if let Some(max_referenced_blob_bytes) = self.max_referenced_blob_bytes {
+      let output_bytes = self.output_bytes(result).await?;
+      let referenced_blob_bytes = match *self.referenced_blob_usage.lock() {
+        Some(ref mut referenced_blob_bytes) => {
+          *referenced_blob_bytes += output_bytes;
+          Some(*referenced_blob_bytes)
+        }
+        None => None,
+      };
+      let referenced_blob_bytes = match referenced_blob_bytes {
+        Some(referenced_blob_bytes) => referenced_blob_bytes,
+        None => self.referenced_blob_bytes().await?,
+      };
+      if referenced_blob_bytes > max_referenced_blob_bytes {
+        self
+          .gc_referenced_blobs((max_referenced_blob_bytes as f64 * GC_TARGET_FRACTION) as u64)
+          .await?;
+      }
+    }
+    Ok(Some(stored_bytes))
+  }
+
+  ///
+  /// Writes any entries which `store` has buffered (see
+  /// `LocalCacheOptions::deferred_write_max_bytes`), for this cache and for its named stores.
+  ///
+  pub async fn flush_deferred_writes(&self) -> Result<(), String> {
+    self.flush_own_deferred_writes().await?;
+    future::try_join_all(
+      self
+        .named_stores
+        .values()
+        .map(|named_store| named_store.flush_own_deferred_writes()),
+    )
+    .await?;
+    Ok(())
+  }
 
+  async fn flush_own_deferred_writes(&self) -> Result<(), String> {
+    // The entries stay visible to `lookup` until they have been written.
+    let items = self
+      .deferred_writes
+      .lock()
+      .entries
+      .iter()
+      .map(|(fingerprint, bytes)| (*fingerprint, bytes.clone()))
+      .collect::<Vec<_>>();
+    if items.is_empty() {
+      return Ok(());
+    }
+    if let Some(ref sync_outputs_fn) = self.sync_outputs_fn {
+      sync_outputs_fn(&self.file_store).await.map_err(|err| {
+        format!(
+          "Not writing deferred local cache entries, because their outputs could not be made \
+           durable: {}",
+          err
+        )
+      })?;
+    }
+    let replaced = self
+      .replaced_usage(
+        &items
+          .iter()
+          .map(|(fingerprint, _)| *fingerprint)
+          .collect::<Vec<_>>(),
+      )
+      .await?;
     self
       .process_execution_store
-      .store_bytes(fingerprint, bytes_to_store, false)
+      .replace_bytes_batch(items.clone(), true)
+      .await?;
+
+    // Remove the written entries from the buffer, unless they were replaced in the meantime.
+    {
+      let mut deferred_writes = self.deferred_writes.lock();
+      for (fingerprint, bytes) in &items {
+        if deferred_writes.entries.get(fingerprint) == Some(bytes) {
+          deferred_writes.entries.remove(fingerprint);
+          deferred_writes.bytes -= bytes.len();
+        }
+      }
+    }
+    let stored_bytes: u64 = items.iter().map(|(_, bytes)| bytes.len() as u64).sum();
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .record_observation(ObservationMetric::LocalCacheBytesWritten, stored_bytes);
+    }
+    self
+      .record_stored(
+        CacheUsage {
+          bytes: stored_bytes,
+          entries: items.len() as u64,
+        },
+        replaced,
+      )
       .await
   }
+
+  ///
+  /// Returns the usage of the existing entries for the given fingerprints, which are about to be
+  /// replaced. This is only computed while the usage of the cache is being tracked.
+  ///
+  async fn replaced_usage(&self, fingerprints: &[Fingerprint]) -> Result<CacheUsage, String> {
+    let mut replaced = CacheUsage::default();
+    let tracked =
+      self.max_total_bytes.is_some() || self.max_entries.is_some() || self.usage.lock().is_some();
+    if !tracked {
+      return Ok(replaced);
+    }
+    for fingerprint in fingerprints {
+      if let Some(entry_bytes) = self
+        .process_execution_store
+        .load_bytes_with(*fingerprint, |bytes| Ok(bytes.len() as u64))
+        .await?
+      {
+        replaced.bytes += entry_bytes;
+        replaced.entries += 1;
+      }
+    }
+    Ok(replaced)
+  }
+
+  ///
+  /// Accounts for newly written entries (less any entries which they replaced) in the usage
+  /// estimate, evicting entries if the cache is now over its limits.
+  ///
+  async fn record_stored(&self, stored: CacheUsage, replaced: CacheUsage) -> Result<(), String> {
+    let known_usage = {
+      let mut usage = self.usage.lock();
+      if let Some(ref mut usage) = *usage {
+        usage.bytes = (usage.bytes + stored.bytes).saturating_sub(replaced.bytes);
+        usage.entries = (usage.entries + stored.entries).saturating_sub(replaced.entries);
+      }
+      *usage
+    };
+    if self.max_total_bytes.is_some() || self.max_entries.is_some() {
+      // If the usage was not yet known, the scan which computes it includes the stored entries.
+      let usage = match known_usage {
+        Some(usage) => usage,
+        None => self.usage().await?,
+      };
+      self.observe_usage(usage);
+      let over_bytes = self
+        .max_total_bytes
+        .map_or(false, |max| usage.bytes > max as u64);
+      let over_entries = self.max_entries.map_or(false, |max| usage.entries > max);
+      if over_bytes || over_entries {
+        // Evict down to below the limits, so that we don't need to re-scan on every store.
+        self
+          .gc_to(
+            self
+              .max_total_bytes
+              .map(|max| (max as f64 * GC_TARGET_FRACTION) as u64),
+            self
+              .max_entries
+              .map(|max| (max as f64 * GC_TARGET_FRACTION) as u64),
+          )
+          .await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+///
+/// Sorts and removes exact duplicates from the output files and directories of the given
+/// ActionResult, so that strict REAPI consumers of the stored result see each output path once.
+///
+/// Errors if the same path is claimed by more than one distinct output, or if any path is not a
+/// normalized relative path: one with no empty, `.` or `..` components. The only exception is
+/// the empty path of an output directory for the root of the output tree.
+///
+/// NB: The ActionResults which `store_inner` builds currently have only a root output directory
+/// (see TREE_DIGEST_IS_OUTPUT_DIRECTORY), so this is defensive: it ensures that stored entries
+/// stay valid for strict consumers if results ever carry individual outputs.
+///
+pub(crate) fn dedupe_outputs(action_result: &mut remexec::ActionResult) -> Result<(), String> {
+  let check_path = |path: &str, is_directory: bool| {
+    let is_root = is_directory && path.is_empty();
+    if is_root || path.split('/').all(|c| !matches!(c, "" | "." | "..")) {
+      Ok(())
+    } else {
+      Err(format!(
+        "Output path {:?} is not a normalized relative path.",
+        path
+      ))
+    }
+  };
+
+  action_result
+    .output_files
+    .sort_by(|a, b| a.path.cmp(&b.path));
+  action_result.output_files.dedup();
+  action_result
+    .output_directories
+    .sort_by(|a, b| a.path.cmp(&b.path));
+  action_result.output_directories.dedup();
+
+  for file in &action_result.output_files {
+    check_path(&file.path, false)?;
+  }
+  for directory in &action_result.output_directories {
+    check_path(&directory.path, true)?;
+  }
+  let mut paths = action_result
+    .output_files
+    .iter()
+    .map(|f| &f.path)
+    .chain(action_result.output_directories.iter().map(|d| &d.path))
+    .collect::<Vec<_>>();
+  paths.sort();
+  if let Some(window) = paths.windows(2).find(|window| window[0] == window[1]) {
+    return Err(format!(
+      "Output path {:?} was claimed by more than one distinct output.",
+      window[0]
+    ));
+  }
+  Ok(())
+}
+
+///
+/// Breaks the given request down into the named components which contribute to its key, with a
+/// digest of each, so that a change to the key can be attributed to the components which caused
+/// it. Environment variables are broken down individually.
+///
+fn key_components(req: &MultiPlatformProcess) -> BTreeMap<String, Digest> {
+  let mut components = BTreeMap::new();
+  for (constraint, process) in &req.0 {
+    let prefix = if req.0.len() > 1 {
+      format!("{:?}.", constraint)
+    } else {
+      String::new()
+    };
+    let mut add = |name: &str, value: String| {
+      components.insert(
+        format!("{}{}", prefix, name),
+        Digest::of_bytes(value.as_bytes()),
+      );
+    };
+    add("argv", format!("{:?}", process.argv));
+    for (name, value) in &process.env {
+      add(&format!("env.{}", name), value.clone());
+    }
+    add("input_files", format!("{:?}", process.input_files));
+    add(
+      "working_directory",
+      format!("{:?}", process.working_directory),
+    );
+    add("output_files", format!("{:?}", process.output_files));
+    add(
+      "output_directories",
+      format!("{:?}", process.output_directories),
+    );
+    add("timeout", format!("{:?}", process.timeout));
+    add(
+      "append_only_caches",
+      format!("{:?}", process.append_only_caches),
+    );
+    add("jdk_home", format!("{:?}", process.jdk_home));
+    add(
+      "platform_constraint",
+      format!("{:?}", process.platform_constraint),
+    );
+    add(
+      "output_schema_version",
+      format!("{:?}", process.output_schema_version),
+    );
+  }
+  components
+}
+
+///
+/// A checksum of the inputs of the given request, which (unlike its cache key) is recorded in its
+/// entry, so that a lookup can detect an entry which was stored for a different request with the
+/// same key. It only covers inputs which contribute to the key, so that requests with the same
+/// key (legitimately) have the same checksum.
+///
+fn input_checksum(req: &MultiPlatformProcess) -> Fingerprint {
+  let mut inputs = req
+    .0
+    .values()
+    .map(|process| {
+      format!(
+        "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+        process.argv,
+        process.env,
+        process.input_files,
+        process.working_directory,
+        process.output_files,
+        process.output_directories,
+        process.platform_constraint
+      )
+    })
+    .collect::<Vec<_>>();
+  // Like the key, independent of the order of the Processes.
+  inputs.sort();
+  Digest::of_bytes(inputs.join("\n").as_bytes()).hash
+}
+
+///
+/// The key under which the set of pinned fingerprints is stored. It is derived from a string
+/// rather than from a process, so it will not collide with the key of any entry.
+///
+fn pinned_fingerprints_key() -> Fingerprint {
+  Digest::of_bytes(b"local process cache: pinned fingerprints").hash
+}
+
+///
+/// The key under which the hit frequencies of entries are persisted. See
+/// `CommandRunner::persist_hit_frequencies`.
+///
+fn hit_frequencies_key() -> Fingerprint {
+  Digest::of_bytes(b"local process cache: hit frequencies").hash
+}
+
+///
+/// The key under which `CommandRunner::health_check` writes its sentinel entry.
+///
+fn health_check_key() -> Fingerprint {
+  Digest::of_bytes(b"local process cache: health check").hash
+}
+
+///
+/// Whether the given key is reserved for the cache's own use, rather than being the key of an
+/// entry. Operations which visit every entry skip reserved keys.
+///
+fn is_reserved_key(fingerprint: Fingerprint) -> bool {
+  fingerprint == pinned_fingerprints_key()
+    || fingerprint == health_check_key()
+    || fingerprint == hit_frequencies_key()
+}
+
+///
+/// Encodes an optional Platform as the platform byte of a framed entry.
+///
+fn encode_platform(platform: Option<Platform>) -> u8 {
+  match platform {
+    None => 0,
+    Some(Platform::Linux_x86_64) => 1,
+    Some(Platform::Macos_x86_64) => 2,
+    Some(Platform::Macos_arm64) => 3,
+  }
+}
+
+fn decode_platform(byte: u8) -> Result<Option<Platform>, String> {
+  match byte {
+    0 => Ok(None),
+    1 => Ok(Some(Platform::Linux_x86_64)),
+    2 => Ok(Some(Platform::Macos_x86_64)),
+    3 => Ok(Some(Platform::Macos_arm64)),
+    _ => Err(format!("unknown platform byte {}", byte)),
+  }
+}
+
+///
+/// Records the given duration as an observation of the given metric, in microseconds.
+///
+fn record_micros(metric: ObservationMetric, elapsed: Duration) {
+  if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+    workunit_store_handle
+      .store
+      .record_observation(metric, elapsed.as_micros() as u64);
+  }
+}
+
+///
+/// Returns the given digests of files, except for the canonical empty digest, which is always
+/// present, and so never needs to be checked for in (or loaded from) the Store. Processes which
+/// succeed silently (with empty stdout and stderr) are very common, so this saves two Store
+/// interactions for most hits.
+///
+fn non_empty_digests(digests: &[Digest]) -> Vec<Digest> {
+  digests
+    .iter()
+    .copied()
+    .filter(|digest| *digest != EMPTY_DIGEST)
+    .collect()
+}
+
+///
+/// Recursively sorts the children of the given Directory and of all of its subdirectories by
+/// name, recording any Directories which change, and returning the digest of the canonical root.
+/// Returns None if any of the Directories is not in the Store.
+///
+/// Directories which are already canonical are not re-recorded, and so keep their digests. The
+/// Directories are loaded without verifying that they are canonical, since they may have been
+/// recorded by an upstream runner which does not sort them.
+///
+fn canonicalize_directory(
+  store: Store,
+  digest: Digest,
+) -> BoxFuture<'static, Result<Option<Digest>, String>> {
+  async move {
+    if digest == EMPTY_DIGEST {
+      return Ok(Some(digest));
+    }
+    let mut directory = if let Some(directory) = store.load_directory_unverified(digest).await? {
+      directory
+    } else {
+      return Ok(None);
+    };
+
+    let child_digests = future::try_join_all(
+      directory
+        .directories
+        .iter()
+        .map(|dir_node| {
+          let store = store.clone();
+          let child_digest = require_digest(dir_node.digest.as_ref());
+          async move { canonicalize_directory(store, child_digest?).await }
+        })
+        .collect::<Vec<_>>(),
+    )
+    .await?;
+    let mut changed = false;
+    for (dir_node, child_digest) in directory.directories.iter_mut().zip(child_digests) {
+      let child_digest: Option<remexec::Digest> = if let Some(child_digest) = child_digest {
+        Some((&child_digest).into())
+      } else {
+        return Ok(None);
+      };
+      if dir_node.digest != child_digest {
+        dir_node.digest = child_digest;
+        changed = true;
+      }
+    }
+
+    let sorted = directory
+      .files
+      .windows(2)
+      .all(|pair| pair[0].name <= pair[1].name)
+      && directory
+        .directories
+        .windows(2)
+        .all(|pair| pair[0].name <= pair[1].name)
+      && directory
+        .symlinks
+        .windows(2)
+        .all(|pair| pair[0].name <= pair[1].name);
+    if sorted && !changed {
+      return Ok(Some(digest));
+    }
+    directory.files.sort_by(|a, b| a.name.cmp(&b.name));
+    directory.directories.sort_by(|a, b| a.name.cmp(&b.name));
+    directory.symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+    store.record_directory(&directory, true).await.map(Some)
+  }
+  .boxed()
 }
@@ -0,0 +1,620 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use filetime::FileTime;
+use hashing::Fingerprint;
+use sharded_lmdb::{EntryMetadata, ShardedLmdb};
+
+///
+/// The storage backend of the local process cache (see `crate::cache::CommandRunner`): a
+/// key-value store of encoded entries, with a lease per entry which records when it was last
+/// used.
+///
+/// `ShardedLmdb` is the default backend. `DirectoryStore` stores each entry as its own file, for
+/// caches which are distributed by syncing files.
+///
+#[async_trait]
+pub trait ProcessExecutionStore: Send + Sync {
+  ///
+  /// The Executor on which blocking operations of the store run.
+  ///
+  fn executor(&self) -> &task_executor::Executor;
+
+  ///
+  /// How long a lease extends from the time that it is taken.
+  ///
+  fn lease_time(&self) -> Duration;
+
+  ///
+  /// Returns true if the store cannot be written to.
+  ///
+  fn is_read_only(&self) -> bool;
+
+  async fn load_bytes(&self, fingerprint: Fingerprint) -> Result<Option<Bytes>, String>;
+
+  ///
+  /// Loads the value for the fingerprint, if any, and calls `f` with it, returning whether there
+  /// was one. Stores which can lend their values without copying them (such as `ShardedLmdb`)
+  /// should override the default, which calls `load_bytes`.
+  ///
+  async fn load_bytes_into(
+    &self,
+    fingerprint: Fingerprint,
+    f: Box<dyn FnOnce(&[u8]) + Send>,
+  ) -> Result<bool, String> {
+    match self.load_bytes(fingerprint).await? {
+      Some(bytes) => {
+        f(&bytes);
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  async fn exists(&self, fingerprint: Fingerprint) -> Result<bool, String>;
+
+  ///
+  /// Stores the given bytes unless a value already exists for the fingerprint, and returns
+  /// whether it did.
+  ///
+  async fn store_bytes_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<bool, String>;
+
+  ///
+  /// Stores the given bytes, replacing any existing value for the fingerprint.
+  ///
+  async fn replace_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<(), String>;
+
+  ///
+  /// Like `replace_bytes` for each of the given values. Stores which support transactions should
+  /// write them in as few transactions as possible.
+  ///
+  async fn replace_bytes_batch(
+    &self,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    for (fingerprint, bytes) in items {
+      self
+        .replace_bytes(fingerprint, bytes, initial_lease)
+        .await?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Removes the value for the fingerprint, and returns whether there was one.
+  ///
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String>;
+
+  ///
+  /// Extends the lease of the value for the fingerprint to `lease_time` from now.
+  ///
+  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String>;
+
+  async fn all_fingerprints(&self, parallelism: usize) -> Result<Vec<Fingerprint>, String> {
+    Ok(
+      self
+        .all_entry_metadata(parallelism)
+        .await?
+        .into_iter()
+        .map(|entry| entry.fingerprint)
+        .collect(),
+    )
+  }
+
+  async fn all_entry_metadata(&self, parallelism: usize) -> Result<Vec<EntryMetadata>, String>;
+}
+
+impl dyn ProcessExecutionStore {
+  ///
+  /// Loads the value for the fingerprint, if any, and applies the given function to it. See
+  /// `ProcessExecutionStore::load_bytes_into`.
+  ///
+  pub async fn load_bytes_with<
+    T: Send + 'static,
+    F: FnOnce(&[u8]) -> Result<T, String> + Send + 'static,
+  >(
+    &self,
+    fingerprint: Fingerprint,
+    f: F,
+  ) -> Result<Option<T>, String> {
+    let result = Arc::new(parking_lot::Mutex::new(None));
+    let result2 = result.clone();
+    self
+      .load_bytes_into(
+        fingerprint,
+        Box::new(move |bytes: &[u8]| *result2.lock() = Some(f(bytes))),
+      )
+      .await?;
+    let result = result.lock().take();
+    result.transpose()
+  }
+}
+
+#[async_trait]
+impl ProcessExecutionStore for ShardedLmdb {
+  fn executor(&self) -> &task_executor::Executor {
+    ShardedLmdb::executor(self)
+  }
+
+  fn lease_time(&self) -> Duration {
+    ShardedLmdb::lease_time(self)
+  }
+
+  fn is_read_only(&self) -> bool {
+    ShardedLmdb::is_read_only(self)
+  }
+
+  async fn load_bytes(&self, fingerprint: Fingerprint) -> Result<Option<Bytes>, String> {
+    ShardedLmdb::load_bytes_with(self, fingerprint, |bytes| Ok(Bytes::copy_from_slice(bytes))).await
+  }
+
+  async fn load_bytes_into(
+    &self,
+    fingerprint: Fingerprint,
+    f: Box<dyn FnOnce(&[u8]) + Send>,
+  ) -> Result<bool, String> {
+    // The value is only borrowed for the duration of the read transaction, so `f` is called
+    // within it rather than copying the value out.
+    let f = parking_lot::Mutex::new(Some(f));
+    let found = ShardedLmdb::load_bytes_with(self, fingerprint, move |bytes| {
+      if let Some(f) = f.lock().take() {
+        f(bytes);
+      }
+      Ok(())
+    })
+    .await?;
+    Ok(found.is_some())
+  }
+
+  async fn exists(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    ShardedLmdb::exists(self, fingerprint).await
+  }
+
+  async fn store_bytes_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<bool, String> {
+    ShardedLmdb::store_bytes_if_absent(self, fingerprint, bytes, initial_lease).await
+  }
+
+  async fn replace_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    ShardedLmdb::replace_bytes(self, fingerprint, bytes, initial_lease).await
+  }
+
+  async fn replace_bytes_batch(
+    &self,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    ShardedLmdb::replace_bytes_batch(self, items, initial_lease).await
+  }
+
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    ShardedLmdb::remove(self, fingerprint).await
+  }
+
+  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    ShardedLmdb::lease(self, fingerprint)
+      .await
+      .map_err(|err| format!("{}", err))
+  }
+
+  async fn all_fingerprints(&self, parallelism: usize) -> Result<Vec<Fingerprint>, String> {
+    ShardedLmdb::all_fingerprints(self, parallelism).await
+  }
+
+  async fn all_entry_metadata(&self, parallelism: usize) -> Result<Vec<EntryMetadata>, String> {
+    ShardedLmdb::all_entry_metadata(self, parallelism).await
+  }
+}
+
+///
+/// A ProcessExecutionStore which stores each entry as a file named by its fingerprint, in a
+/// directory per leading byte of the fingerprint (for example, `<root>/ab/ab01...`). Unlike an
+/// LMDB database, the entries can be synced individually and incrementally: with rsync, or to an
+/// object store.
+///
+/// Entries are written to a temporary file and then renamed into place, so a reader (or a sync)
+/// never observes a partially written entry. The lease of an entry is the modification time of
+/// its file, which is set when the entry is written or leased.
+///
+#[derive(Clone)]
+pub struct DirectoryStore {
+  inner: Arc<DirectoryStoreInner>,
+}
+
+struct DirectoryStoreInner {
+  root: PathBuf,
+  lease_time: Duration,
+  executor: task_executor::Executor,
+}
+
+impl DirectoryStore {
+  pub fn new(
+    root: PathBuf,
+    lease_time: Duration,
+    executor: task_executor::Executor,
+  ) -> Result<DirectoryStore, String> {
+    std::fs::create_dir_all(&root).map_err(|err| {
+      format!(
+        "Error creating process cache directory {}: {}",
+        root.display(),
+        err
+      )
+    })?;
+    Ok(DirectoryStore {
+      inner: Arc::new(DirectoryStoreInner {
+        root,
+        lease_time,
+        executor,
+      }),
+    })
+  }
+
+  fn shard_dir(&self, fingerprint: Fingerprint) -> PathBuf {
+    self.inner.root.join(format!("{:02x}", fingerprint.0[0]))
+  }
+
+  fn path(&self, fingerprint: Fingerprint) -> PathBuf {
+    self.shard_dir(fingerprint).join(fingerprint.to_hex())
+  }
+
+  async fn spawn_blocking<T: Send + 'static>(
+    &self,
+    f: impl FnOnce(DirectoryStore) -> Result<T, String> + Send + 'static,
+  ) -> Result<T, String> {
+    let store = self.clone();
+    self.inner.executor.spawn_blocking(move || f(store)).await
+  }
+
+  ///
+  /// Writes the bytes to a temporary file in the entry's shard, and then moves it into place,
+  /// unless `replace` is false and the entry already exists. Returns whether the entry was
+  /// written.
+  ///
+  fn put(&self, fingerprint: Fingerprint, bytes: &[u8], replace: bool) -> Result<bool, String> {
+    let path = self.path(fingerprint);
+    let shard_dir = self.shard_dir(fingerprint);
+    let write_err = |err: io::Error| {
+      format!(
+        "Error writing process cache file {}: {}",
+        path.display(),
+        err
+      )
+    };
+    std::fs::create_dir_all(&shard_dir).map_err(write_err)?;
+    let mut file = tempfile::NamedTempFile::new_in(&shard_dir).map_err(write_err)?;
+    file.write_all(bytes).map_err(write_err)?;
+    let persisted = if replace {
+      file.persist(&path)
+    } else {
+      file.persist_noclobber(&path)
+    };
+    match persisted {
+      Ok(_) => Ok(true),
+      Err(err) if !replace && err.error.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+      Err(err) => Err(write_err(err.error)),
+    }
+  }
+}
+
+#[async_trait]
+impl ProcessExecutionStore for DirectoryStore {
+  fn executor(&self) -> &task_executor::Executor {
+    &self.inner.executor
+  }
+
+  fn lease_time(&self) -> Duration {
+    self.inner.lease_time
+  }
+
+  fn is_read_only(&self) -> bool {
+    tempfile::tempfile_in(&self.inner.root).is_err()
+  }
+
+  async fn load_bytes(&self, fingerprint: Fingerprint) -> Result<Option<Bytes>, String> {
+    self
+      .spawn_blocking(move |store| {
+        let path = store.path(fingerprint);
+        match std::fs::read(&path) {
+          Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+          Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+          Err(err) => Err(format!(
+            "Error reading process cache file {}: {}",
+            path.display(),
+            err
+          )),
+        }
+      })
+      .await
+  }
+
+  async fn exists(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    self
+      .spawn_blocking(move |store| Ok(store.path(fingerprint).is_file()))
+      .await
+  }
+
+  async fn store_bytes_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    _initial_lease: bool,
+  ) -> Result<bool, String> {
+    self
+      .spawn_blocking(move |store| store.put(fingerprint, &bytes, false))
+      .await
+  }
+
+  async fn replace_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    _initial_lease: bool,
+  ) -> Result<(), String> {
+    self
+      .spawn_blocking(move |store| store.put(fingerprint, &bytes, true).map(|_| ()))
+      .await
+  }
+
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    self
+      .spawn_blocking(move |store| {
+        let path = store.path(fingerprint);
+        match std::fs::remove_file(&path) {
+          Ok(()) => Ok(true),
+          Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+          Err(err) => Err(format!(
+            "Error removing process cache file {}: {}",
+            path.display(),
+            err
+          )),
+        }
+      })
+      .await
+  }
+
+  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    self
+      .spawn_blocking(move |store| {
+        let path = store.path(fingerprint);
+        match filetime::set_file_mtime(&path, FileTime::from_system_time(SystemTime::now())) {
+          // As with ShardedLmdb, leasing a missing entry is not an error.
+          Err(err) if err.kind() != io::ErrorKind::NotFound => Err(format!(
+            "Error leasing process cache file {}: {}",
+            path.display(),
+            err
+          )),
+          _ => Ok(()),
+        }
+      })
+      .await
+  }
+
+  async fn all_entry_metadata(&self, _parallelism: usize) -> Result<Vec<EntryMetadata>, String> {
+    self
+      .spawn_blocking(|store| {
+        let read_err = |path: &PathBuf, err: io::Error| {
+          format!(
+            "Error scanning process cache directory {}: {}",
+            path.display(),
+            err
+          )
+        };
+        let mut entries = Vec::new();
+        let shards =
+          std::fs::read_dir(&store.inner.root).map_err(|err| read_err(&store.inner.root, err))?;
+        for shard in shards {
+          let shard_path = shard
+            .map_err(|err| read_err(&store.inner.root, err))?
+            .path();
+          if !shard_path.is_dir() {
+            continue;
+          }
+          for file in std::fs::read_dir(&shard_path).map_err(|err| read_err(&shard_path, err))? {
+            let file = file.map_err(|err| read_err(&shard_path, err))?;
+            // Skip temporary files, and anything else which is not an entry.
+            let fingerprint = match file
+              .file_name()
+              .to_str()
+              .and_then(|name| Fingerprint::from_hex_string(name).ok())
+            {
+              Some(fingerprint) => fingerprint,
+              None => continue,
+            };
+            let metadata = match file.metadata() {
+              Ok(metadata) => metadata,
+              // The entry was removed concurrently.
+              Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+              Err(err) => return Err(read_err(&file.path(), err)),
+            };
+            let leased_at = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+            entries.push(EntryMetadata {
+              fingerprint,
+              size_bytes: metadata.len() as usize,
+              leased_until_secs: (leased_at + store.inner.lease_time)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            });
+          }
+        }
+        Ok(entries)
+      })
+      .await
+  }
+}
+
+///
+/// A ProcessExecutionStore which holds its entries in memory, for fast and deterministic tests of
+/// the cache. As the simplest implementation, it is also the reference for the semantics of the
+/// trait.
+///
+/// Clones share their entries, so a test may inject write failures into a store which a cache is
+/// using.
+///
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MemoryStore {
+  /// The bytes of each entry, and when its lease expires (in seconds since the unix epoch).
+  entries: Arc<parking_lot::Mutex<std::collections::HashMap<Fingerprint, (Bytes, u64)>>>,
+  /// Whether writes fail.
+  fail_writes: Arc<std::sync::atomic::AtomicBool>,
+  lease_time: Duration,
+  executor: task_executor::Executor,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+  pub fn new(lease_time: Duration) -> MemoryStore {
+    MemoryStore {
+      entries: Arc::default(),
+      fail_writes: Arc::default(),
+      lease_time,
+      executor: task_executor::Executor::new(),
+    }
+  }
+
+  ///
+  /// Sets whether writes to the store fail.
+  ///
+  pub fn set_fail_writes(&self, fail_writes: bool) {
+    self
+      .fail_writes
+      .store(fail_writes, std::sync::atomic::Ordering::SeqCst);
+  }
+
+  fn check_writable(&self) -> Result<(), String> {
+    if self.fail_writes.load(std::sync::atomic::Ordering::SeqCst) {
+      Err("Injected write failure.".to_owned())
+    } else {
+      Ok(())
+    }
+  }
+
+  fn lease_until_secs(&self) -> u64 {
+    (SystemTime::now() + self.lease_time)
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs()
+  }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ProcessExecutionStore for MemoryStore {
+  fn executor(&self) -> &task_executor::Executor {
+    &self.executor
+  }
+
+  fn lease_time(&self) -> Duration {
+    self.lease_time
+  }
+
+  fn is_read_only(&self) -> bool {
+    false
+  }
+
+  async fn load_bytes(&self, fingerprint: Fingerprint) -> Result<Option<Bytes>, String> {
+    Ok(
+      self
+        .entries
+        .lock()
+        .get(&fingerprint)
+        .map(|(bytes, _)| bytes.clone()),
+    )
+  }
+
+  async fn exists(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    Ok(self.entries.lock().contains_key(&fingerprint))
+  }
+
+  async fn store_bytes_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<bool, String> {
+    self.check_writable()?;
+    let leased_until_secs = if initial_lease {
+      self.lease_until_secs()
+    } else {
+      0
+    };
+    let mut entries = self.entries.lock();
+    if entries.contains_key(&fingerprint) {
+      return Ok(false);
+    }
+    entries.insert(fingerprint, (bytes, leased_until_secs));
+    Ok(true)
+  }
+
+  async fn replace_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    self.check_writable()?;
+    let mut entries = self.entries.lock();
+    let leased_until_secs = if initial_lease {
+      self.lease_until_secs()
+    } else {
+      // As with ShardedLmdb, replacing an entry without a lease keeps its existing lease.
+      entries
+        .get(&fingerprint)
+        .map_or(0, |(_, leased_until_secs)| *leased_until_secs)
+    };
+    entries.insert(fingerprint, (bytes, leased_until_secs));
+    Ok(())
+  }
+
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    Ok(self.entries.lock().remove(&fingerprint).is_some())
+  }
+
+  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    let leased_until_secs = self.lease_until_secs();
+    if let Some((_, lease)) = self.entries.lock().get_mut(&fingerprint) {
+      *lease = leased_until_secs;
+    }
+    Ok(())
+  }
+
+  async fn all_entry_metadata(&self, _parallelism: usize) -> Result<Vec<EntryMetadata>, String> {
+    Ok(
+      self
+        .entries
+        .lock()
+        .iter()
+        .map(|(fingerprint, (bytes, leased_until_secs))| EntryMetadata {
+          fingerprint: *fingerprint,
+          size_bytes: bytes.len(),
+          leased_until_secs: *leased_until_secs,
+        })
+        .collect(),
+    )
+  }
+}
@@ -1,17 +1,34 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use sharded_lmdb::{ShardedLmdb, DEFAULT_LEASE_TIME};
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bytes::Bytes;
+use futures::FutureExt;
+use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+use prost::Message;
+use sharded_lmdb::{EntryMetadata, ShardedLmdb, DEFAULT_LEASE_TIME};
 use store::Store;
 use tempfile::TempDir;
-use testutil::data::TestData;
+use testutil::data::{TestData, TestDirectory};
 use testutil::relative_paths;
 use workunit_store::{RunningWorkunit, WorkunitStore};
 
+use crate::cache::{
+  namespace_key_prefix, redact_patterns, relativize_absolute_paths, train_compression_dictionary,
+  AuditReport, CacheAdmin, CacheEntry, CircuitBreaker, CircuitBreakerOptions, CommandRunner,
+  CoverageReport, EvictionPolicy, FillWatermarkOptions, LocalCacheCounters, LocalCacheOptions,
+  MaterializePolicy, MemoryPressurePolicy, NamedStoreOptions, SyncOutputsFn, ValidationStatus,
+};
+use crate::cache_store::{DirectoryStore, MemoryStore};
 use crate::{
-  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform, NamedCaches,
-  Process, ProcessMetadata,
+  CacheTier, CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess, NamedCaches, Platform, Process, ProcessCacheScope, ProcessMetadata,
+  ProcessResultMetadata, ProcessResultSource, SessionCacheStats,
 };
 
 struct RoundtripResults {
@@ -38,7 +55,15 @@ fn create_local_runner() -> (Box<dyn CommandRunnerTrait>, Store, TempDir) {
 fn create_cached_runner(
   local: Box<dyn CommandRunnerTrait>,
   store: Store,
-) -> (Box<dyn CommandRunnerTrait>, TempDir) {
+) -> (CommandRunner, TempDir) {
+  create_cached_runner_with_options(local, store, LocalCacheOptions::default())
+}
+
+fn create_cached_runner_with_options(
+  local: Box<dyn CommandRunnerTrait>,
+  store: Store,
+  options: LocalCacheOptions,
+) -> (CommandRunner, TempDir) {
   let runtime = task_executor::Executor::new();
   let cache_dir = TempDir::new().unwrap();
   let max_lmdb_size = 50 * 1024 * 1024; //50 MB - I didn't pick that number but it seems reasonable.
@@ -52,16 +77,34 @@ fn create_cached_runner(
   )
   .unwrap();
 
-  let runner = Box::new(crate::cache::CommandRunner::new(
+  let runner = CommandRunner::new(
     local.into(),
     process_execution_store,
     store,
     ProcessMetadata::default(),
-  ));
+    options,
+  );
 
   (runner, cache_dir)
 }
 
+///
+/// Like `create_cached_runner_with_options`, but caches in memory rather than in LMDB.
+///
+fn create_in_memory_cached_runner(
+  local: Box<dyn CommandRunnerTrait>,
+  store: Store,
+  options: LocalCacheOptions,
+) -> CommandRunner {
+  CommandRunner::new_with_store(
+    local.into(),
+    Arc::new(MemoryStore::new(DEFAULT_LEASE_TIME)),
+    store,
+    ProcessMetadata::default(),
+    options,
+  )
+}
+
 fn create_script(script_exit_code: i8) -> (Process, PathBuf, TempDir) {
   let script_dir = TempDir::new().unwrap();
   let script_path = script_dir.path().join("script");
@@ -85,6 +128,23 @@ fn create_script(script_exit_code: i8) -> (Process, PathBuf, TempDir) {
   (process, script_path, script_dir)
 }
 
+///
+/// Records `TestDirectory::recursive()` and all of its contents in the given Store.
+///
+async fn record_recursive_directory(store: &Store) -> Digest {
+  for data in vec![TestData::roland(), TestData::catnip()] {
+    store.store_file_bytes(data.bytes(), false).await.unwrap();
+  }
+  store
+    .record_directory(&TestDirectory::containing_roland().directory(), false)
+    .await
+    .unwrap();
+  store
+    .record_directory(&TestDirectory::recursive().directory(), false)
+    .await
+    .unwrap()
+}
+
 async fn run_roundtrip(script_exit_code: i8, workunit: &mut RunningWorkunit) -> RoundtripResults {
   let (local, store, _local_runner_dir) = create_local_runner();
   let (process, script_path, _script_dir) = create_script(script_exit_code);
@@ -115,6 +175,30 @@ async fn run_roundtrip(script_exit_code: i8, workunit: &mut RunningWorkunit) ->
   }
 }
 
+async fn remove_first_output_file(store: &Store, output_dir_digest: Digest) {
+  let output_dir = store
+    .load_directory(output_dir_digest)
+    .await
+    .unwrap()
+    .unwrap();
+  let output_child_digest = output_dir
+    .files
+    .first()
+    .unwrap()
+    .digest
+    .as_ref()
+    .unwrap()
+    .try_into()
+    .unwrap();
+  let removed = store.remove_file(output_child_digest).await.unwrap();
+  assert!(removed);
+  assert!(store
+    .contents_for_directory(output_dir_digest)
+    .await
+    .err()
+    .is_some())
+}
+
 #[tokio::test]
 async fn cache_success() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();
@@ -131,6 +215,58 @@ async fn failures_not_cached() {
   assert_eq!(results.maybe_cached.unwrap().exit_code, 127); // aka the return code for file not found
 }
 
+#[tokio::test]
+async fn never_cached() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (mut process, script_path, _script_dir) = create_script(0);
+  process.cache_scope = ProcessCacheScope::Never;
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(result.exit_code, 0);
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+
+  // Nothing was stored, so the process is run again (and fails without its script).
+  std::fs::remove_file(&script_path).unwrap();
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.exit_code, 127);
+}
+
+#[tokio::test]
+async fn non_hermetic_processes_bypass_the_cache() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, script_path, _script_dir) = create_script(0);
+
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(caching.stats().await.unwrap().entries, 1);
+
+  // The same process is cached, but if it is not hermetic it is run again (and fails without
+  // its script) rather than hitting.
+  std::fs::remove_file(&script_path).unwrap();
+  let result = caching
+    .run(
+      Context::default(),
+      &mut workunit,
+      process.hermetic(false).into(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(result.exit_code, 127);
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+}
+
 #[tokio::test]
 async fn recover_from_missing_store_contents() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();
@@ -147,30 +283,7 @@ async fn recover_from_missing_store_contents() {
 
   // Delete the first child of the output directory parent to confirm that we ensure that more
   // than just the root of the output is present when hitting the cache.
-  {
-    let output_dir_digest = first_result.output_directory;
-    let output_dir = store
-      .load_directory(output_dir_digest)
-      .await
-      .unwrap()
-      .unwrap();
-    let output_child_digest = output_dir
-      .files
-      .first()
-      .unwrap()
-      .digest
-      .as_ref()
-      .unwrap()
-      .try_into()
-      .unwrap();
-    let removed = store.remove_file(output_child_digest).await.unwrap();
-    assert!(removed);
-    assert!(store
-      .contents_for_directory(output_dir_digest)
-      .await
-      .err()
-      .is_some())
-  }
+  remove_first_output_file(&store, first_result.output_directory).await;
 
   // Ensure that we don't fail if we re-run.
   let second_result = caching
@@ -185,3 +298,4114 @@ async fn recover_from_missing_store_contents() {
     .ok()
     .is_some())
 }
+
+#[tokio::test]
+async fn lookup_respects_materialize_policy() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  remove_first_output_file(&store, result.output_directory).await;
+
+  // Only the policy which ensures the output directory should notice the missing file.
+  let key = caching.fingerprint(&process.into());
+  assert!(caching.lookup(key, MaterializePolicy::All).await.is_err());
+  for policy in vec![
+    MaterializePolicy::OutputTreeOnly,
+    MaterializePolicy::StdoutStderrOnly,
+    MaterializePolicy::Lazy,
+  ] {
+    let hit = caching.lookup(key, policy).await.unwrap().unwrap();
+    assert_eq!(hit.output_directory, result.output_directory);
+  }
+}
+
+#[tokio::test]
+async fn fast_unsafe_reads() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      fast_unsafe_reads: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  remove_first_output_file(&store, result.output_directory).await;
+
+  // The hit is served despite the missing file...
+  let key = caching.fingerprint(&process.into());
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.output_directory, result.output_directory);
+
+  // ...but the entry is removed once the background verification notices it.
+  let mut attempts = 0;
+  while caching.raw_entry(key, false).await.unwrap().is_some() {
+    attempts += 1;
+    assert!(attempts < 100, "The entry was not removed.");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+}
+
+#[tokio::test]
+async fn default_materialize_outputs() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      default_materialize_outputs: false,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  remove_first_output_file(&store, result.output_directory).await;
+
+  // By default, the missing output file goes unnoticed...
+  let hit = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitLocally);
+
+  // ...but a process which requires its outputs to be materialized is re-run.
+  let rerun = caching
+    .run(
+      Context::default(),
+      &mut workunit,
+      process.materialize_cached_outputs(true).into(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(rerun.metadata.source, ProcessResultSource::RanLocally);
+  assert!(store
+    .contents_for_directory(rerun.output_directory)
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn lookup_defers_materialization_of_many_files() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      max_materialize_files: Some(0),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  remove_first_output_file(&store, result.output_directory).await;
+
+  // The output file exceeds the limit, so it is left to be fetched on demand rather than being
+  // ensured (which would notice that it is missing).
+  let key = caching.fingerprint(&process.into());
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.output_directory, result.output_directory);
+}
+
+#[tokio::test]
+async fn store_canonicalizes_output_directory() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+
+  // Record two equivalent trees whose files, directories and symlinks are in different orders,
+  // both in their roots and in a subdirectory.
+  let file = |name: &str| remexec::FileNode {
+    name: name.to_owned(),
+    digest: Some((&EMPTY_DIGEST).into()),
+    ..remexec::FileNode::default()
+  };
+  let symlink = |name: &str| remexec::SymlinkNode {
+    name: name.to_owned(),
+    target: "target".to_owned(),
+    ..remexec::SymlinkNode::default()
+  };
+  let directory = |name: &str, digest: Digest| remexec::DirectoryNode {
+    name: name.to_owned(),
+    digest: Some((&digest).into()),
+  };
+  let sorted_child = store
+    .record_directory(
+      &remexec::Directory {
+        files: vec![file("c"), file("d")],
+        ..remexec::Directory::default()
+      },
+      true,
+    )
+    .await
+    .unwrap();
+  let unsorted_child = store
+    .record_directory(
+      &remexec::Directory {
+        files: vec![file("d"), file("c")],
+        ..remexec::Directory::default()
+      },
+      true,
+    )
+    .await
+    .unwrap();
+  let sorted = remexec::Directory {
+    files: vec![file("a"), file("b")],
+    directories: vec![directory("x", sorted_child), directory("y", EMPTY_DIGEST)],
+    symlinks: vec![symlink("a"), symlink("b")],
+    ..remexec::Directory::default()
+  };
+  let unsorted = remexec::Directory {
+    files: vec![file("b"), file("a")],
+    directories: vec![directory("y", EMPTY_DIGEST), directory("x", unsorted_child)],
+    symlinks: vec![symlink("b"), symlink("a")],
+    ..remexec::Directory::default()
+  };
+  let sorted_digest = store.record_directory(&sorted, true).await.unwrap();
+  let unsorted_digest = store.record_directory(&unsorted, true).await.unwrap();
+  assert_ne!(sorted_digest, unsorted_digest);
+
+  let result_for = |output_directory| FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let sorted_key = Digest::of_bytes(b"sorted").hash;
+  let unsorted_key = Digest::of_bytes(b"unsorted").hash;
+  caching
+    .store(sorted_key, &result_for(sorted_digest))
+    .await
+    .unwrap();
+  caching
+    .store(unsorted_key, &result_for(unsorted_digest))
+    .await
+    .unwrap();
+
+  let stored_digest = |key| {
+    let caching = caching.clone();
+    async move {
+      caching
+        .lookup(key, MaterializePolicy::Lazy)
+        .await
+        .unwrap()
+        .unwrap()
+        .output_directory
+    }
+  };
+  assert_eq!(stored_digest(sorted_key).await, sorted_digest);
+  assert_eq!(stored_digest(unsorted_key).await, sorted_digest);
+}
+
+#[tokio::test]
+async fn store_skips_unavailable_output_directory() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+
+  // The output directory was never recorded in the Store.
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: TestDirectory::containing_roland().digest(),
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"unavailable").hash;
+  caching.store(key, &result).await.unwrap();
+  assert!(caching
+    .lookup(key, MaterializePolicy::Lazy)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn ttl_remaining() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+  let ttl = Duration::from_secs(60 * 60);
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      ttl: Some(ttl),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let key = caching.fingerprint(&process.clone().into());
+  assert_eq!(caching.ttl_remaining(key).await.unwrap(), None);
+
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  let remaining = caching.ttl_remaining(key).await.unwrap().unwrap();
+  assert!(remaining <= ttl);
+  assert!(remaining > ttl - Duration::from_secs(60));
+
+  // An entry whose process expires sooner than the TTL reports its own deadline.
+  let mut expiring = process.clone();
+  expiring
+    .env
+    .insert("EXPIRING".to_owned(), "true".to_owned());
+  expiring.expires_at = Some(SystemTime::now() + Duration::from_secs(60));
+  let expiring_key = caching.fingerprint(&expiring.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, expiring.clone().into())
+    .await
+    .unwrap();
+  let remaining = caching.ttl_remaining(expiring_key).await.unwrap().unwrap();
+  assert!(remaining <= Duration::from_secs(60));
+
+  // Without a TTL, there is no remaining time to report...
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(caching.ttl_remaining(key).await.unwrap(), None);
+
+  // ...unless the entry itself expires.
+  caching
+    .run(Context::default(), &mut workunit, expiring.into())
+    .await
+    .unwrap();
+  let remaining = caching.ttl_remaining(expiring_key).await.unwrap().unwrap();
+  assert!(remaining <= Duration::from_secs(60));
+  assert!(remaining > Duration::ZERO);
+}
+
+#[tokio::test]
+async fn ttl_expiry_with_fake_clock() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+  let ttl = Duration::from_secs(60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      ttl: Some(ttl),
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(caching.ttl_remaining(key).await.unwrap(), Some(ttl));
+
+  *now.lock() += Duration::from_secs(59);
+  assert_eq!(
+    caching.ttl_remaining(key).await.unwrap(),
+    Some(Duration::from_secs(1))
+  );
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+
+  *now.lock() += Duration::from_secs(1);
+  assert_eq!(
+    caching.ttl_remaining(key).await.unwrap(),
+    Some(Duration::from_secs(0))
+  );
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn migrate() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  let old_key = caching.fingerprint(&process.into());
+  let new_key = Digest::of_bytes(b"new key scheme").hash;
+
+  // Entries for which no new key can be computed are left in place.
+  assert_eq!(caching.migrate(|_| None).await.unwrap(), 0);
+  assert!(caching
+    .lookup(old_key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+
+  let migrated = caching
+    .migrate(|entry| {
+      assert_eq!(entry.fingerprint, old_key);
+      Some(new_key)
+    })
+    .await
+    .unwrap();
+  assert_eq!(migrated, 1);
+  assert!(caching
+    .lookup(old_key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+  let migrated_result = caching
+    .lookup(new_key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(migrated_result, result);
+}
+
+#[tokio::test]
+async fn platform_compatibility_fn() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      platform_compatibility_fn: Some(Arc::new(|stored, current| stored != current)),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+
+  // The entry was stored for the current platform, which the function deems incompatible.
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn contains_many() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (success, _success_path, _success_dir) = create_script(0);
+  let (failure, _failure_path, _failure_dir) = create_script(1);
+  let success_key = caching.fingerprint(&success.clone().into());
+  let failure_key = caching.fingerprint(&failure.clone().into());
+
+  assert_eq!(
+    caching
+      .contains_many(&[success_key, failure_key])
+      .await
+      .unwrap(),
+    vec![false, false]
+  );
+
+  for process in vec![success, failure] {
+    caching
+      .run(Context::default(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+
+  // Only the successful result will have been cached.
+  assert_eq!(
+    caching
+      .contains_many(&[failure_key, success_key, failure_key])
+      .await
+      .unwrap(),
+    vec![false, true, false]
+  );
+}
+
+#[tokio::test]
+async fn coverage() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (success, _success_path, _success_dir) = create_script(0);
+  let (failure, _failure_path, _failure_dir) = create_script(1);
+  let mut non_hermetic = success.clone();
+  non_hermetic.hermetic = false;
+  let requests: Vec<MultiPlatformProcess> = vec![
+    success.clone().into(),
+    failure.clone().into(),
+    non_hermetic.into(),
+  ];
+  let failure_key = caching.fingerprint(&failure.clone().into());
+
+  for process in vec![success, failure] {
+    caching
+      .run(Context::default(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+
+  // Only the successful result will have been cached, and the non-hermetic process never is.
+  let report = caching
+    .coverage(&Context::default(), &requests)
+    .await
+    .unwrap();
+  assert_eq!(
+    report,
+    CoverageReport {
+      total: 2,
+      cached: 1,
+      uncached: vec![failure_key],
+      skipped: 1,
+    }
+  );
+  assert!((report.fraction() - 0.5).abs() < f64::EPSILON);
+  assert!((CoverageReport::default().fraction() - 1.0).abs() < f64::EPSILON);
+
+  // Nothing is checked when the Context says that nothing will be cached.
+  let report = caching
+    .coverage(
+      &Context::default().with_cache_scope_override(ProcessCacheScope::Never),
+      &requests,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    report,
+    CoverageReport {
+      total: 0,
+      cached: 0,
+      uncached: vec![],
+      skipped: 3,
+    }
+  );
+}
+
+#[tokio::test]
+async fn coverage_of_expired_entries() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      ttl: None,
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (mut process, _script_path, _script_dir) = create_script(0);
+  process.expires_at = Some(*now.lock() + Duration::from_secs(60));
+  let key = caching.fingerprint(&process.clone().into());
+  let requests: Vec<MultiPlatformProcess> = vec![process.clone().into()];
+
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(
+    caching
+      .coverage(&Context::default(), &requests)
+      .await
+      .unwrap()
+      .cached,
+    1
+  );
+
+  // Even without a TTL, an entry which is past its `expires_at` would miss, so is not covered.
+  *now.lock() += Duration::from_secs(60);
+  assert_eq!(caching.contains_many(&[key]).await.unwrap(), vec![false]);
+  assert_eq!(
+    caching
+      .coverage(&Context::default(), &requests)
+      .await
+      .unwrap(),
+    CoverageReport {
+      total: 1,
+      cached: 0,
+      uncached: vec![key],
+      skipped: 0,
+    }
+  );
+}
+
+#[tokio::test]
+async fn stats_and_gc() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      max_total_bytes: Some(1024 * 1024),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let empty_stats = caching.stats().await.unwrap();
+  assert_eq!(empty_stats.used_bytes, 0);
+  assert_eq!(empty_stats.fill_fraction, Some(0.0));
+
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  let stats = caching.stats().await.unwrap();
+  assert!(stats.used_bytes > 0);
+  assert_eq!(
+    stats.fill_fraction,
+    Some(stats.used_bytes as f64 / (1024 * 1024) as f64)
+  );
+
+  // Shrinking to a size smaller than the entry evicts it.
+  assert_eq!(caching.gc(0).await.unwrap(), 0);
+  assert_eq!(caching.stats().await.unwrap().used_bytes, 0);
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn max_entries() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      max_entries: Some(3),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let keys = (0..4)
+    .map(|i| Digest::of_bytes(format!("entry {}", i).as_bytes()).hash)
+    .collect::<Vec<_>>();
+
+  for key in &keys[..3] {
+    caching.store(*key, &result).await.unwrap();
+  }
+  let stats = caching.stats().await.unwrap();
+  assert_eq!(stats.entries, 3);
+  assert_eq!(stats.max_entries, Some(3));
+
+  // Exceeding the limit evicts down to below it.
+  caching.store(keys[3], &result).await.unwrap();
+  assert_eq!(caching.stats().await.unwrap().entries, 2);
+  let present = caching.contains_many(&keys).await.unwrap();
+  assert_eq!(present.into_iter().filter(|p| *p).count(), 2);
+
+  assert_eq!(caching.gc_entries(0).await.unwrap(), 0);
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+}
+
+#[tokio::test]
+async fn max_referenced_blob_bytes() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      max_referenced_blob_bytes: Some(150),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let mut keys = Vec::new();
+  for (i, output) in vec![b'a', b'b'].into_iter().enumerate() {
+    let stdout_digest = store
+      .store_file_bytes(Bytes::from(vec![output; 100]), false)
+      .await
+      .unwrap();
+    let key = Digest::of_bytes(format!("entry {}", i).as_bytes()).hash;
+    caching
+      .store(
+        key,
+        &FallibleProcessResultWithPlatform {
+          stdout_digest,
+          stderr_digest: EMPTY_DIGEST,
+          exit_code: 0,
+          output_directory: EMPTY_DIGEST,
+          platform: Platform::current().unwrap(),
+          metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+        },
+      )
+      .await
+      .unwrap();
+    keys.push(key);
+  }
+
+  // The second entry took the referenced blobs over the budget, so one entry was evicted.
+  let present = caching.contains_many(&keys).await.unwrap();
+  assert_eq!(present.into_iter().filter(|p| *p).count(), 1);
+  assert_eq!(caching.referenced_blob_bytes().await.unwrap(), 100);
+
+  assert_eq!(caching.gc_referenced_blobs(0).await.unwrap(), 1);
+  assert_eq!(caching.referenced_blob_bytes().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn fill_watermark() {
+  let fired = Arc::new(AtomicUsize::new(0));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      max_total_bytes: Some(1024 * 1024),
+      fill_watermark: Some(FillWatermarkOptions {
+        fraction: 0.000_001,
+        min_interval: Duration::from_secs(0),
+        callback: {
+          let fired = fired.clone();
+          Arc::new(move || {
+            fired.fetch_add(1, Ordering::SeqCst);
+          })
+        },
+      }),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let store_entry = |name: &str| {
+    let caching = caching.clone();
+    let result = result.clone();
+    let key = Digest::of_bytes(name.as_bytes()).hash;
+    async move { caching.store(key, &result).await.unwrap() }
+  };
+
+  // The callback fires when the watermark is crossed, but not again while the cache stays above it.
+  store_entry("first").await;
+  assert_eq!(fired.load(Ordering::SeqCst), 1);
+  store_entry("second").await;
+  assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+  // Once the cache has fallen below the watermark, crossing it fires again.
+  caching.gc(0).await.unwrap();
+  store_entry("third").await;
+  assert_eq!(fired.load(Ordering::SeqCst), 2);
+}
+
+fn eviction_order(
+  policy: EvictionPolicy,
+  entries: &[EntryMetadata],
+  pins: &BTreeSet<Fingerprint>,
+  hit_counts: &HashMap<Fingerprint, u64>,
+) -> Vec<usize> {
+  let mut sorted = entries.to_vec();
+  policy.sort_for_eviction(&mut sorted, pins, hit_counts);
+  sorted
+    .into_iter()
+    .map(|entry| entries.iter().position(|e| *e == entry).unwrap())
+    .collect()
+}
+
+///
+/// Entries (named by their index) which are: 0) large and old, 1) small and old, 2) small and new
+/// and frequently hit, 3) large and new.
+///
+fn eviction_test_entries() -> (Vec<EntryMetadata>, HashMap<Fingerprint, u64>) {
+  let entry = |name: &str, size_bytes, leased_until_secs| EntryMetadata {
+    fingerprint: Digest::of_bytes(name.as_bytes()).hash,
+    size_bytes,
+    leased_until_secs,
+  };
+  let entries = vec![
+    entry("0", 100, 10),
+    entry("1", 10, 10),
+    entry("2", 10, 20),
+    entry("3", 100, 20),
+  ];
+  let hit_counts = vec![(entries[1].fingerprint, 1), (entries[2].fingerprint, 5)]
+    .into_iter()
+    .collect();
+  (entries, hit_counts)
+}
+
+#[test]
+fn eviction_policy_lru() {
+  let (entries, hit_counts) = eviction_test_entries();
+  let order = eviction_order(EvictionPolicy::Lru, &entries, &BTreeSet::new(), &hit_counts);
+  // Older entries are evicted first: ties are broken (arbitrarily, but deterministically) by
+  // fingerprint.
+  assert_eq!(
+    order[..2].iter().copied().collect::<BTreeSet<_>>(),
+    vec![0, 1].into_iter().collect()
+  );
+  assert_eq!(
+    order[2..].iter().copied().collect::<BTreeSet<_>>(),
+    vec![2, 3].into_iter().collect()
+  );
+  assert_eq!(
+    order,
+    eviction_order(EvictionPolicy::Lru, &entries, &BTreeSet::new(), &hit_counts)
+  );
+
+  // Pinned entries are evicted last.
+  let pins = vec![entries[0].fingerprint, entries[1].fingerprint]
+    .into_iter()
+    .collect();
+  let order = eviction_order(EvictionPolicy::Lru, &entries, &pins, &hit_counts);
+  assert_eq!(
+    order[2..].iter().copied().collect::<BTreeSet<_>>(),
+    vec![0, 1].into_iter().collect()
+  );
+}
+
+#[test]
+fn eviction_policy_lru_smallest_first() {
+  let (entries, hit_counts) = eviction_test_entries();
+  assert_eq!(
+    eviction_order(
+      EvictionPolicy::LruSmallestFirst,
+      &entries,
+      &BTreeSet::new(),
+      &hit_counts
+    ),
+    vec![1, 0, 2, 3]
+  );
+}
+
+#[test]
+fn eviction_policy_lfu() {
+  let (entries, hit_counts) = eviction_test_entries();
+  // Entries which were never hit are evicted first (oldest first), then by increasing hits.
+  assert_eq!(
+    eviction_order(EvictionPolicy::Lfu, &entries, &BTreeSet::new(), &hit_counts),
+    vec![0, 3, 1, 2]
+  );
+}
+
+#[test]
+fn dedupe_outputs() {
+  let file = |path: &str, content: &[u8]| remexec::OutputFile {
+    path: path.to_owned(),
+    digest: Some((&Digest::of_bytes(content)).into()),
+    ..remexec::OutputFile::default()
+  };
+
+  // Exact duplicates are removed, and outputs are sorted.
+  let mut action_result = remexec::ActionResult {
+    output_files: vec![file("b", b"b"), file("a", b"a"), file("b", b"b")],
+    ..remexec::ActionResult::default()
+  };
+  crate::cache::dedupe_outputs(&mut action_result).unwrap();
+  assert_eq!(
+    action_result.output_files,
+    vec![file("a", b"a"), file("b", b"b")]
+  );
+
+  // Distinct outputs for the same path are ambiguous.
+  let mut action_result = remexec::ActionResult {
+    output_files: vec![file("a", b"a"), file("a", b"other")],
+    ..remexec::ActionResult::default()
+  };
+  assert!(crate::cache::dedupe_outputs(&mut action_result).is_err());
+
+  let mut action_result = remexec::ActionResult {
+    output_files: vec![file("a", b"a")],
+    output_directories: vec![remexec::OutputDirectory {
+      path: "a".to_owned(),
+      tree_digest: Some((&EMPTY_DIGEST).into()),
+    }],
+    ..remexec::ActionResult::default()
+  };
+  assert!(crate::cache::dedupe_outputs(&mut action_result).is_err());
+
+  // As are paths which are not normalized.
+  for path in vec!["../a", "/a", "a/", "a//b", "./a", "a/.", ""] {
+    let mut action_result = remexec::ActionResult {
+      output_files: vec![file(path, b"a")],
+      ..remexec::ActionResult::default()
+    };
+    assert!(
+      crate::cache::dedupe_outputs(&mut action_result).is_err(),
+      "{:?} should have been rejected",
+      path
+    );
+  }
+
+  // Other than the empty path of the root output directory.
+  let directory = |path: &str| remexec::OutputDirectory {
+    path: path.to_owned(),
+    tree_digest: Some((&EMPTY_DIGEST).into()),
+  };
+  let mut action_result = remexec::ActionResult {
+    output_files: vec![file("a/b", b"b")],
+    output_directories: vec![directory("")],
+    ..remexec::ActionResult::default()
+  };
+  crate::cache::dedupe_outputs(&mut action_result).unwrap();
+  let mut action_result = remexec::ActionResult {
+    output_directories: vec![directory("a//b")],
+    ..remexec::ActionResult::default()
+  };
+  assert!(crate::cache::dedupe_outputs(&mut action_result).is_err());
+}
+
+#[tokio::test]
+async fn pinned_entries_are_evicted_last() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (first, _first_path, _first_dir) = create_script(0);
+  let (second, _second_path, _second_dir) = create_script(0);
+  let first_key = caching.fingerprint(&first.clone().into());
+  let second_key = caching.fingerprint(&second.clone().into());
+  for process in vec![first, second] {
+    caching
+      .run(Context::default(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+
+  caching.pin(first_key).await.unwrap();
+  assert!(caching.pinned().await.unwrap().contains(&first_key));
+
+  // Shrinking by a single entry evicts the unpinned one, and leaves the pinned one.
+  let used_bytes = caching.stats().await.unwrap().used_bytes;
+  caching.gc(used_bytes as usize - 1).await.unwrap();
+  assert_eq!(
+    caching
+      .contains_many(&[first_key, second_key])
+      .await
+      .unwrap(),
+    vec![true, false]
+  );
+
+  // But pinned entries are still evicted as a last resort.
+  caching.gc(0).await.unwrap();
+  assert_eq!(
+    caching.contains_many(&[first_key]).await.unwrap(),
+    vec![false]
+  );
+
+  caching.unpin(first_key).await.unwrap();
+  assert!(caching.pinned().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn store_and_lookup_roundtrip() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+
+  // A result with non-empty stdout and stderr, and a nested output directory containing
+  // multiple files.
+  let stdout_digest = store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .unwrap();
+  let stderr_digest = store
+    .store_file_bytes(TestData::robin().bytes(), false)
+    .await
+    .unwrap();
+  let output_directory = record_recursive_directory(&store).await;
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest,
+    stderr_digest,
+    exit_code: 0,
+    output_directory,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(
+      Some(concrete_time::Duration::new(5, 150)),
+      ProcessResultSource::RanLocally,
+    ),
+  };
+
+  let key = Digest::of_bytes(b"roundtrip").hash;
+  caching.store(key, &result).await.unwrap();
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+
+  assert_eq!(hit, result);
+  assert_eq!(hit.metadata.total_elapsed, result.metadata.total_elapsed);
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitLocally);
+  assert_eq!(hit.metadata.cache_tier, Some(CacheTier::LocalDisk));
+
+  let load_file = |digest: Digest| {
+    let store = store.clone();
+    async move {
+      store
+        .load_file_bytes_with(digest, |bytes| bytes.to_vec())
+        .await
+        .unwrap()
+        .unwrap()
+    }
+  };
+  assert_eq!(
+    load_file(hit.stdout_digest).await,
+    TestData::roland().bytes().to_vec()
+  );
+  assert_eq!(
+    load_file(hit.stderr_digest).await,
+    TestData::robin().bytes().to_vec()
+  );
+
+  let contents = store
+    .contents_for_directory(hit.output_directory)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|file_content| {
+      (
+        file_content.path,
+        file_content.content,
+        file_content.is_executable,
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    contents,
+    vec![
+      (
+        PathBuf::from("cats/roland.ext"),
+        TestData::roland().bytes(),
+        false
+      ),
+      (
+        PathBuf::from("treats.ext"),
+        TestData::catnip().bytes(),
+        false
+      ),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn verify_on_write() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      verify_on_write: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  // A healthy store verifies successfully, and leaves the entry in place.
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+}
+
+#[tokio::test]
+async fn verify_digests_on_write() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      verify_digests_on_write: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  caching
+    .store(Digest::of_bytes(b"intact").hash, &result)
+    .await
+    .unwrap();
+  remove_first_output_file(&store, result.output_directory).await;
+  let dangling = Digest::of_bytes(b"dangling after removal").hash;
+  assert!(caching.store(dangling, &result).await.is_err());
+  assert_eq!(caching.raw_entry(dangling, false).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn cache_key_gen_version_partitions_keys() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+
+  // Two runners which share a store, but which differ in their cache_key_gen_version.
+  let runner_for_version = |version: &str| {
+    CommandRunner::new(
+      local.clone(),
+      process_execution_store.clone(),
+      store.clone(),
+      ProcessMetadata {
+        cache_key_gen_version: Some(version.to_owned()),
+        ..ProcessMetadata::default()
+      },
+      LocalCacheOptions::default(),
+    )
+  };
+  let v1 = runner_for_version("1");
+  let v2 = runner_for_version("2");
+
+  let (process, _script_path, _script_dir) = create_script(0);
+  let v1_key = v1.fingerprint(&process.clone().into());
+  let v2_key = v2.fingerprint(&process.clone().into());
+  assert_ne!(v1_key, v2_key);
+
+  v1.run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(
+    v2.contains_many(&[v1_key, v2_key]).await.unwrap(),
+    vec![true, false]
+  );
+  assert!(v2
+    .lookup(v2_key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn output_schema_version_partitions_keys() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, script_path, _script_dir) = create_script(0);
+  let action_digest = |process: &Process| {
+    let (_, _, execute_request) =
+      crate::remote::make_execute_request(process, ProcessMetadata::default()).unwrap();
+    bazel_protos::require_digest(execute_request.action_digest.as_ref()).unwrap()
+  };
+
+  // Without a version, the key is unchanged.
+  let unversioned_key = caching.fingerprint(&process.clone().into());
+  assert_eq!(
+    unversioned_key,
+    caching.action_digest_fingerprint(action_digest(&process))
+  );
+  let v1 = process.clone().output_schema_version(1);
+  let v2 = process.clone().output_schema_version(2);
+  let v1_key = caching.fingerprint(&v1.clone().into());
+  assert_ne!(v1_key, unversioned_key);
+  assert_ne!(v1_key, caching.fingerprint(&v2.clone().into()));
+
+  // The version is part of the Action, so the remote cache is partitioned by it too.
+  assert_ne!(action_digest(&v1), action_digest(&process));
+  assert_ne!(action_digest(&v1), action_digest(&v2));
+  assert_eq!(
+    v1_key,
+    caching.action_digest_fingerprint(action_digest(&v1))
+  );
+
+  // Bumping the version invalidates the cached result (so the process runs again, and fails
+  // without its script).
+  caching
+    .run(Context::default(), &mut workunit, v1.clone().into())
+    .await
+    .unwrap();
+  std::fs::remove_file(&script_path).unwrap();
+  let hit = caching
+    .run(Context::default(), &mut workunit, v1.into())
+    .await
+    .unwrap();
+  assert_eq!(hit.exit_code, 0);
+  let rerun = caching
+    .run(Context::default(), &mut workunit, v2.into())
+    .await
+    .unwrap();
+  assert_eq!(rerun.exit_code, 127);
+}
+
+#[tokio::test]
+async fn declared_outputs_partition_keys() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (captured, _script_path, _script_dir) = create_script(0);
+  let discarded = captured.clone().output_files(BTreeSet::new());
+  let as_directory = discarded
+    .clone()
+    .output_directories(relative_paths(&["roland"]).collect());
+  let other_file = captured
+    .clone()
+    .output_files(relative_paths(&["roland", "other"]).collect());
+
+  let keys = vec![&captured, &discarded, &as_directory, &other_file]
+    .into_iter()
+    .map(|process| caching.fingerprint(&process.clone().into()))
+    .collect::<BTreeSet<_>>();
+  assert_eq!(keys.len(), 4);
+
+  // A result which captured no outputs is not served to a request which expects them.
+  let result = caching
+    .run(Context::default(), &mut workunit, discarded.into())
+    .await
+    .unwrap();
+  assert_eq!(result.output_directory, EMPTY_DIGEST);
+  let result = caching
+    .run(Context::default(), &mut workunit, captured.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert_ne!(result.output_directory, EMPTY_DIGEST);
+}
+
+#[tokio::test]
+async fn audit() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  assert_eq!(
+    caching.audit().await.unwrap(),
+    AuditReport {
+      entries_checked: 1,
+      ..AuditReport::default()
+    }
+  );
+
+  // Once an output file is garbage collected from the file Store, the entry is dangling.
+  remove_first_output_file(&store, result.output_directory).await;
+  assert_eq!(
+    caching.audit().await.unwrap(),
+    AuditReport {
+      entries_checked: 1,
+      dangling: vec![(key, vec![TestData::roland().digest()])],
+      ..AuditReport::default()
+    }
+  );
+}
+
+#[tokio::test]
+async fn verify_sample() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  for i in 0..20 {
+    let key = Digest::of_bytes(format!("entry {}", i).as_bytes()).hash;
+    caching.store(key, &result).await.unwrap();
+  }
+
+  assert_eq!(
+    caching.verify_sample(0.0, None).await.unwrap(),
+    AuditReport::default()
+  );
+  assert_eq!(
+    caching.verify_sample(1.0, None).await.unwrap(),
+    AuditReport {
+      entries_checked: 20,
+      ..AuditReport::default()
+    }
+  );
+  assert!(caching.verify_sample(1.5, None).await.is_err());
+
+  // A seeded sample is reproducible.
+  let sample = caching.verify_sample(0.5, Some(7)).await.unwrap();
+  assert!(sample.entries_checked > 0 && sample.entries_checked < 20);
+  assert_eq!(caching.verify_sample(0.5, Some(7)).await.unwrap(), sample);
+}
+
+#[tokio::test]
+async fn validate() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  assert_eq!(
+    caching.validate(key).await.unwrap(),
+    ValidationStatus::Missing
+  );
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(
+    caching.validate(key).await.unwrap(),
+    ValidationStatus::Usable
+  );
+
+  remove_first_output_file(&store, result.output_directory).await;
+  assert_eq!(
+    caching.validate(key).await.unwrap(),
+    ValidationStatus::Dangling(vec![TestData::roland().digest()])
+  );
+}
+
+#[tokio::test]
+async fn validated_entries_skip_verification_on_lookup() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let verification_lifetime = Duration::from_secs(60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      verification_lifetime: Some(verification_lifetime),
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(
+    caching.validate(key).await.unwrap(),
+    ValidationStatus::Usable
+  );
+
+  // While the entry is verified, its outputs are not checked, so it is served even though one is
+  // now missing.
+  remove_first_output_file(&store, result.output_directory).await;
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+
+  // But once the verification lapses, they are checked again.
+  *now.lock() += verification_lifetime;
+  assert!(caching.lookup(key, MaterializePolicy::All).await.is_err());
+}
+
+#[tokio::test]
+async fn named_stores() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (routed, _routed_path, _routed_dir) = create_script(0);
+  let (unrouted, _unrouted_path, _unrouted_dir) = create_script(0);
+  let small_dir = TempDir::new().unwrap();
+  let small_store = ShardedLmdb::new(
+    small_dir.path().to_owned(),
+    1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let routed_argv = routed.argv.clone();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      named_stores: vec![(
+        "small".to_owned(),
+        NamedStoreOptions {
+          store: small_store,
+          ttl: Some(Duration::from_secs(60)),
+          max_total_bytes: None,
+          max_entries: None,
+        },
+      )]
+      .into_iter()
+      .collect(),
+      store_selector_fn: Some(Arc::new(move |process: &Process| {
+        if process.argv == routed_argv {
+          Some("small".to_owned())
+        } else {
+          None
+        }
+      })),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let small = caching.named_store("small").unwrap();
+
+  let routed = routed.into();
+  let unrouted = unrouted.into();
+  assert!(std::ptr::eq(caching.select_store(&routed), small));
+  assert!(std::ptr::eq(caching.select_store(&unrouted), &caching));
+
+  let routed_key = caching.fingerprint(&routed);
+  let unrouted_key = caching.fingerprint(&unrouted);
+  for req in vec![routed, unrouted] {
+    caching
+      .run(Context::default(), &mut workunit, req)
+      .await
+      .unwrap();
+  }
+
+  // Each result was stored only in its selected store.
+  let keys = [routed_key, unrouted_key];
+  assert_eq!(
+    caching.contains_many(&keys).await.unwrap(),
+    vec![false, true]
+  );
+  assert_eq!(small.contains_many(&keys).await.unwrap(), vec![true, false]);
+  assert_eq!(
+    small
+      .ttl_remaining(routed_key)
+      .await
+      .unwrap()
+      .map(|ttl| ttl <= Duration::from_secs(60)),
+    Some(true)
+  );
+}
+
+#[tokio::test]
+async fn unknown_platform() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let runner = |strict_platform| {
+    CommandRunner::new(
+      local.clone(),
+      process_execution_store.clone(),
+      store.clone(),
+      ProcessMetadata::default(),
+      LocalCacheOptions {
+        strict_platform,
+        ..LocalCacheOptions::default()
+      },
+    )
+  };
+
+  // Write an entry without a recorded platform.
+  let response = remexec::ExecuteResponse {
+    cached_result: true,
+    result: Some(remexec::ActionResult {
+      stdout_digest: Some((&EMPTY_DIGEST).into()),
+      stderr_digest: Some((&EMPTY_DIGEST).into()),
+      ..remexec::ActionResult::default()
+    }),
+    ..remexec::ExecuteResponse::default()
+  };
+  let mut response_bytes = Vec::new();
+  response.encode(&mut response_bytes).unwrap();
+  let entry = CacheEntry {
+    platform: None,
+    response_bytes,
+    response_compressed: false,
+    created_at_secs: 0,
+    worker_id: None,
+    response_dictionary: None,
+    input_checksum: None,
+    expires_at_secs: None,
+    response_digest: None,
+  };
+  let key = Digest::of_bytes(b"unknown platform").hash;
+  process_execution_store
+    .store_bytes(key, entry.encode().unwrap(), false)
+    .await
+    .unwrap();
+
+  // By default, the entry is assumed to be for the current platform.
+  let hit = runner(false)
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.platform, Platform::current().unwrap());
+  assert_eq!(
+    runner(false)
+      .load_entry(key)
+      .await
+      .unwrap()
+      .unwrap()
+      .platform,
+    None
+  );
+
+  // But in strict mode, it is a miss.
+  assert!(runner(true)
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn access_log() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let log_dir = TempDir::new().unwrap();
+  let log_path = log_dir.path().join("access.log");
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      access_log_path: Some(log_path.clone()),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  for _ in 0..2 {
+    caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+  }
+
+  // The log is written in the background, so wait for both records to arrive.
+  let mut records = Vec::new();
+  for _ in 0..100 {
+    records = std::fs::read_to_string(&log_path)
+      .unwrap()
+      .lines()
+      .map(|line| line.split('\t').map(str::to_owned).collect::<Vec<_>>())
+      .collect();
+    if records.len() == 2 {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  assert_eq!(records.len(), 2);
+  for (record, expected_outcome) in records.iter().zip(&["miss", "hit"]) {
+    assert_eq!(record[1], key.to_hex());
+    assert_eq!(record[2], *expected_outcome);
+  }
+  // The size of the stored entry is the size of the entry which was hit.
+  assert_eq!(records[0][3], records[1][3]);
+  assert_ne!(records[0][3], "0");
+}
+
+#[tokio::test]
+async fn decision_log() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let log_dir = TempDir::new().unwrap();
+  let log_path = log_dir.path().join("decisions.jsonl");
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      decision_log_path: Some(log_path.clone()),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  for _ in 0..2 {
+    caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+  }
+
+  // The log is written in the background, so wait for both records to arrive.
+  let mut records = Vec::new();
+  for _ in 0..100 {
+    records = std::fs::read_to_string(&log_path)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+      .collect();
+    if records.len() == 2 {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  assert_eq!(records.len(), 2);
+  let expected = [("miss", "ran_locally"), ("hit", "hit_locally")];
+  for (record, (decision, source)) in records.iter().zip(&expected) {
+    assert_eq!(record["fingerprint"], key.to_hex());
+    assert_eq!(record["decision"], *decision);
+    assert_eq!(record["source"], *source);
+    assert_eq!(record["exit_code"], 0);
+  }
+  // Time is only saved by a hit.
+  assert!(records[0]["time_saved_ms"].is_null());
+  assert_eq!(records[0]["entry_bytes"], records[1]["entry_bytes"]);
+  assert_ne!(records[0]["entry_bytes"], 0);
+}
+
+#[tokio::test]
+async fn semantic_cache_key() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+
+  // Two identical scripts at different absolute paths.
+  let (first, _first_path, _first_dir) = create_script(0);
+  let (second, _second_path, _second_dir) = create_script(0);
+  async fn run(
+    caching: &CommandRunner,
+    workunit: &mut RunningWorkunit,
+    process: Process,
+    semantic_cache_key: bool,
+  ) -> FallibleProcessResultWithPlatform {
+    let process = Process {
+      semantic_cache_key,
+      ..process
+    };
+    caching
+      .run(Context::default(), workunit, process.into())
+      .await
+      .unwrap()
+  }
+
+  // Without opting in, the second script misses.
+  run(&caching, &mut workunit, first.clone(), false).await;
+  assert_eq!(
+    run(&caching, &mut workunit, second.clone(), false)
+      .await
+      .metadata
+      .source,
+    ProcessResultSource::RanLocally
+  );
+
+  // But with it, the second script hits for the semantic key of the first.
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let first = Process {
+    semantic_cache_key: true,
+    ..first
+  };
+  let second = Process {
+    semantic_cache_key: true,
+    ..second
+  };
+  assert_ne!(
+    caching.fingerprint(&first.clone().into()),
+    caching.fingerprint(&second.clone().into())
+  );
+  assert_eq!(
+    caching.semantic_fingerprint(&first.clone().into()),
+    caching.semantic_fingerprint(&second.clone().into())
+  );
+  run(&caching, &mut workunit, first, true).await;
+  assert_eq!(
+    run(&caching, &mut workunit, second, true)
+      .await
+      .metadata
+      .source,
+    ProcessResultSource::HitLocally
+  );
+}
+
+#[tokio::test]
+async fn silent_successes() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let redactions = Arc::new(AtomicUsize::new(0));
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      verify_digests_on_write: true,
+      redact_fn: Some({
+        let redactions = redactions.clone();
+        Arc::new(move |_: &[u8]| {
+          redactions.fetch_add(1, Ordering::SeqCst);
+          None
+        })
+      }),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let process = Process::new(vec![
+    testutil::path::find_bash(),
+    "-c".to_owned(),
+    "true".to_owned(),
+  ]);
+  let key = caching.fingerprint(&process.clone().into());
+
+  for expected_source in vec![
+    ProcessResultSource::RanLocally,
+    ProcessResultSource::HitLocally,
+  ] {
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.metadata.source, expected_source);
+    assert_eq!(result.stdout_digest, EMPTY_DIGEST);
+    assert_eq!(result.stderr_digest, EMPTY_DIGEST);
+  }
+  // The empty outputs were recorded as-is, without being loaded to be redacted.
+  assert_eq!(redactions.load(Ordering::SeqCst), 0);
+  assert_eq!(
+    caching.validate(key).await.unwrap(),
+    ValidationStatus::Usable
+  );
+}
+
+#[test]
+fn relativize_absolute_paths_normalizes_paths() {
+  let process = Process::new(vec![
+    "/usr/local/bin/tool".to_owned(),
+    "relative/path".to_owned(),
+    "/".to_owned(),
+  ])
+  .env(
+    vec![("TOOL".to_owned(), "/home/alice/bin/tool".to_owned())]
+      .into_iter()
+      .collect(),
+  );
+  let normalized = relativize_absolute_paths(&process);
+  assert_eq!(normalized.argv, vec!["tool", "relative/path", ""]);
+  assert_eq!(normalized.env.get("TOOL").unwrap(), "tool");
+}
+
+#[tokio::test]
+async fn context_cache_scope_override() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (success, _success_path, _success_dir) = create_script(0);
+  let (failure, _failure_path, _failure_dir) = create_script(1);
+  let keys = [
+    caching.fingerprint(&success.clone().into()),
+    caching.fingerprint(&failure.clone().into()),
+  ];
+
+  // Overriding to a scope which is never persisted bypasses the cache.
+  let context = Context::default().with_cache_scope_override(ProcessCacheScope::PerSession);
+  for process in vec![success.clone(), failure.clone()] {
+    caching
+      .run(context.clone(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    caching.contains_many(&keys).await.unwrap(),
+    vec![false, false]
+  );
+
+  // Overriding to Always caches even the failing process.
+  let context = Context::default().with_cache_scope_override(ProcessCacheScope::Always);
+  for process in vec![success, failure] {
+    caching
+      .run(context.clone(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    caching.contains_many(&keys).await.unwrap(),
+    vec![true, true]
+  );
+}
+
+#[tokio::test]
+async fn entry_footprint() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+
+  // stdout and stderr are identical, and also appear in the output directory, so they should
+  // only be counted once.
+  let roland = store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .unwrap();
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: roland,
+    stderr_digest: roland,
+    exit_code: 0,
+    output_directory: record_recursive_directory(&store).await,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"footprint").hash;
+  assert!(caching.entry_footprint(key).await.is_err());
+  caching.store(key, &result).await.unwrap();
+
+  let footprint = caching.entry_footprint(key).await.unwrap();
+  assert!(footprint.entry_bytes > 0);
+  assert_eq!(
+    footprint.referenced_blob_bytes,
+    TestData::roland().len()
+      + TestData::catnip().len()
+      + TestDirectory::containing_roland().bytes().len()
+      + TestDirectory::recursive().bytes().len()
+  );
+  assert_eq!(
+    footprint.total_bytes(),
+    footprint.entry_bytes + footprint.referenced_blob_bytes
+  );
+}
+
+#[tokio::test]
+async fn largest_entries() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+
+  let roland = store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .unwrap();
+  let result = |output_directory: Digest| FallibleProcessResultWithPlatform {
+    stdout_digest: roland,
+    stderr_digest: roland,
+    exit_code: 0,
+    output_directory,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let large = Digest::of_bytes(b"large").hash;
+  caching
+    .store(large, &result(record_recursive_directory(&store).await))
+    .await
+    .unwrap();
+  let small = Digest::of_bytes(b"small").hash;
+  caching
+    .store(small, &result(TestDirectory::containing_roland().digest()))
+    .await
+    .unwrap();
+
+  let largest = caching.largest_entries(1).await.unwrap();
+  assert_eq!(largest.len(), 1);
+  assert_eq!(largest[0].0, large);
+  assert_eq!(
+    largest[0].1,
+    caching.entry_footprint(large).await.unwrap().total_bytes() as u64
+  );
+  let fingerprints: Vec<Fingerprint> = caching
+    .largest_entries(10)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|(fingerprint, _)| fingerprint)
+    .collect();
+  assert_eq!(fingerprints, vec![large, small]);
+  assert!(caching.largest_entries(0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn configurable_hit_source() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      hit_source: ProcessResultSource::HitRemotely,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitRemotely);
+  assert_eq!(hit.metadata.cache_tier, Some(CacheTier::LocalDisk));
+}
+
+///
+/// Concurrently stores, looks up, and removes entries for an overlapping set of keys while also
+/// garbage collecting, to validate that concurrent mutation never corrupts the store.
+///
+/// Runs for one second by default: set `LOCAL_CACHE_SOAK_TEST_SECS` to soak for longer.
+///
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_soak() {
+  let duration = Duration::from_secs(
+    std::env::var("LOCAL_CACHE_SOAK_TEST_SECS")
+      .ok()
+      .and_then(|secs| secs.parse().ok())
+      .unwrap_or(1),
+  );
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      // Small enough that stores regularly trigger eviction.
+      max_total_bytes: Some(4 * 1024),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let keys = (0..32_u8)
+    .map(|i| Digest::of_bytes(&[i]).hash)
+    .collect::<Vec<_>>();
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+
+  let deadline = std::time::Instant::now() + duration;
+  let mut tasks = (0..8)
+    .map(|_| {
+      let caching = caching.clone();
+      let keys = keys.clone();
+      let result = result.clone();
+      tokio::spawn(async move {
+        while std::time::Instant::now() < deadline {
+          let key = keys[rand::random::<usize>() % keys.len()];
+          match rand::random::<u8>() % 3 {
+            0 => caching.store(key, &result).await.unwrap(),
+            1 => {
+              if let Some(hit) = caching.lookup(key, MaterializePolicy::All).await.unwrap() {
+                assert_eq!(hit, result);
+              }
+            }
+            _ => {
+              caching.remove(key).await.unwrap();
+            }
+          }
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+  tasks.push({
+    let caching = caching.clone();
+    tokio::spawn(async move {
+      while std::time::Instant::now() < deadline {
+        caching.gc(2 * 1024).await.unwrap();
+      }
+    })
+  });
+
+  // Deadlocks surface as a timeout, and panics as errors joining the tasks.
+  tokio::time::timeout(
+    duration + Duration::from_secs(30),
+    futures::future::join_all(tasks),
+  )
+  .await
+  .expect("Soak test deadlocked.")
+  .into_iter()
+  .collect::<Result<Vec<_>, _>>()
+  .unwrap();
+
+  // Everything which remains is decodable.
+  for key in keys {
+    caching.lookup(key, MaterializePolicy::All).await.unwrap();
+  }
+}
+
+#[tokio::test]
+async fn export_response() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  assert!(caching.load_entry(key).await.unwrap().is_none());
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  let entry = caching.load_entry(key).await.unwrap().unwrap();
+  assert!(entry.execute_response.cached_result);
+  let exported = entry.export_response(false);
+  assert!(!exported.cached_result);
+  assert_eq!(exported.result, entry.execute_response.result);
+}
+
+#[tokio::test]
+async fn raw_entry() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      compress_response: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  assert_eq!(caching.raw_entry(key, false).await.unwrap(), None);
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  let raw = caching.raw_entry(key, false).await.unwrap().unwrap();
+  let decompressed = caching.raw_entry(key, true).await.unwrap().unwrap();
+  assert_ne!(raw, decompressed);
+  // Both are framed by the same format version.
+  assert_eq!(raw[0], decompressed[0]);
+}
+
+#[tokio::test]
+async fn health_check() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  caching.health_check().await.unwrap();
+  // The sentinel is removed, and is not visible as an entry in any case.
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+  assert_eq!(caching.audit().await.unwrap().entries_checked, 0);
+
+  let (process, _script_path, _script_dir) = create_script(0);
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  caching.health_check().await.unwrap();
+  assert_eq!(caching.stats().await.unwrap().entries, 1);
+}
+
+#[tokio::test]
+async fn shard_wait_micros() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  assert_eq!(caching.shard_wait_micros(), vec![0; 1]);
+
+  // Uncontended waits may round down to zero, so only check that the shards are tracked.
+  let (process, _script_path, _script_dir) = create_script(0);
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(caching.shard_wait_micros().len(), 1);
+}
+
+#[tokio::test]
+async fn worker_id() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      worker_id: Some("worker-1".to_owned()),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+
+  let key = caching.fingerprint(&process.into());
+  let entry = caching.load_entry(key).await.unwrap().unwrap();
+  assert_eq!(entry.worker_id, Some("worker-1".to_owned()));
+}
+
+#[tokio::test]
+async fn store_if_absent() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  let key = caching.fingerprint(&process.into());
+  let first_entry = caching.raw_entry(key, false).await.unwrap();
+
+  // The existing entry is kept exactly as it was written...
+  let mut second_result = result.clone();
+  second_result.metadata.total_elapsed = None;
+  assert!(!caching.store_if_absent(key, &second_result).await.unwrap());
+  assert_eq!(caching.raw_entry(key, false).await.unwrap(), first_entry);
+
+  // ...but once it is gone, the result is stored.
+  caching.remove(key).await.unwrap();
+  assert!(caching.store_if_absent(key, &second_result).await.unwrap());
+  assert_ne!(caching.raw_entry(key, false).await.unwrap(), first_entry);
+}
+
+#[tokio::test]
+async fn reset_metrics() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  for _ in 0..3 {
+    caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+  }
+
+  let expected = LocalCacheCounters {
+    hits: 2,
+    misses: 1,
+    ..LocalCacheCounters::default()
+  };
+  assert_eq!(caching.stats().await.unwrap().counters, expected);
+  assert_eq!(caching.reset_metrics(), expected);
+  assert_eq!(
+    caching.stats().await.unwrap().counters,
+    LocalCacheCounters::default()
+  );
+
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(caching.stats().await.unwrap().counters.hits, 1);
+}
+
+#[test]
+fn train_compression_dictionary_finds_common_substrings() {
+  let common = b"a substring which is common to several samples".to_vec();
+  let samples: Vec<Vec<u8>> = (0..4_u8)
+    .map(|i| {
+      let mut sample = vec![i; 20];
+      sample.extend_from_slice(&common);
+      sample.extend(vec![i + 100; 20]);
+      sample
+    })
+    .collect();
+  // Content which is unique to one sample is not included.
+  assert_eq!(train_compression_dictionary(&samples, 1024), common);
+
+  assert!(train_compression_dictionary(&samples[0..1], 1024).is_empty());
+  assert!(train_compression_dictionary(&samples, 8).is_empty());
+}
+
+#[tokio::test]
+async fn compression_dictionary() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      compress_response: true,
+      compression_dictionary: Some(Bytes::from_static(b"stdout_digest stderr_digest")),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  let hit = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitLocally);
+  assert_eq!(hit.output_directory, result.output_directory);
+
+  let key = caching.fingerprint(&process.into());
+  assert!(caching.raw_entry(key, true).await.unwrap().is_some());
+  assert_eq!(
+    caching.train_dictionary(10, 1024).await.unwrap(),
+    Vec::<u8>::new()
+  );
+}
+
+#[tokio::test]
+async fn purge_prefix() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (first, _first_path, _first_dir) = create_script(0);
+  let (second, _second_path, _second_dir) = create_script(0);
+  // Only the first process is in the namespace.
+  let namespaced_argv = first.argv.clone();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      key_namespace_fn: Some(Arc::new(move |process: &Process| {
+        if process.argv == namespaced_argv {
+          Some("bash".to_owned())
+        } else {
+          None
+        }
+      })),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let first_key = caching.fingerprint(&first.clone().into());
+  let second_key = caching.fingerprint(&second.clone().into());
+  assert!(first_key
+    .as_ref()
+    .starts_with(&namespace_key_prefix("bash")));
+
+  for process in vec![first, second] {
+    caching
+      .run(Context::default(), &mut workunit, process.into())
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    caching
+      .purge_prefix(&namespace_key_prefix("bash"))
+      .await
+      .unwrap(),
+    1
+  );
+  assert_eq!(
+    caching
+      .contains_many(&[first_key, second_key])
+      .await
+      .unwrap(),
+    vec![false, true]
+  );
+}
+
+#[test]
+fn blocking_lookup() {
+  let runtime = tokio::runtime::Runtime::new().unwrap();
+  let ((caching, _cache_dir), _local_runner_dir) = {
+    let _guard = runtime.enter();
+    let (local, store, local_runner_dir) = create_local_runner();
+    (create_cached_runner(local, store), local_runner_dir)
+  };
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"blocking").hash;
+
+  assert_eq!(caching.blocking_lookup(key).unwrap(), None);
+  runtime.block_on(caching.store(key, &result)).unwrap();
+  assert_eq!(caching.blocking_lookup(key).unwrap(), Some(result));
+}
+
+#[tokio::test]
+async fn lookup_by_action_digest() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  let (_, _, execute_request) =
+    crate::remote::make_execute_request(&process, ProcessMetadata::default()).unwrap();
+  let action_digest = bazel_protos::require_digest(execute_request.action_digest.as_ref()).unwrap();
+
+  assert_eq!(
+    caching.action_digest_fingerprint(action_digest),
+    caching.fingerprint(&process.clone().into())
+  );
+  assert_eq!(
+    caching
+      .lookup_by_action_digest(action_digest, MaterializePolicy::All)
+      .await
+      .unwrap(),
+    None
+  );
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  let hit = caching
+    .lookup_by_action_digest(action_digest, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.stdout_digest, result.stdout_digest);
+  assert_eq!(hit.exit_code, result.exit_code);
+}
+
+#[tokio::test]
+async fn malformed_entries_are_evicted() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    local,
+    process_execution_store.clone(),
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  // Write an entry whose stdout and stderr are intact, but whose output directory is not.
+  let response = remexec::ExecuteResponse {
+    cached_result: true,
+    result: Some(remexec::ActionResult {
+      stdout_digest: Some((&EMPTY_DIGEST).into()),
+      stderr_digest: Some((&EMPTY_DIGEST).into()),
+      output_directories: vec![remexec::OutputDirectory {
+        path: String::new(),
+        tree_digest: Some(remexec::Digest {
+          hash: "not a hash".to_owned(),
+          size_bytes: 0,
+        }),
+      }],
+      ..remexec::ActionResult::default()
+    }),
+    ..remexec::ExecuteResponse::default()
+  };
+  let mut response_bytes = Vec::new();
+  response.encode(&mut response_bytes).unwrap();
+  let entry = CacheEntry {
+    platform: Some(Platform::current().unwrap()),
+    response_bytes,
+    response_compressed: false,
+    created_at_secs: 0,
+    worker_id: None,
+    response_dictionary: None,
+    input_checksum: None,
+    expires_at_secs: None,
+    response_digest: None,
+  };
+  process_execution_store
+    .store_bytes(key, entry.encode().unwrap(), false)
+    .await
+    .unwrap();
+
+  // The entry can never be used, so looking it up fails, and evicts it.
+  assert!(caching.lookup(key, MaterializePolicy::All).await.is_err());
+  assert!(caching.load_entry(key).await.unwrap().is_none());
+
+  // And the process is re-executed (rather than failing), and its result is cached.
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+}
+
+#[tokio::test]
+async fn non_ok_statuses_are_misses() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    local,
+    process_execution_store.clone(),
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  // Write an entry with an otherwise usable result, but an errored status.
+  let response = remexec::ExecuteResponse {
+    cached_result: true,
+    result: Some(remexec::ActionResult {
+      stdout_digest: Some((&EMPTY_DIGEST).into()),
+      stderr_digest: Some((&EMPTY_DIGEST).into()),
+      ..remexec::ActionResult::default()
+    }),
+    status: Some(bazel_protos::gen::google::rpc::Status {
+      code: tonic::Code::Unavailable as i32,
+      message: "remote cache unavailable".to_owned(),
+      ..bazel_protos::gen::google::rpc::Status::default()
+    }),
+    ..remexec::ExecuteResponse::default()
+  };
+  let mut response_bytes = Vec::new();
+  response.encode(&mut response_bytes).unwrap();
+  let entry = CacheEntry {
+    platform: Some(Platform::current().unwrap()),
+    response_bytes,
+    response_compressed: false,
+    created_at_secs: 0,
+    worker_id: None,
+    response_dictionary: None,
+    input_checksum: None,
+    expires_at_secs: None,
+    response_digest: None,
+  };
+  process_execution_store
+    .store_bytes(key, entry.encode().unwrap(), false)
+    .await
+    .unwrap();
+
+  // The entry is a miss, but (unlike a malformed entry) it is not evicted.
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+  assert!(caching.load_entry(key).await.unwrap().is_some());
+
+  // The process is re-executed, and its result replaces the entry.
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+}
+
+#[tokio::test]
+async fn fingerprint_collisions_are_misses() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    local,
+    process_execution_store.clone(),
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let mut other_process = process.clone();
+  other_process
+    .env
+    .insert("OTHER".to_owned(), "value".to_owned());
+  let key = caching.fingerprint(&process.clone().into());
+  let other_key = caching.fingerprint(&other_process.clone().into());
+  assert_ne!(key, other_key);
+
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  // Simulate a collision by copying the entry for one process to the key of the other.
+  let entry_bytes = process_execution_store
+    .load_bytes_with(key, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+    .await
+    .unwrap()
+    .unwrap();
+  process_execution_store
+    .replace_bytes(other_key, entry_bytes, false)
+    .await
+    .unwrap();
+
+  // The entry was stored for different inputs, so it is not used, and is replaced.
+  let result = caching
+    .run(
+      Context::default(),
+      &mut workunit,
+      other_process.clone().into(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  let result = caching
+    .run(Context::default(), &mut workunit, other_process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+}
+
+#[tokio::test]
+async fn lfu_hit_frequencies_persist_and_decay() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let half_life = Duration::from_secs(60 * 60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let runner = || {
+    CommandRunner::new(
+      local.clone(),
+      process_execution_store.clone(),
+      store.clone(),
+      ProcessMetadata::default(),
+      LocalCacheOptions {
+        eviction_policy: EvictionPolicy::Lfu,
+        lfu_half_life: half_life,
+        clock: {
+          let now = now.clone();
+          Arc::new(move || *now.lock())
+        },
+        ..LocalCacheOptions::default()
+      },
+    )
+  };
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let frequent = Digest::of_bytes(b"frequent").hash;
+  let infrequent = Digest::of_bytes(b"infrequent").hash;
+
+  let caching = runner();
+  caching.store(frequent, &result).await.unwrap();
+  caching.store(infrequent, &result).await.unwrap();
+  for _ in 0..2 {
+    caching
+      .lookup(frequent, MaterializePolicy::All)
+      .await
+      .unwrap()
+      .unwrap();
+  }
+  caching.persist_hit_frequencies().await.unwrap();
+
+  // The frequencies survive a restart, and are not themselves an entry.
+  let caching = runner();
+  assert_eq!(
+    caching.hit_frequencies().await.unwrap(),
+    vec![(frequent, 2.0)].into_iter().collect()
+  );
+  assert_eq!(caching.stats().await.unwrap().entries, 2);
+
+  // After one half-life, earlier hits count half as much as new ones.
+  *now.lock() += half_life;
+  caching
+    .lookup(infrequent, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    caching.hit_frequencies().await.unwrap(),
+    vec![(frequent, 1.0), (infrequent, 1.0)]
+      .into_iter()
+      .collect()
+  );
+
+  // And the least frequently hit entry is evicted first.
+  *now.lock() += half_life;
+  caching
+    .lookup(frequent, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  caching.gc_entries(1).await.unwrap();
+  assert!(caching.load_entry(frequent).await.unwrap().is_some());
+  assert!(caching.load_entry(infrequent).await.unwrap().is_none());
+  assert_eq!(
+    caching.hit_frequencies().await.unwrap(),
+    vec![(frequent, 1.5)].into_iter().collect()
+  );
+}
+
+#[test]
+fn redact_patterns_replaces_matches() {
+  let redact = redact_patterns(vec![
+    regex::bytes::Regex::new("token=[a-z0-9]+").unwrap(),
+    regex::bytes::Regex::new("AKIA[A-Z0-9]{4}").unwrap(),
+  ]);
+  assert_eq!(
+    redact(b"token=hunter2 and AKIAABCD, token=x"),
+    Some(Bytes::from_static(b"[REDACTED] and [REDACTED], [REDACTED]"))
+  );
+  assert_eq!(redact(b"nothing to see"), None);
+}
+
+#[tokio::test]
+async fn redaction() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      redact_fn: Some(redact_patterns(vec![regex::bytes::Regex::new(
+        "password: [^ ]+",
+      )
+      .unwrap()])),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let stdout_digest = store
+    .store_file_bytes(Bytes::from_static(b"password: hunter2 ok"), false)
+    .await
+    .unwrap();
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"redaction").hash;
+  caching.store(key, &result).await.unwrap();
+
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.stderr_digest, EMPTY_DIGEST);
+  assert_eq!(
+    store
+      .load_file_bytes_with(hit.stdout_digest, |bytes| Bytes::copy_from_slice(bytes))
+      .await
+      .unwrap()
+      .unwrap(),
+    Bytes::from_static(b"[REDACTED] ok")
+  );
+}
+
+#[tokio::test]
+async fn outputs_are_synced_before_the_entry_is_written() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let key = Digest::of_bytes(b"synced").hash;
+  // A mock of the file Store's sync, which records whether the entry was already visible when
+  // it was called (in which case a crash at that moment would have left it without its outputs),
+  // and which fails once it is "crashed".
+  let entry_visible_at_sync = Arc::new(parking_lot::Mutex::new(Vec::new()));
+  let crashed = Arc::new(parking_lot::Mutex::new(false));
+  let sync_outputs_fn: SyncOutputsFn = {
+    let process_execution_store = process_execution_store.clone();
+    let entry_visible_at_sync = entry_visible_at_sync.clone();
+    let crashed = crashed.clone();
+    Arc::new(move |_store: &Store| {
+      let process_execution_store = process_execution_store.clone();
+      let entry_visible_at_sync = entry_visible_at_sync.clone();
+      let crashed = *crashed.lock();
+      async move {
+        let visible = process_execution_store.exists(key).await?;
+        entry_visible_at_sync.lock().push(visible);
+        if crashed {
+          Err("crashed".to_owned())
+        } else {
+          Ok(())
+        }
+      }
+      .boxed()
+    })
+  };
+  let caching = CommandRunner::new(
+    local,
+    process_execution_store,
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions {
+      sync_outputs_fn: Some(sync_outputs_fn),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+
+  // If the outputs cannot be made durable, the entry is never written.
+  *crashed.lock() = true;
+  assert!(caching.store(key, &result).await.is_err());
+  assert!(caching.load_entry(key).await.unwrap().is_none());
+
+  // Otherwise, the outputs are synced before the entry becomes visible.
+  *crashed.lock() = false;
+  caching.store(key, &result).await.unwrap();
+  assert!(caching.load_entry(key).await.unwrap().is_some());
+  assert_eq!(*entry_visible_at_sync.lock(), vec![false, false]);
+}
+
+#[tokio::test]
+async fn iter_fingerprints_since() {
+  // Entries are leased (by the real clock) when they are stored, so use creation times (from the
+  // injected clock) in the future to distinguish entries which were created recently.
+  let start = std::time::SystemTime::now();
+  let now = Arc::new(parking_lot::Mutex::new(start));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let old = Digest::of_bytes(b"old").hash;
+  let new = Digest::of_bytes(b"new").hash;
+  caching.store(old, &result).await.unwrap();
+  *now.lock() = start + Duration::from_secs(24 * 60 * 60);
+  caching.store(new, &result).await.unwrap();
+
+  assert_eq!(
+    caching
+      .iter_fingerprints_since(start + Duration::from_secs(60 * 60))
+      .await
+      .unwrap(),
+    vec![new]
+  );
+  // Both entries were accessed (by being stored) since before they were stored.
+  let mut all = vec![old, new];
+  all.sort();
+  assert_eq!(
+    caching
+      .iter_fingerprints_since(start - Duration::from_secs(60))
+      .await
+      .unwrap(),
+    all
+  );
+}
+
+#[tokio::test]
+async fn store_skips_under_memory_pressure() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  // Block the first store after it has reserved its memory, until it is released.
+  let (entered, entered_receiver) = tokio::sync::oneshot::channel::<()>();
+  let (release, released) = tokio::sync::oneshot::channel::<()>();
+  let blocked = Arc::new(parking_lot::Mutex::new(Some((entered, released))));
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      max_in_flight_store_bytes: Some(1),
+      memory_pressure_policy: MemoryPressurePolicy::Skip,
+      sync_outputs_fn: Some(Arc::new(move |_store: &Store| {
+        let blocked = blocked.lock().take();
+        async move {
+          if let Some((entered, released)) = blocked {
+            entered.send(()).unwrap();
+            released.await.unwrap();
+          }
+          Ok(())
+        }
+        .boxed()
+      })),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let first = Digest::of_bytes(b"first").hash;
+  let second = Digest::of_bytes(b"second").hash;
+
+  let first_store = {
+    let caching = caching.clone();
+    let result = result.clone();
+    tokio::spawn(async move { caching.store(first, &result).await })
+  };
+  entered_receiver.await.unwrap();
+
+  // While the first store holds the whole budget, the second is skipped.
+  assert!(!caching.store_if_absent(second, &result).await.unwrap());
+  assert!(caching.load_entry(second).await.unwrap().is_none());
+
+  // Once the first completes, the budget is available again.
+  release.send(()).unwrap();
+  first_store.await.unwrap().unwrap();
+  assert!(caching.load_entry(first).await.unwrap().is_some());
+  assert!(caching.store_if_absent(second, &result).await.unwrap());
+}
+
+#[tokio::test]
+async fn immortal_entries() {
+  let immortal = Digest::of_bytes(b"immortal").hash;
+  let mortal = Digest::of_bytes(b"mortal").hash;
+  let ttl = Duration::from_secs(60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      ttl: Some(ttl),
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      immortal_fingerprints: vec![immortal].into_iter().collect(),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  caching.store(immortal, &result).await.unwrap();
+  caching.store(mortal, &result).await.unwrap();
+
+  // Only the mortal entry expires.
+  *now.lock() += ttl * 2;
+  assert_eq!(
+    caching.contains_many(&[immortal, mortal]).await.unwrap(),
+    vec![true, false]
+  );
+  assert_eq!(caching.ttl_remaining(immortal).await.unwrap(), None);
+  assert!(caching
+    .lookup(immortal, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_some());
+
+  // And only the mortal entry is evicted, even though the cache remains over the target.
+  assert_eq!(caching.gc_entries(0).await.unwrap(), 1);
+  assert!(caching.load_entry(immortal).await.unwrap().is_some());
+  assert!(caching.load_entry(mortal).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn decode_errors_describe_the_entry() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    local.into(),
+    process_execution_store.clone(),
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+  let entry = CacheEntry {
+    platform: Some(Platform::current().unwrap()),
+    response_bytes: vec![0; 64],
+    response_compressed: false,
+    created_at_secs: 0,
+    worker_id: None,
+    response_dictionary: None,
+    input_checksum: None,
+    expires_at_secs: None,
+    response_digest: None,
+  }
+  .encode()
+  .unwrap();
+
+  // A truncated entry.
+  let truncated = Digest::of_bytes(b"truncated").hash;
+  process_execution_store
+    .store_bytes(truncated, entry.slice(..20), false)
+    .await
+    .unwrap();
+  let err = caching.load_entry(truncated).await.unwrap_err();
+  assert!(err.contains("entry is 20 bytes"), "{}", err);
+  assert!(
+    err.contains(&format!(
+      "starting with {}...",
+      entry[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+    )),
+    "{}",
+    err
+  );
+
+  // An entry in another format version.
+  let other_version = Digest::of_bytes(b"other version").hash;
+  process_execution_store
+    .store_bytes(other_version, Bytes::from_static(&[0, 1, 2]), false)
+    .await
+    .unwrap();
+  let err = caching.load_entry(other_version).await.unwrap_err();
+  assert!(
+    err.contains("entry is 3 bytes, with format version 0, starting with 000102"),
+    "{}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn framed_entries() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let local: Arc<dyn CommandRunnerTrait> = local.into();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let runner = |framed_entries| {
+    CommandRunner::new(
+      local.clone(),
+      process_execution_store.clone(),
+      store.clone(),
+      ProcessMetadata::default(),
+      LocalCacheOptions {
+        framed_entries,
+        ..LocalCacheOptions::default()
+      },
+    )
+  };
+
+  // Every field of an entry survives the framing.
+  let response = remexec::ExecuteResponse {
+    cached_result: true,
+    result: Some(remexec::ActionResult {
+      stdout_digest: Some((&EMPTY_DIGEST).into()),
+      stderr_digest: Some((&EMPTY_DIGEST).into()),
+      ..remexec::ActionResult::default()
+    }),
+    ..remexec::ExecuteResponse::default()
+  };
+  let mut response_bytes = Vec::new();
+  response.encode(&mut response_bytes).unwrap();
+  let entry = CacheEntry {
+    platform: Some(Platform::current().unwrap()),
+    response_bytes,
+    response_compressed: false,
+    created_at_secs: 1_000_000,
+    worker_id: Some("worker-1".to_owned()),
+    response_dictionary: None,
+    input_checksum: Some(Digest::of_bytes(b"inputs").hash),
+    expires_at_secs: Some(4_000_000_000),
+    response_digest: None,
+  };
+  let key = Digest::of_bytes(b"framed").hash;
+  process_execution_store
+    .store_bytes(key, entry.encode_framed().unwrap(), false)
+    .await
+    .unwrap();
+  let loaded = runner(false).load_entry(key).await.unwrap().unwrap();
+  assert_eq!(loaded.platform, entry.platform);
+  assert_eq!(loaded.execute_response, response);
+  assert_eq!(
+    loaded.created_at,
+    UNIX_EPOCH + Duration::from_secs(1_000_000)
+  );
+  assert_eq!(
+    loaded.expires_at,
+    Some(UNIX_EPOCH + Duration::from_secs(4_000_000_000))
+  );
+  assert_eq!(loaded.worker_id, entry.worker_id);
+
+  // Entries stored in either format are hit by runners which store the other.
+  let (process, _script_path, _script_dir) = create_script(0);
+  let result = runner(true)
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  let result = runner(false)
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+}
+
+#[tokio::test]
+async fn dedupe_responses() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store.clone(),
+    LocalCacheOptions {
+      dedupe_responses: true,
+      // The reference to the response must survive either framing.
+      framed_entries: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let first = Digest::of_bytes(b"first").hash;
+  let second = Digest::of_bytes(b"second").hash;
+  caching.store(first, &result).await.unwrap();
+  caching.store(second, &result).await.unwrap();
+
+  // Identical results share one stored response, which lookups follow transparently.
+  let response_digest = caching
+    .load_entry(first)
+    .await
+    .unwrap()
+    .unwrap()
+    .response_digest
+    .unwrap();
+  let second_entry = caching.load_entry(second).await.unwrap().unwrap();
+  assert_eq!(second_entry.response_digest, Some(response_digest));
+  assert_eq!(second_entry.execute_response.result.unwrap().exit_code, 0);
+  for key in &[first, second] {
+    let hit = caching
+      .lookup(*key, MaterializePolicy::All)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(hit.exit_code, 0);
+  }
+
+  // If the shared response is lost, both entries are dangling.
+  assert!(store.remove_file(response_digest).await.unwrap());
+  assert!(caching.lookup(first, MaterializePolicy::All).await.is_err());
+  assert_eq!(
+    caching.validate(second).await.unwrap(),
+    ValidationStatus::Dangling(vec![response_digest])
+  );
+}
+
+#[tokio::test]
+async fn empty_output_directory_is_materialized_from_a_hit() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store.clone());
+  let script_dir = TempDir::new().unwrap();
+  let script_path = script_dir.path().join("script");
+  std::fs::write(&script_path, "mkdir out").unwrap();
+  let process = Process::new(vec![
+    testutil::path::find_bash(),
+    format!("{}", script_path.display()),
+  ])
+  .output_directories(relative_paths(&["out"]).collect());
+
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  // Ensure that the second run is a hit.
+  std::fs::remove_file(&script_path).unwrap();
+  let hit = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitLocally);
+  assert_ne!(hit.output_directory, EMPTY_DIGEST);
+
+  let materialize_dir = TempDir::new().unwrap();
+  store
+    .materialize_directory(materialize_dir.path().to_owned(), hit.output_directory)
+    .await
+    .unwrap();
+  let out = materialize_dir.path().join("out");
+  assert!(out.is_dir());
+  assert_eq!(std::fs::read_dir(&out).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn cache_admin() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let mut keys = vec![
+    Digest::of_bytes(b"first").hash,
+    Digest::of_bytes(b"second").hash,
+  ];
+  keys.sort();
+  for key in &keys {
+    caching.store(*key, &result).await.unwrap();
+  }
+
+  // Drive the cache only through the trait object, as a transport would.
+  let admin: Arc<dyn CacheAdmin> = Arc::new(caching);
+  assert_eq!(admin.stats().await.unwrap().entries, 2);
+  assert_eq!(admin.iter_fingerprints(None).await.unwrap(), keys);
+  assert_eq!(admin.describe_all().await.unwrap().entries, 2);
+  assert_eq!(
+    admin.describe(keys[0]).await.unwrap().unwrap().fingerprint,
+    keys[0]
+  );
+  assert_eq!(
+    admin.validate(keys[0]).await.unwrap(),
+    ValidationStatus::Usable
+  );
+  assert_eq!(admin.audit(None).await.unwrap().entries_checked, 2);
+  assert!(admin.named_store_names().is_empty());
+  assert!(admin.named_store_admin("missing").is_none());
+
+  assert!(admin.remove(keys[0]).await.unwrap());
+  assert!(admin.describe(keys[0]).await.unwrap().is_none());
+  assert_eq!(admin.purge(&[]).await.unwrap(), 1);
+  assert_eq!(admin.gc_entries(0).await.unwrap(), 0);
+  assert!(admin.iter_fingerprints(None).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn describe_all_summary() {
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = |exit_code| FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+
+  // Two entries (one of them a failure) which will be two days old, and one fresh entry.
+  caching
+    .store(Digest::of_bytes(b"old success").hash, &result(0))
+    .await
+    .unwrap();
+  caching
+    .store(Digest::of_bytes(b"old failure").hash, &result(1))
+    .await
+    .unwrap();
+  *now.lock() += Duration::from_secs(2 * 24 * 60 * 60);
+  caching
+    .store(Digest::of_bytes(b"new success").hash, &result(0))
+    .await
+    .unwrap();
+
+  let summary = caching.describe_all_summary().await.unwrap();
+  assert_eq!(summary.entries, 3);
+  assert!(summary.total_bytes > 0);
+  assert_eq!(
+    summary.exit_codes,
+    vec![(0, 2), (1, 1)].into_iter().collect::<BTreeMap<_, _>>()
+  );
+  assert_eq!(
+    summary.platforms,
+    vec![(Some(Platform::current().unwrap()), 3)]
+      .into_iter()
+      .collect::<BTreeMap<_, _>>()
+  );
+  assert_eq!(summary.age_histogram, [1, 0, 2, 0, 0]);
+  assert_eq!(summary.undecodable, 0);
+}
+
+#[tokio::test]
+async fn deferred_writes() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      deferred_write_max_bytes: Some(1024 * 1024),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = |exit_code| FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"deferred").hash;
+
+  // Repeated writes are coalesced in the buffer, where they are visible to lookups, but have not
+  // been written.
+  caching.store(key, &result(0)).await.unwrap();
+  caching.store(key, &result(1)).await.unwrap();
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.exit_code, 1);
+  assert_eq!(caching.contains_many(&[key]).await.unwrap(), vec![false]);
+
+  // Flushing writes the last of them.
+  caching.flush_deferred_writes().await.unwrap();
+  assert_eq!(caching.contains_many(&[key]).await.unwrap(), vec![true]);
+  let hit = caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(hit.exit_code, 1);
+}
+
+#[tokio::test]
+async fn deferred_writes_are_flushed_when_over_the_memory_cap() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      deferred_write_max_bytes: Some(1),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"deferred").hash;
+
+  caching.store(key, &result).await.unwrap();
+  assert_eq!(caching.contains_many(&[key]).await.unwrap(), vec![true]);
+}
+
+#[tokio::test]
+async fn miss_diagnostics() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      miss_diagnostics_capacity: Some(10),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  // The first run misses, but there is no previous key to compare to. The second run hits.
+  for _ in 0..2 {
+    caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+  }
+  assert!(caching.miss_causes().is_empty());
+
+  // Changing an environment variable changes the key, and is reported as the cause of the miss.
+  let mut changed = process.clone();
+  changed
+    .env
+    .insert("CHANGED".to_owned(), "changed".to_owned());
+  caching
+    .run(Context::default(), &mut workunit, changed.into())
+    .await
+    .unwrap();
+  assert_eq!(caching.miss_causes(), vec![("env.CHANGED".to_owned(), 1)]);
+}
+
+#[tokio::test]
+async fn serve_expired_on_error() {
+  use std::os::unix::fs::PermissionsExt;
+
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let ttl = Duration::from_secs(60);
+  let grace = Duration::from_secs(60 * 60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      ttl: Some(ttl),
+      serve_expired_on_error_within: Some(grace),
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+
+  // A process which is its own executable, so that removing it causes an error launching it
+  // (rather than a non-zero exit code).
+  let script_dir = TempDir::new().unwrap();
+  let script_path = script_dir.path().join("script");
+  std::fs::write(
+    &script_path,
+    format!(
+      "#!{}\necho -n {} > roland\n",
+      testutil::path::find_bash(),
+      TestData::roland().string()
+    ),
+  )
+  .unwrap();
+  std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+  let process = Process::new(vec![format!("{}", script_path.display())])
+    .output_files(relative_paths(&["roland"]).collect());
+
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  std::fs::remove_file(&script_path).unwrap();
+
+  // Within the grace window, the expired entry is served when re-execution fails.
+  *now.lock() += ttl + grace / 2;
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(result.exit_code, 0);
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+
+  // After it, the error is returned.
+  *now.lock() += grace;
+  assert!(caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn underlying_bypasses_the_cache() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  let result = caching
+    .underlying()
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+}
+
+#[tokio::test]
+async fn directory_store() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let cache_dir = TempDir::new().unwrap();
+  let directory_store = DirectoryStore::new(
+    cache_dir.path().to_owned(),
+    DEFAULT_LEASE_TIME,
+    task_executor::Executor::new(),
+  )
+  .unwrap();
+  let caching = CommandRunner::new_with_store(
+    local.into(),
+    Arc::new(directory_store),
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+
+  // The entry is stored as its own file, sharded by the first byte of its key.
+  let key_hex = key.to_hex();
+  assert!(cache_dir
+    .path()
+    .join(&key_hex[..2])
+    .join(&key_hex)
+    .is_file());
+  assert_eq!(caching.stats().await.unwrap().entries, 1);
+
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+
+  assert!(caching.remove(key).await.unwrap());
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+}
+
+#[tokio::test]
+async fn multi_platform_requests_are_keyed_on_the_compatible_process() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let current = Platform::current().unwrap();
+  let other = *[Platform::Linux_x86_64, Platform::Macos_x86_64]
+    .iter()
+    .find(|platform| **platform != current)
+    .unwrap();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  let mut other_process = process.clone();
+  other_process.argv.push("--for-another-platform".to_owned());
+  let single = MultiPlatformProcess(vec![(Some(current), process.clone())].into_iter().collect());
+  let multi = MultiPlatformProcess(
+    vec![(Some(current), process), (Some(other), other_process)]
+      .into_iter()
+      .collect(),
+  );
+  assert_eq!(caching.fingerprint(&single), caching.fingerprint(&multi));
+
+  // A result for the compatible variant alone satisfies the request with both variants.
+  let result = caching
+    .run(Context::default(), &mut workunit, single)
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  let result = caching
+    .run(Context::default(), &mut workunit, multi)
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+}
+
+#[tokio::test]
+async fn failures_are_cached_according_to_the_scope_of_the_compatible_process() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let current = Platform::current().unwrap();
+  let other = *[Platform::Linux_x86_64, Platform::Macos_x86_64]
+    .iter()
+    .find(|platform| **platform != current)
+    .unwrap();
+  let (process, _script_path, _script_dir) = create_script(1);
+  let request = |current_scope, other_scope| {
+    let mut current_process = process.clone();
+    current_process.cache_scope = current_scope;
+    let mut other_process = process.clone();
+    other_process.cache_scope = other_scope;
+    MultiPlatformProcess(
+      vec![
+        (Some(current), current_process),
+        (Some(other), other_process),
+      ]
+      .into_iter()
+      .collect(),
+    )
+  };
+
+  // An `Always` variant for another platform does not cause the failure to be cached.
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let result = caching
+    .run(
+      Context::default(),
+      &mut workunit,
+      request(ProcessCacheScope::Successful, ProcessCacheScope::Always),
+    )
+    .await
+    .unwrap();
+  assert_eq!(result.exit_code, 1);
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+
+  // But an `Always` variant for this platform does.
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  caching
+    .run(
+      Context::default(),
+      &mut workunit,
+      request(ProcessCacheScope::Always, ProcessCacheScope::Successful),
+    )
+    .await
+    .unwrap();
+  assert_eq!(caching.stats().await.unwrap().entries, 1);
+}
+
+#[tokio::test]
+async fn lookups_over_the_concurrency_limit_wait() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      max_concurrent_lookups: Some(1),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+
+  // All of the concurrent lookups hit, rather than any of them failing.
+  let lookups =
+    futures::future::try_join_all((0..8).map(|_| caching.lookup(key, MaterializePolicy::All)))
+      .await
+      .unwrap();
+  assert!(lookups.iter().all(Option::is_some));
+}
+
+#[tokio::test]
+async fn session_cache_stats() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner(local, store);
+  let (process, _script_path, _script_dir) = create_script(0);
+  let session_cache_stats = Arc::new(SessionCacheStats::default());
+  let context = Context::default().with_session_cache_stats(session_cache_stats.clone());
+
+  for _ in 0..3 {
+    caching
+      .run(context.clone(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+  }
+  assert_eq!(session_cache_stats.hits(), 2);
+  assert_eq!(session_cache_stats.misses(), 1);
+  assert!(session_cache_stats.bytes_read() > 0);
+  assert!(session_cache_stats.to_string().starts_with(&format!(
+    "cache summary: 2 hits, 1 misses, {} bytes read, ",
+    session_cache_stats.bytes_read()
+  )));
+}
+
+#[tokio::test]
+async fn rebuild_index() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let options = LocalCacheOptions {
+    index_sidecars: true,
+    ..LocalCacheOptions::default()
+  };
+  let (caching, _cache_dir) =
+    create_cached_runner_with_options(local, store.clone(), options.clone());
+  let (process, _script_path, _script_dir) = create_script(0);
+  caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+
+  // Lose the cache, but keep the file Store.
+  let rebuilt_dir = TempDir::new().unwrap();
+  let rebuilt = CommandRunner::new(
+    caching.underlying().clone(),
+    ShardedLmdb::new(
+      rebuilt_dir.path().to_owned(),
+      50 * 1024 * 1024,
+      task_executor::Executor::new(),
+      DEFAULT_LEASE_TIME,
+      1,
+    )
+    .unwrap(),
+    store,
+    ProcessMetadata::default(),
+    options,
+  );
+  assert_eq!(rebuilt.stats().await.unwrap().entries, 0);
+
+  assert_eq!(rebuilt.rebuild_index().await.unwrap(), 1);
+  let result = rebuilt
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+
+  // Entries which are already present are not recovered again.
+  assert_eq!(rebuilt.rebuild_index().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn results_are_cached_according_to_their_output_size() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+  // The script's outputs total a few dozen bytes.
+  for (min, max, expected_source) in vec![
+    (Some(1024 * 1024), None, ProcessResultSource::RanLocally),
+    (None, Some(1), ProcessResultSource::RanLocally),
+    (Some(1), Some(1024 * 1024), ProcessResultSource::HitLocally),
+  ] {
+    let (local, store, _local_runner_dir) = create_local_runner();
+    let (caching, _cache_dir) = create_cached_runner_with_options(
+      local,
+      store,
+      LocalCacheOptions {
+        min_output_bytes_to_cache: min,
+        max_output_bytes_to_cache: max,
+        ..LocalCacheOptions::default()
+      },
+    );
+    caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.metadata.source, expected_source);
+  }
+}
+
+#[tokio::test]
+async fn speculation() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      speculate: true,
+      ..LocalCacheOptions::default()
+    },
+  );
+  let process = Process::new(vec![
+    testutil::path::find_bash(),
+    "-c".to_owned(),
+    "sleep 60".to_owned(),
+  ]);
+  let key = caching.fingerprint(&process.clone().into());
+  caching
+    .store(
+      key,
+      &FallibleProcessResultWithPlatform {
+        stdout_digest: EMPTY_DIGEST,
+        stderr_digest: EMPTY_DIGEST,
+        exit_code: 0,
+        output_directory: EMPTY_DIGEST,
+        platform: Platform::current().unwrap(),
+        metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+      },
+    )
+    .await
+    .unwrap();
+
+  // The lookup completes first, and the (slow) process is cancelled rather than awaited.
+  let start = std::time::Instant::now();
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::HitLocally);
+  assert!(start.elapsed() < Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn store_transform() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      // Discard stdout, as though it contained only ephemeral paths.
+      store_transform_fn: Some(Arc::new(
+        |result: &mut FallibleProcessResultWithPlatform| {
+          result.stdout_digest = EMPTY_DIGEST;
+        },
+      )),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+
+  // The run which stores the result returns it untransformed, but hits return it transformed.
+  let result = caching
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert_ne!(result.stdout_digest, EMPTY_DIGEST);
+  let hit = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(hit.metadata.source, ProcessResultSource::HitLocally);
+  assert_eq!(hit.stdout_digest, EMPTY_DIGEST);
+  assert_eq!(hit.stderr_digest, result.stderr_digest);
+}
+
+#[tokio::test]
+async fn per_process_expiration() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (mut process, _script_path, _script_dir) = create_script(0);
+  process.expires_at = Some(*now.lock() + Duration::from_secs(60));
+
+  for expected_source in vec![
+    ProcessResultSource::RanLocally,
+    ProcessResultSource::HitLocally,
+  ] {
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.metadata.source, expected_source);
+  }
+
+  // Without any TTL configured, the entry still expires when its process said it would.
+  *now.lock() += Duration::from_secs(60);
+  let result = caching
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+}
+
+#[tokio::test]
+async fn memory_store() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let ttl = Duration::from_secs(60);
+  let now = Arc::new(parking_lot::Mutex::new(
+    UNIX_EPOCH + Duration::from_secs(1_000_000),
+  ));
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let caching = create_in_memory_cached_runner(
+    local,
+    store,
+    LocalCacheOptions {
+      ttl: Some(ttl),
+      clock: {
+        let now = now.clone();
+        Arc::new(move || *now.lock())
+      },
+      ..LocalCacheOptions::default()
+    },
+  );
+  let (process, _script_path, _script_dir) = create_script(0);
+  let key = caching.fingerprint(&process.clone().into());
+
+  for expected_source in vec![
+    ProcessResultSource::RanLocally,
+    ProcessResultSource::HitLocally,
+  ] {
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.metadata.source, expected_source);
+  }
+  assert_eq!(caching.stats().await.unwrap().entries, 1);
+
+  // The entry expires.
+  *now.lock() += ttl;
+  assert!(caching
+    .lookup(key, MaterializePolicy::All)
+    .await
+    .unwrap()
+    .is_none());
+
+  // And can be evicted.
+  caching.gc(0).await.unwrap();
+  assert_eq!(caching.stats().await.unwrap().entries, 0);
+}
+
+fn tripped_circuit_breaker() -> CircuitBreaker {
+  let breaker = CircuitBreaker::new(Some(CircuitBreakerOptions {
+    failure_threshold: 2,
+    failure_window: Duration::from_secs(60),
+    // The cooldown elapses immediately, so the next request is a trial.
+    cooldown: Duration::ZERO,
+  }));
+  breaker.allow_request().unwrap().record_failure();
+  breaker.allow_request().unwrap().record_failure();
+  breaker
+}
+
+#[test]
+fn circuit_breaker_closes_after_a_successful_trial() {
+  let breaker = tripped_circuit_breaker();
+
+  // Once the cooldown has elapsed, a single trial request is allowed through.
+  let trial = breaker.allow_request().unwrap();
+  assert!(breaker.allow_request().is_none());
+
+  // A failed trial reopens the breaker.
+  trial.record_failure();
+  let trial = breaker.allow_request().unwrap();
+  assert!(breaker.allow_request().is_none());
+
+  // And a successful one closes it.
+  trial.record_success();
+  let first = breaker.allow_request().unwrap();
+  let second = breaker.allow_request().unwrap();
+  first.record_success();
+  second.record_success();
+}
+
+#[test]
+fn circuit_breaker_stays_open_during_cooldown() {
+  let breaker = CircuitBreaker::new(Some(CircuitBreakerOptions {
+    failure_threshold: 1,
+    failure_window: Duration::from_secs(60),
+    cooldown: Duration::from_secs(60 * 60),
+  }));
+  breaker.allow_request().unwrap().record_failure();
+  assert!(breaker.allow_request().is_none());
+}
+
+#[test]
+fn circuit_breaker_releases_a_cancelled_trial() {
+  let breaker = tripped_circuit_breaker();
+
+  // A trial which is dropped without an outcome does not leave the breaker half-open forever...
+  let trial = breaker.allow_request().unwrap();
+  assert!(breaker.allow_request().is_none());
+  std::mem::drop(trial);
+
+  // ...but nor does it close the breaker: the next request becomes the trial instead.
+  let trial = breaker.allow_request().unwrap();
+  assert!(breaker.allow_request().is_none());
+  trial.record_success();
+  assert!(breaker.allow_request().is_some());
+}
+
+#[tokio::test]
+async fn scan_parallelism() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    16,
+  )
+  .unwrap();
+  let caching = CommandRunner::new(
+    local.into(),
+    process_execution_store,
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions {
+      scan_parallelism: 4,
+      ..LocalCacheOptions::default()
+    },
+  );
+
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  // Keys whose first bytes are spread across every shard.
+  let keys = (0..=255_u8)
+    .step_by(8)
+    .map(|b| Fingerprint([b; 32]))
+    .collect::<Vec<_>>();
+  for key in &keys {
+    caching.store(*key, &result).await.unwrap();
+  }
+
+  assert_eq!(caching.iter_fingerprints(None).await.unwrap(), keys);
+  assert_eq!(caching.stats().await.unwrap().entries, keys.len() as u64);
+}
+
+#[tokio::test]
+async fn fail_on_write_error() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (process, _script_path, _script_dir) = create_script(0);
+  for fail_on_write_error in vec![false, true] {
+    let (local, store, _local_runner_dir) = create_local_runner();
+    let process_execution_store = MemoryStore::new(DEFAULT_LEASE_TIME);
+    process_execution_store.set_fail_writes(true);
+    let caching = CommandRunner::new_with_store(
+      local.into(),
+      Arc::new(process_execution_store),
+      store,
+      ProcessMetadata::default(),
+      LocalCacheOptions {
+        fail_on_write_error,
+        ..LocalCacheOptions::default()
+      },
+    );
+
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await;
+    if fail_on_write_error {
+      let err = result.unwrap_err();
+      assert!(
+        err.contains("Error storing process execution result to local cache"),
+        "{}",
+        err
+      );
+    } else {
+      assert_eq!(result.unwrap().exit_code, 0);
+    }
+  }
+}
+
+#[tokio::test]
+async fn read_only_store_disables_writes() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+  let shard_dir = cache_dir.path().join("0");
+  let mut permissions = std::fs::metadata(&shard_dir).unwrap().permissions();
+  permissions.set_readonly(true);
+  std::fs::set_permissions(&shard_dir, permissions.clone()).unwrap();
+
+  // The store is detected to be read-only (and reported) once, when the runner is created...
+  let caching = CommandRunner::new(
+    local.into(),
+    process_execution_store,
+    store,
+    ProcessMetadata::default(),
+    LocalCacheOptions::default(),
+  );
+
+  // ...rather than failing the write for each process.
+  let (process, _script_path, _script_dir) = create_script(0);
+  for _ in 0..2 {
+    let result = caching
+      .run(Context::default(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  }
+  let counters = caching.stats().await.unwrap().counters;
+  assert_eq!(counters.misses, 2);
+  assert_eq!(counters.write_errors, 0);
+
+  // Restore permissions so that the tempdir can be cleaned up.
+  permissions.set_readonly(false);
+  std::fs::set_permissions(&shard_dir, permissions).unwrap();
+}
+
+#[tokio::test]
+async fn usage_counts_replaced_entries_once() {
+  let (local, store, _local_runner_dir) = create_local_runner();
+  let (caching, _cache_dir) = create_cached_runner_with_options(
+    local,
+    store,
+    LocalCacheOptions {
+      // Usage is tracked incrementally while there is a limit.
+      max_total_bytes: Some(10 * 1024 * 1024),
+      ..LocalCacheOptions::default()
+    },
+  );
+  let result = FallibleProcessResultWithPlatform {
+    stdout_digest: EMPTY_DIGEST,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code: 0,
+    output_directory: EMPTY_DIGEST,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  };
+  let key = Digest::of_bytes(b"replaced").hash;
+  caching.store(key, &result).await.unwrap();
+  let stats = caching.stats().await.unwrap();
+  assert_eq!(stats.entries, 1);
+  assert!(stats.used_bytes > 0);
+
+  // Replacing the entry replaces its bytes, rather than adding to them.
+  caching.store(key, &result).await.unwrap();
+  let replaced_stats = caching.stats().await.unwrap();
+  assert_eq!(replaced_stats.entries, 1);
+  assert_eq!(replaced_stats.used_bytes, stats.used_bytes);
+
+  // Like the entry count, the bytes recomputed by a scan exclude reserved keys such as the pins.
+  caching.pin(key).await.unwrap();
+  assert_eq!(
+    caching.gc(10 * 1024 * 1024).await.unwrap(),
+    stats.used_bytes
+  );
+}
+
+#[test]
+fn circuit_breaker_trial_is_released_once() {
+  let breaker = tripped_circuit_breaker();
+
+  // A trial which is explicitly released (as when speculation cancels a lookup)...
+  let released = breaker.allow_request().unwrap();
+  released.release();
+
+  // ...does not release the trial of a later request when it is eventually dropped.
+  let trial = breaker.allow_request().unwrap();
+  std::mem::drop(released);
+  assert!(breaker.allow_request().is_none());
+  trial.record_success();
+  assert!(breaker.allow_request().is_some());
+}
@@ -32,7 +32,9 @@ extern crate derivative;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
+use std::fmt;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 pub use log::Level;
@@ -46,6 +48,7 @@ use serde::{Deserialize, Serialize};
 use workunit_store::{RunningWorkunit, WorkunitStore};
 
 pub mod cache;
+pub mod cache_store;
 #[cfg(test)]
 mod cache_tests;
 
@@ -157,6 +160,9 @@ pub enum ProcessCacheScope {
   // Will run once per Session, i.e. once per run of Pants. This happens because the engine
   // de-duplicates identical work; the process is neither memoized in memory nor cached to disk.
   PerSession,
+  // Like PerSession, but additionally the process caches skip it entirely: they neither compute
+  // its cache key nor read or write it.
+  Never,
 }
 
 impl TryFrom<String> for ProcessCacheScope {
@@ -168,6 +174,7 @@ impl TryFrom<String> for ProcessCacheScope {
       "per_restart_always" => Ok(ProcessCacheScope::PerRestartAlways),
       "per_restart_successful" => Ok(ProcessCacheScope::PerRestartSuccessful),
       "per_session" => Ok(ProcessCacheScope::PerSession),
+      "never" => Ok(ProcessCacheScope::Never),
       other => Err(format!("Unknown Process cache scope: {:?}", other)),
     }
   }
@@ -254,6 +261,46 @@ pub struct Process {
   pub is_nailgunnable: bool,
 
   pub cache_scope: ProcessCacheScope,
+
+  ///
+  /// Whether the local cache may also store and look up the result of this process under a
+  /// looser "semantic" key, which ignores trivial differences between processes. This trades
+  /// correctness for hit rate: see `cache::LocalCacheOptions::semantic_key_fn`.
+  ///
+  pub semantic_cache_key: bool,
+
+  ///
+  /// If set, overrides whether the local cache ensures that the output directory of a hit for
+  /// this process is loadable before returning it: see
+  /// `cache::LocalCacheOptions::default_materialize_outputs`. Consumers which only need the exit
+  /// code or stdio of a process can disable this to skip needless I/O.
+  ///
+  pub materialize_cached_outputs: Option<bool>,
+
+  ///
+  /// Whether this process is hermetic: that is, whether it reads only its declared inputs (rather
+  /// than un-sandboxed absolute paths like `/etc` or `/usr`). The results of non-hermetic
+  /// processes may differ between machines, so the local cache neither looks them up nor stores
+  /// them.
+  ///
+  pub hermetic: bool,
+
+  ///
+  /// The version of the format of this process's outputs, for tools whose output format can
+  /// change independently of their inputs. If set, it is included in the REAPI Action (see
+  /// `crate::remote::make_execute_request`), so bumping it invalidates the local and remote
+  /// cached results of only the processes which declare it, rather than requiring the whole cache
+  /// to be wiped.
+  ///
+  pub output_schema_version: Option<u32>,
+
+  ///
+  /// If set, the time after which the result of this process is no longer valid (for example,
+  /// because it was derived from data which is only valid until then). The local cache records
+  /// it with the result, and treats the entry as expired after it, independent of its TTL. It is
+  /// not part of the cache key, so a later run with a new expiration replaces the expired entry.
+  ///
+  pub expires_at: Option<std::time::SystemTime>,
 }
 
 impl Process {
@@ -284,6 +331,11 @@ impl Process {
       is_nailgunnable: false,
       execution_slot_variable: None,
       cache_scope: ProcessCacheScope::Successful,
+      semantic_cache_key: false,
+      materialize_cached_outputs: None,
+      hermetic: true,
+      output_schema_version: None,
+      expires_at: None,
     }
   }
 
@@ -311,6 +363,30 @@ impl Process {
     self
   }
 
+  ///
+  /// Overrides whether the local cache materializes the outputs of a hit for this process.
+  ///
+  pub fn materialize_cached_outputs(mut self, materialize_cached_outputs: bool) -> Process {
+    self.materialize_cached_outputs = Some(materialize_cached_outputs);
+    self
+  }
+
+  ///
+  /// Sets the version of the format of this process's outputs.
+  ///
+  pub fn output_schema_version(mut self, output_schema_version: u32) -> Process {
+    self.output_schema_version = Some(output_schema_version);
+    self
+  }
+
+  ///
+  /// Sets whether this process is hermetic.
+  ///
+  pub fn hermetic(mut self, hermetic: bool) -> Process {
+    self.hermetic = hermetic;
+    self
+  }
+
   ///
   /// Replaces the append only caches for this process.
   ///
@@ -376,6 +452,9 @@ impl From<Process> for MultiPlatformProcess {
 #[derive(Clone, Debug, Default)]
 pub struct ProcessMetadata {
   pub instance_name: Option<String>,
+  /// An arbitrary version string which is mixed into every cache key. Changing it (for example,
+  /// when a tool which is not otherwise captured in process inputs is upgraded) makes every
+  /// previously cached result unreachable, without needing to wipe any caches.
   pub cache_key_gen_version: Option<String>,
   pub platform_properties: Vec<(String, String)>,
 }
@@ -407,6 +486,8 @@ pub struct ProcessResultMetadata {
   pub total_elapsed: Option<Duration>,
   /// The source of the result.
   pub source: ProcessResultSource,
+  /// For results which were hits, the tier of cache which served them.
+  pub cache_tier: Option<CacheTier>,
 }
 
 impl ProcessResultMetadata {
@@ -414,6 +495,7 @@ impl ProcessResultMetadata {
     ProcessResultMetadata {
       total_elapsed,
       source,
+      cache_tier: CacheTier::default_for(source),
     }
   }
 
@@ -430,6 +512,7 @@ impl ProcessResultMetadata {
     Self {
       total_elapsed,
       source,
+      cache_tier: CacheTier::default_for(source),
     }
   }
 
@@ -492,10 +575,39 @@ pub enum ProcessResultSource {
   HitRemotely,
 }
 
+///
+/// The tier of cache which served a hit. A cache with more than one tier for a given
+/// ProcessResultSource (such as an in-memory layer in front of its on-disk store) should
+/// distinguish them here.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheTier {
+  /// The on-disk (LMDB) local process cache.
+  LocalDisk,
+  /// A remote Action Cache.
+  Remote,
+}
+
+impl CacheTier {
+  fn default_for(source: ProcessResultSource) -> Option<CacheTier> {
+    match source {
+      ProcessResultSource::HitLocally => Some(CacheTier::LocalDisk),
+      ProcessResultSource::HitRemotely => Some(CacheTier::Remote),
+      ProcessResultSource::RanLocally | ProcessResultSource::RanRemotely => None,
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct Context {
   workunit_store: WorkunitStore,
   build_id: String,
+  /// If set, takes precedence over the `cache_scope` of every Process run with this Context (for
+  /// example, to disable caching for a single build).
+  cache_scope_override: Option<ProcessCacheScope>,
+  /// If set, the local cache accumulates the hits and misses of every Process run with this
+  /// Context into it.
+  session_cache_stats: Option<Arc<SessionCacheStats>>,
 }
 
 impl Default for Context {
@@ -503,6 +615,8 @@ impl Default for Context {
     Context {
       workunit_store: WorkunitStore::new(false),
       build_id: String::default(),
+      cache_scope_override: None,
+      session_cache_stats: None,
     }
   }
 }
@@ -512,6 +626,102 @@ impl Context {
     Context {
       workunit_store,
       build_id,
+      cache_scope_override: None,
+      session_cache_stats: None,
+    }
+  }
+
+  ///
+  /// Accumulates the use of the cache by every Process run with this Context into the given
+  /// (usually session-scoped) SessionCacheStats.
+  ///
+  pub fn with_session_cache_stats(self, session_cache_stats: Arc<SessionCacheStats>) -> Context {
+    Context {
+      session_cache_stats: Some(session_cache_stats),
+      ..self
+    }
+  }
+
+  ///
+  /// Overrides the `cache_scope` of every Process run with this Context.
+  ///
+  pub fn with_cache_scope_override(self, cache_scope_override: ProcessCacheScope) -> Context {
+    Context {
+      cache_scope_override: Some(cache_scope_override),
+      ..self
+    }
+  }
+
+  ///
+  /// Returns the cache scope which applies to the given Process when it is run with this Context.
+  ///
+  pub fn cache_scope(&self, process: &Process) -> ProcessCacheScope {
+    self.cache_scope_override.unwrap_or(process.cache_scope)
+  }
+}
+
+///
+/// The hits and misses of the local cache for the Processes run in a session (see
+/// `Context::with_session_cache_stats`), which are displayed as a one line summary at the end of
+/// a build.
+///
+#[derive(Debug, Default)]
+pub struct SessionCacheStats {
+  hits: AtomicU64,
+  misses: AtomicU64,
+  bytes_read: AtomicU64,
+  time_saved_ms: AtomicU64,
+}
+
+impl SessionCacheStats {
+  pub fn record_hit(&self, bytes_read: u64, time_saved_ms: Option<u64>) {
+    self.hits.fetch_add(1, Ordering::Relaxed);
+    self.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+    if let Some(time_saved_ms) = time_saved_ms {
+      self
+        .time_saved_ms
+        .fetch_add(time_saved_ms, Ordering::Relaxed);
+    }
+  }
+
+  pub fn record_miss(&self) {
+    self.misses.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn hits(&self) -> u64 {
+    self.hits.load(Ordering::Relaxed)
+  }
+
+  pub fn misses(&self) -> u64 {
+    self.misses.load(Ordering::Relaxed)
+  }
+
+  ///
+  /// The total size of the cache entries read by hits.
+  ///
+  pub fn bytes_read(&self) -> u64 {
+    self.bytes_read.load(Ordering::Relaxed)
+  }
+
+  pub fn time_saved(&self) -> std::time::Duration {
+    std::time::Duration::from_millis(self.time_saved_ms.load(Ordering::Relaxed))
+  }
+}
+
+impl fmt::Display for SessionCacheStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let time_saved = self.time_saved().as_secs();
+    write!(
+      f,
+      "cache summary: {} hits, {} misses, {} bytes read, ",
+      self.hits(),
+      self.misses(),
+      self.bytes_read()
+    )?;
+    if time_saved < 60 {
+      write!(f, "{}s saved", time_saved)
+    } else {
+      write!(f, "{}m saved", time_saved / 60)
     }
   }
 }
@@ -536,6 +746,14 @@ pub trait CommandRunner: Send + Sync {
   /// first candidate that will be run if the multi platform request is submitted to
   /// `fn run(..)`
   fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process>;
+
+  ///
+  /// Flushes any work which has been buffered (such as deferred cache writes) before shutdown.
+  /// CommandRunners which wrap another CommandRunner should shut it down too.
+  ///
+  async fn shutdown(&self) -> Result<(), String> {
+    Ok(())
+  }
 }
 
 // TODO(#8513) possibly move to the MEPR struct, or to the hashing crate?
@@ -543,8 +761,11 @@ pub fn digest(req: MultiPlatformProcess, metadata: &ProcessMetadata) -> Digest {
   let mut hashes: Vec<String> = req
     .0
     .values()
-    .map(|process| crate::remote::make_execute_request(process, metadata.clone()).unwrap())
-    .map(|(_a, _b, er)| {
+    .map(|process| {
+      // NB: The Action includes the declared output files and directories, so results which
+      // captured different outputs are never served for one another. It also includes the
+      // `output_schema_version`, so that bumping it invalidates remote cache entries too.
+      let (_a, _b, er) = crate::remote::make_execute_request(process, metadata.clone()).unwrap();
       er.action_digest
         .map(|d| d.hash)
         .unwrap_or_else(|| EMPTY_FINGERPRINT.to_hex())
@@ -615,6 +836,10 @@ impl CommandRunner for BoundedCommandRunner {
   fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
     self.inner.0.extract_compatible_request(req)
   }
+
+  async fn shutdown(&self) -> Result<(), String> {
+    self.inner.0.shutdown().await
+  }
 }
 
 impl From<Box<BoundedCommandRunner>> for Arc<dyn CommandRunner> {
@@ -66,6 +66,11 @@ fn construct_nailgun_server_request(
     is_nailgunnable: true,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::PerSession,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   }
 }
 
@@ -60,6 +60,11 @@ pub const CACHE_KEY_SALT_ENV_VAR_NAME: &str = "PANTS_CACHE_KEY_SALT";
 // CommandRunner.
 pub const CACHE_KEY_TARGET_PLATFORM_ENV_VAR_NAME: &str = "PANTS_CACHE_KEY_TARGET_PLATFORM";
 
+// Environment variable which is used to include the `output_schema_version` of a Process (if any)
+// in its Action, so that bumping the version invalidates both local and remote cache entries.
+pub const CACHE_KEY_OUTPUT_SCHEMA_VERSION_ENV_VAR_NAME: &str =
+  "PANTS_CACHE_KEY_OUTPUT_SCHEMA_VERSION";
+
 #[derive(Debug)]
 pub enum OperationOrStatus {
   Operation(Operation),
@@ -888,6 +893,7 @@ pub fn make_execute_request(
     if name == CACHE_KEY_GEN_VERSION_ENV_VAR_NAME
       || name == CACHE_KEY_TARGET_PLATFORM_ENV_VAR_NAME
       || name == CACHE_KEY_SALT_ENV_VAR_NAME
+      || name == CACHE_KEY_OUTPUT_SCHEMA_VERSION_ENV_VAR_NAME
     {
       return Err(format!(
         "Cannot set env var with name {} as that is reserved for internal use by pants",
@@ -927,9 +933,20 @@ pub fn make_execute_request(
       });
   }
 
+  // NB: Only set when declared, so that the Actions of other processes are unchanged.
+  if let Some(output_schema_version) = req.output_schema_version {
+    command
+      .environment_variables
+      .push(remexec::command::EnvironmentVariable {
+        name: CACHE_KEY_OUTPUT_SCHEMA_VERSION_ENV_VAR_NAME.to_string(),
+        value: output_schema_version.to_string(),
+      });
+  }
+
   if matches!(
     req.cache_scope,
     ProcessCacheScope::PerSession
+      | ProcessCacheScope::Never
       | ProcessCacheScope::PerRestartAlways
       | ProcessCacheScope::PerRestartSuccessful
   ) {
@@ -541,6 +541,15 @@ impl crate::CommandRunner for CommandRunner {
     workunit: &mut RunningWorkunit,
     req: MultiPlatformProcess,
   ) -> Result<FallibleProcessResultWithPlatform, String> {
+    // As in the local cache, the results of non-hermetic processes may differ between machines, so
+    // are never cached.
+    if req
+      .0
+      .values()
+      .any(|process| !process.hermetic || context.cache_scope(process) == ProcessCacheScope::Never)
+    {
+      return self.underlying.run(context, workunit, req).await;
+    }
     let cache_lookup_start = Instant::now();
     // Construct the REv2 ExecuteRequest and related data for this execution request.
     let request = self
@@ -551,7 +560,7 @@ impl crate::CommandRunner for CommandRunner {
     let write_failures_to_cache = req
       .0
       .values()
-      .any(|process| process.cache_scope == ProcessCacheScope::Always);
+      .any(|process| context.cache_scope(process) == ProcessCacheScope::Always);
 
     // Ensure the action and command are stored locally.
     let (command_digest, action_digest) =
@@ -634,4 +643,8 @@ impl crate::CommandRunner for CommandRunner {
   fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
     self.underlying.extract_compatible_request(req)
   }
+
+  async fn shutdown(&self) -> Result<(), String> {
+    self.underlying.shutdown().await
+  }
 }
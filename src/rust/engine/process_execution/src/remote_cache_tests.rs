@@ -365,6 +365,34 @@ async fn cache_write_not_for_failures() {
   assert!(action_cache.action_map.lock().is_empty());
 }
 
+#[tokio::test]
+async fn non_hermetic_processes_bypass_the_cache() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let store_setup = StoreSetup::new();
+  let (local_runner, local_runner_call_counter) = create_local_runner(0, 100);
+  let (cache_runner, action_cache) = create_cached_runner(local_runner, &store_setup, 0, 0, false);
+  let (process, action_digest) = create_process(&store_setup.store).await;
+  let process = process.hermetic(false);
+
+  // The result is not written...
+  let local_result = cache_runner
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(local_result.exit_code, 0);
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 1);
+  sleep(Duration::from_millis(100)).await;
+  assert!(action_cache.action_map.lock().is_empty());
+
+  // ...and an existing result is not read.
+  insert_into_action_cache(&action_cache, &action_digest, 0, EMPTY_DIGEST, EMPTY_DIGEST);
+  cache_runner
+    .run(Context::default(), &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 2);
+}
+
 /// Cache writes should be async and not block the CommandRunner from returning.
 #[tokio::test]
 async fn cache_write_does_not_block() {
@@ -89,6 +89,11 @@ async fn make_execute_request() {
     is_nailgunnable: false,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let want_command = remexec::Command {
@@ -166,6 +171,11 @@ async fn make_execute_request_with_instance_name() {
     is_nailgunnable: false,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let want_command = remexec::Command {
@@ -256,6 +266,11 @@ async fn make_execute_request_with_cache_key_gen_version() {
     is_nailgunnable: false,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let mut want_command = remexec::Command {
@@ -493,6 +508,11 @@ async fn make_execute_request_with_timeout() {
     is_nailgunnable: false,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let want_command = remexec::Command {
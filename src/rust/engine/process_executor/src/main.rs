@@ -435,6 +435,11 @@ fn make_request_from_flat_args(
     is_nailgunnable: args.use_nailgun,
     execution_slot_variable: None,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let metadata = ProcessMetadata {
@@ -520,6 +525,11 @@ async fn extract_request_from_action_digest(
     platform_constraint: None,
     is_nailgunnable: false,
     cache_scope: ProcessCacheScope::Always,
+    semantic_cache_key: false,
+    materialize_cached_outputs: None,
+    hermetic: true,
+    output_schema_version: None,
+    expires_at: None,
   };
 
   let metadata = ProcessMetadata {
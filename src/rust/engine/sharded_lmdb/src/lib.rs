@@ -27,19 +27,21 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+use std::cmp::max;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{self, Duration};
+use std::time::{self, Duration, Instant};
 
 use bytes::{BufMut, Bytes};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use hashing::{Digest, Fingerprint, WriterHasher, FINGERPRINT_SIZE};
 use lmdb::{
-  self, Database, DatabaseFlags, Environment, EnvironmentCopyFlags, EnvironmentFlags,
-  RwTransaction, Transaction, WriteFlags,
+  self, Cursor, Database, DatabaseFlags, Environment, EnvironmentCopyFlags, EnvironmentFlags,
+  RoTransaction, RwTransaction, Transaction, WriteFlags,
 };
 use log::trace;
 use tempfile::TempDir;
@@ -103,6 +105,37 @@ impl AsRef<[u8]> for VersionedFingerprint {
   }
 }
 
+///
+/// The size and lease of an entry, as returned by `ShardedLmdb::all_entry_metadata`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EntryMetadata {
+  pub fingerprint: Fingerprint,
+  pub size_bytes: usize,
+  /// When the lease on this entry expires, in seconds since the unix epoch, or 0 if it has never
+  /// been leased.
+  pub leased_until_secs: u64,
+}
+
+///
+/// Called with the index of a shard and the time spent waiting to begin a write transaction on
+/// it. Since each shard has at most one concurrent writer, this measures contention on the shard.
+///
+pub type TxnWaitObserver = Arc<dyn Fn(u8, Duration) + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct OptionalTxnWaitObserver(Option<TxnWaitObserver>);
+
+impl Debug for OptionalTxnWaitObserver {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.0.is_some() {
+      write!(f, "Some(TxnWaitObserver)")
+    } else {
+      write!(f, "None")
+    }
+  }
+}
+
 // Each LMDB directory can have at most one concurrent writer.
 // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
 // fingerprint being stored, so that we can write to them in parallel.
@@ -116,6 +149,7 @@ pub struct ShardedLmdb {
   lease_time: Duration,
   shard_count: u8,
   shard_fingerprint_mask: u8,
+  txn_wait_observer: OptionalTxnWaitObserver,
 }
 
 impl ShardedLmdb {
@@ -199,9 +233,53 @@ impl ShardedLmdb {
       lease_time,
       shard_count,
       shard_fingerprint_mask,
+      txn_wait_observer: OptionalTxnWaitObserver::default(),
     })
   }
 
+  ///
+  /// Returns a copy of this store which reports the time spent waiting to begin each write
+  /// transaction to the given observer.
+  ///
+  pub fn with_txn_wait_observer(mut self, observer: TxnWaitObserver) -> ShardedLmdb {
+    self.txn_wait_observer = OptionalTxnWaitObserver(Some(observer));
+    self
+  }
+
+  pub fn shard_count(&self) -> u8 {
+    self.shard_count
+  }
+
+  ///
+  /// The duration of each lease: an entry whose lease expires at `t` was last leased at
+  /// `t - lease_time`.
+  ///
+  pub fn lease_time(&self) -> Duration {
+    self.lease_time
+  }
+
+  ///
+  /// Begins a write transaction on the given shard's environment, reporting the time spent
+  /// waiting for it to the `TxnWaitObserver`, if any.
+  ///
+  fn begin_rw_txn<'env>(
+    &self,
+    env: &'env Environment,
+    fingerprint: &Fingerprint,
+  ) -> Result<RwTransaction<'env>, lmdb::Error> {
+    let observer = if let Some(observer) = &self.txn_wait_observer.0 {
+      observer
+    } else {
+      return env.begin_rw_txn();
+    };
+    let start = Instant::now();
+    let txn = env.begin_rw_txn();
+    let shard = (fingerprint.0[0] & self.shard_fingerprint_mask)
+      .rotate_right(Self::shard_shift(self.shard_count) as u32);
+    observer(shard, start.elapsed());
+    txn
+  }
+
   ///
   /// Return the left shift value that will place the relevant portion of a byte (for the given
   /// shard count, which is asserted in the constructor to be a power of two) into the high order
@@ -274,6 +352,35 @@ impl ShardedLmdb {
       .map_err(|e| format!("Error making env for store at {:?}: {}", dir, e))
   }
 
+  pub fn executor(&self) -> &task_executor::Executor {
+    &self.executor
+  }
+
+  ///
+  /// Returns true if any shard cannot be written to: for example, because its directory has
+  /// been made read-only to share it between machines.
+  ///
+  /// NB: This checks permissions rather than beginning a write transaction, which would block
+  /// while another process holds the write lock of a shard.
+  ///
+  pub fn is_read_only(&self) -> bool {
+    self.lmdbs.values().any(|(dir, _, _, _)| {
+      let data_file = dir.join("data.mdb");
+      let permissions_read_only = [dir, &data_file].iter().any(|path| {
+        std::fs::metadata(path)
+          .map(|metadata| metadata.permissions().readonly())
+          .unwrap_or(false)
+      });
+      // Opening the data file for writing (without creating or truncating it) checks whether this
+      // process may write to it, without modifying it.
+      permissions_read_only
+        || std::fs::OpenOptions::new()
+          .write(true)
+          .open(&data_file)
+          .is_err()
+    })
+  }
+
   // First Database is content, second is leases.
   pub fn get(&self, fingerprint: &Fingerprint) -> (Arc<Environment>, Database, Database) {
     let (_, env, db1, db2) = self.get_raw(fingerprint.0[0]);
@@ -301,9 +408,15 @@ impl ShardedLmdb {
       .executor
       .spawn_blocking(move || {
         let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
-        let (env, db, _lease_database) = store.get(&fingerprint);
-        let del_res = env.begin_rw_txn().and_then(|mut txn| {
+        let (env, db, lease_database) = store.get(&fingerprint);
+        let del_res = store.begin_rw_txn(&env, &fingerprint).and_then(|mut txn| {
           txn.del(db, &effective_key, None)?;
+          txn
+            .del(lease_database, &effective_key, None)
+            .or_else(|err| match err {
+              lmdb::Error::NotFound => Ok(()),
+              err => Err(err),
+            })?;
           txn.commit()
         });
 
@@ -320,6 +433,29 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Flushes the data of all shards to disk. Since environments are opened with NO_SYNC, writes
+  /// are otherwise not guaranteed to survive a system crash.
+  ///
+  pub async fn sync(&self) -> Result<(), String> {
+    let envs = self
+      .lmdbs
+      .values()
+      .map(|(_, env, _, _)| env.clone())
+      .collect::<Vec<_>>();
+    self
+      .executor
+      .spawn_blocking(move || {
+        for env in envs {
+          env
+            .sync(true)
+            .map_err(|err| format!("Failed to sync lmdb environment: {}", err))?;
+        }
+        Ok(())
+      })
+      .await
+  }
+
   pub async fn exists(&self, fingerprint: Fingerprint) -> Result<bool, String> {
     let store = self.clone();
     let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
@@ -343,20 +479,213 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Returns the fingerprints of all entries stored at the current schema version, scanning at
+  /// most `parallelism` shards concurrently.
+  ///
+  pub async fn all_fingerprints(&self, parallelism: usize) -> Result<Vec<Fingerprint>, String> {
+    self
+      .scan_shards(parallelism, |txn, db, _| {
+        let mut fingerprints = Vec::new();
+        let mut cursor = txn
+          .open_ro_cursor(db)
+          .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+        for (key, _) in cursor.iter() {
+          if key.len() == VERSIONED_FINGERPRINT_SIZE
+            && key[FINGERPRINT_SIZE] == ShardedLmdb::SCHEMA_VERSION
+          {
+            fingerprints.push(VersionedFingerprint::from_bytes_unsafe(key).get_fingerprint());
+          }
+        }
+        Ok(fingerprints)
+      })
+      .await
+  }
+
+  ///
+  /// Returns the size and lease expiry of all entries stored at the current schema version,
+  /// scanning at most `parallelism` shards concurrently.
+  ///
+  pub async fn all_entry_metadata(&self, parallelism: usize) -> Result<Vec<EntryMetadata>, String> {
+    self
+      .scan_shards(parallelism, |txn, db, lease_database| {
+        let mut entries = Vec::new();
+        let mut cursor = txn
+          .open_ro_cursor(db)
+          .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+        for (key, bytes) in cursor.iter() {
+          if key.len() != VERSIONED_FINGERPRINT_SIZE
+            || key[FINGERPRINT_SIZE] != ShardedLmdb::SCHEMA_VERSION
+          {
+            continue;
+          }
+          let leased_until_secs = match txn.get(lease_database, &key) {
+            Ok(lease_bytes) if lease_bytes.len() == 8 => {
+              let mut array = [0_u8; 8];
+              array.copy_from_slice(lease_bytes);
+              u64::from_le_bytes(array)
+            }
+            Ok(_) | Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(format!("Error reading lease: {}", err)),
+          };
+          entries.push(EntryMetadata {
+            fingerprint: VersionedFingerprint::from_bytes_unsafe(key).get_fingerprint(),
+            size_bytes: bytes.len(),
+            leased_until_secs,
+          });
+        }
+        Ok(entries)
+      })
+      .await
+  }
+
+  ///
+  /// Runs `f` in a read transaction against each shard (with its content and lease databases),
+  /// with at most `parallelism` shards being scanned concurrently, and concatenates the results
+  /// in the order in which the shards complete.
+  ///
+  async fn scan_shards<T, F>(&self, parallelism: usize, f: F) -> Result<Vec<T>, String>
+  where
+    T: Send + 'static,
+    F: Fn(&RoTransaction<'_>, Database, Database) -> Result<Vec<T>, String> + Send + Sync + 'static,
+  {
+    let f = Arc::new(f);
+    let scans = self
+      .all_lmdbs()
+      .into_iter()
+      .map(|(env, db, lease_database)| {
+        let executor = self.executor.clone();
+        let f = f.clone();
+        // NB: `spawn_blocking` starts the task immediately, so it is deferred until this future
+        // is first polled in order for `parallelism` to be respected.
+        async move {
+          executor
+            .spawn_blocking(move || {
+              let txn = env
+                .begin_ro_txn()
+                .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
+              f(&txn, db, lease_database)
+            })
+            .await
+        }
+      });
+    let results_per_shard = stream::iter(scans)
+      .buffer_unordered(max(parallelism, 1))
+      .try_collect::<Vec<_>>()
+      .await?;
+    Ok(results_per_shard.into_iter().flatten().collect())
+  }
+
   pub async fn store_bytes(
     &self,
     fingerprint: Fingerprint,
     bytes: Bytes,
     initial_lease: bool,
+  ) -> Result<(), String> {
+    self
+      .put_bytes(fingerprint, bytes, initial_lease, WriteFlags::NO_OVERWRITE)
+      .await
+      .map(|_| ())
+  }
+
+  ///
+  /// Like `store_bytes`, but returns whether the bytes were stored: false if a value already
+  /// existed for the fingerprint (and was left in place).
+  ///
+  pub async fn store_bytes_if_absent(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<bool, String> {
+    self
+      .put_bytes(fingerprint, bytes, initial_lease, WriteFlags::NO_OVERWRITE)
+      .await
+  }
+
+  ///
+  /// Like `store_bytes`, but replaces any existing value for the fingerprint.
+  ///
+  pub async fn replace_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    self
+      .put_bytes(fingerprint, bytes, initial_lease, WriteFlags::empty())
+      .await
+      .map(|_| ())
+  }
+
+  ///
+  /// Like `replace_bytes` for each of the given values, but with a single write transaction per
+  /// shard, which is cheaper than a transaction per value.
+  ///
+  pub async fn replace_bytes_batch(
+    &self,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
   ) -> Result<(), String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(move || {
+        let mut items_by_shard: HashMap<u8, Vec<(Fingerprint, Bytes)>> = HashMap::new();
+        for (fingerprint, bytes) in items {
+          items_by_shard
+            .entry(fingerprint.0[0] & store.shard_fingerprint_mask)
+            .or_default()
+            .push((fingerprint, bytes));
+        }
+        for shard_items in items_by_shard.values() {
+          let (env, db, lease_database) = store.get(&shard_items[0].0);
+          let put_res = store
+            .begin_rw_txn(&env, &shard_items[0].0)
+            .and_then(|mut txn| {
+              for (fingerprint, bytes) in shard_items {
+                let effective_key =
+                  VersionedFingerprint::new(*fingerprint, ShardedLmdb::SCHEMA_VERSION);
+                txn.put(db, &effective_key, bytes, WriteFlags::empty())?;
+                if initial_lease {
+                  store.lease_inner(
+                    lease_database,
+                    &effective_key,
+                    store.lease_until_secs_since_epoch(),
+                    &mut txn,
+                  )?;
+                }
+              }
+              txn.commit()
+            });
+          put_res.map_err(|err| {
+            format!(
+              "Error storing a batch of {} values: {}",
+              shard_items.len(),
+              err
+            )
+          })?;
+        }
+        Ok(())
+      })
+      .await
+  }
+
+  async fn put_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+    write_flags: WriteFlags,
+  ) -> Result<bool, String> {
     let store = self.clone();
     self
       .executor
       .spawn_blocking(move || {
         let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
         let (env, db, lease_database) = store.get(&fingerprint);
-        let put_res = env.begin_rw_txn().and_then(|mut txn| {
-          txn.put(db, &effective_key, &bytes, WriteFlags::NO_OVERWRITE)?;
+        let put_res = store.begin_rw_txn(&env, &fingerprint).and_then(|mut txn| {
+          txn.put(db, &effective_key, &bytes, write_flags)?;
           if initial_lease {
             store.lease_inner(
               lease_database,
@@ -369,8 +698,8 @@ impl ShardedLmdb {
         });
 
         match put_res {
-          Ok(()) => Ok(()),
-          Err(lmdb::Error::KeyExist) => Ok(()),
+          Ok(()) => Ok(true),
+          Err(lmdb::Error::KeyExist) => Ok(false),
           Err(err) => Err(format!(
             "Error storing versioned key {:?}: {}",
             effective_key.to_hex(),
@@ -416,8 +745,8 @@ impl ShardedLmdb {
 
           let effective_key = VersionedFingerprint::new(digest.hash, ShardedLmdb::SCHEMA_VERSION);
           let (env, db, lease_database) = store.get(&digest.hash);
-          let put_res: Result<(), StoreError> = env
-            .begin_rw_txn()
+          let put_res: Result<(), StoreError> = store
+            .begin_rw_txn(&env, &digest.hash)
             .map_err(StoreError::Lmdb)
             .and_then(|mut txn| {
               // Second pass: copy into the reserved memory.
@@ -502,7 +831,7 @@ impl ShardedLmdb {
       .spawn_blocking(move || {
         let until_secs_since_epoch: u64 = store.lease_until_secs_since_epoch();
         let (env, _, lease_database) = store.get(&fingerprint);
-        env.begin_rw_txn().and_then(|mut txn| {
+        store.begin_rw_txn(&env, &fingerprint).and_then(|mut txn| {
           store.lease_inner(
             lease_database,
             &VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION),
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use bytes::{Buf, Bytes};
-use hashing::Digest;
+use hashing::{Digest, Fingerprint};
 use parking_lot::Mutex;
 use task_executor::Executor;
 use tempfile::TempDir;
@@ -91,6 +92,169 @@ async fn store_failure() {
   assert!(result.is_err());
 }
 
+#[tokio::test]
+async fn all_fingerprints() {
+  let (s, _tempdir) = new_store(4);
+  assert!(s.all_fingerprints(2).await.unwrap().is_empty());
+
+  let mut expected = (0..10)
+    .map(|b| Digest::of_bytes(&bytes(b)).hash)
+    .collect::<Vec<_>>();
+  for (b, fingerprint) in expected.iter().enumerate() {
+    s.store_bytes(*fingerprint, bytes(b as u8), false)
+      .await
+      .unwrap();
+  }
+
+  let mut actual = s.all_fingerprints(2).await.unwrap();
+  actual.sort();
+  expected.sort();
+  assert_eq!(actual, expected);
+}
+
+#[tokio::test]
+async fn all_fingerprints_scans_shards_in_parallel() {
+  let (s, _tempdir) = new_store(16);
+
+  // Keys whose first bytes are spread across every shard.
+  let mut expected = (0..=255_u8)
+    .step_by(4)
+    .map(|b| Fingerprint([b; 32]))
+    .collect::<Vec<_>>();
+  for fingerprint in &expected {
+    s.store_bytes(*fingerprint, bytes(fingerprint.0[1]), false)
+      .await
+      .unwrap();
+  }
+
+  // Including a parallelism which does not evenly divide the shard count.
+  for parallelism in vec![3, 16, 64] {
+    let mut actual = s.all_fingerprints(parallelism).await.unwrap();
+    actual.sort();
+    expected.sort();
+    assert_eq!(actual, expected);
+    assert_eq!(
+      s.all_entry_metadata(parallelism).await.unwrap().len(),
+      expected.len()
+    );
+  }
+}
+
+#[tokio::test]
+async fn all_entry_metadata() {
+  let (s, _tempdir) = new_store(4);
+  let leased = Digest::of_bytes(&bytes(0)).hash;
+  let unleased = Digest::of_bytes(&bytes(1)).hash;
+  s.store_bytes(leased, bytes(0), true).await.unwrap();
+  s.store_bytes(unleased, Bytes::from(vec![1; 10]), false)
+    .await
+    .unwrap();
+
+  let mut entries = s.all_entry_metadata(4).await.unwrap();
+  entries.sort_by_key(|entry| entry.size_bytes);
+  assert_eq!(entries.len(), 2);
+  assert_eq!(entries[0].fingerprint, unleased);
+  assert_eq!(entries[0].size_bytes, 10);
+  assert_eq!(entries[0].leased_until_secs, 0);
+  assert_eq!(entries[1].fingerprint, leased);
+  assert_eq!(entries[1].size_bytes, 100);
+  assert!(entries[1].leased_until_secs > 0);
+
+  // Removing an entry removes its lease too.
+  assert!(s.remove(leased).await.unwrap());
+  assert_eq!(s.all_entry_metadata(4).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn replace_bytes() {
+  let (s, _tempdir) = new_store(1);
+  let fingerprint = Digest::of_bytes(&bytes(0)).hash;
+  let load = |s: ShardedLmdb| async move {
+    s.load_bytes_with(fingerprint, |b| Ok(Bytes::copy_from_slice(b)))
+      .await
+      .unwrap()
+  };
+
+  s.store_bytes(fingerprint, bytes(0), false).await.unwrap();
+  s.store_bytes(fingerprint, bytes(1), false).await.unwrap();
+  assert_eq!(load(s.clone()).await, Some(bytes(0)));
+
+  s.replace_bytes(fingerprint, bytes(1), false).await.unwrap();
+  assert_eq!(load(s.clone()).await, Some(bytes(1)));
+}
+
+#[tokio::test]
+async fn store_bytes_if_absent() {
+  let (s, _tempdir) = new_store(1);
+  let fingerprint = Digest::of_bytes(&bytes(0)).hash;
+
+  assert!(s
+    .store_bytes_if_absent(fingerprint, bytes(0), false)
+    .await
+    .unwrap());
+  assert!(!s
+    .store_bytes_if_absent(fingerprint, bytes(1), false)
+    .await
+    .unwrap());
+  assert_eq!(
+    s.load_bytes_with(fingerprint, |b| Ok(Bytes::copy_from_slice(b)))
+      .await
+      .unwrap(),
+    Some(bytes(0))
+  );
+}
+
+#[tokio::test]
+async fn txn_wait_observer() {
+  let (s, _tempdir) = new_store(4);
+  let observed = Arc::new(Mutex::new(Vec::new()));
+  let s = s.with_txn_wait_observer({
+    let observed = observed.clone();
+    Arc::new(move |shard, _wait| observed.lock().push(shard))
+  });
+
+  // The two high order bits of the fingerprint select one of the four shards.
+  s.store_bytes(Fingerprint([0b1100_0000; 32]), bytes(0), false)
+    .await
+    .unwrap();
+  s.store_bytes(Fingerprint([0b0100_0000; 32]), bytes(0), true)
+    .await
+    .unwrap();
+  s.remove(Fingerprint([0b1100_0000; 32])).await.unwrap();
+  // Reads do not wait for writers, and so are not observed.
+  s.load_bytes_with(Fingerprint([0b0100_0000; 32]), |_| Ok(()))
+    .await
+    .unwrap();
+  assert_eq!(*observed.lock(), vec![3, 1, 3]);
+}
+
+#[tokio::test]
+async fn is_read_only() {
+  let (s, tempdir) = new_store(2);
+  assert!(!s.is_read_only());
+
+  let shard_dir = tempdir.path().join("0");
+  let mut permissions = std::fs::metadata(&shard_dir).unwrap().permissions();
+  permissions.set_readonly(true);
+  std::fs::set_permissions(&shard_dir, permissions.clone()).unwrap();
+  assert!(s.is_read_only());
+
+  // Restore permissions so that the tempdir can be cleaned up.
+  permissions.set_readonly(false);
+  std::fs::set_permissions(&shard_dir, permissions).unwrap();
+}
+
+#[tokio::test]
+async fn is_read_only_does_not_wait_for_writers() {
+  let (s, _tempdir) = new_store(2);
+  let (env, _, _) = s.get(&Fingerprint([0; 32]));
+
+  // A shard whose write lock is held is still writable.
+  let txn = env.begin_rw_txn().unwrap();
+  assert!(!s.is_read_only());
+  std::mem::drop(txn);
+}
+
 fn bytes(content: u8) -> Bytes {
   Bytes::from(vec![content; 100])
 }
@@ -286,6 +286,7 @@ impl Core {
         process_execution_store,
         full_store.clone(),
         process_execution_metadata.clone(),
+        process_execution::cache::LocalCacheOptions::default(),
       ))
     } else {
       maybe_remote_enabled_command_runner
@@ -490,6 +491,10 @@ impl Core {
     if let Err(msg) = self.sessions.shutdown(timeout).await {
       log::warn!("During shutdown: {}", msg);
     }
+    // Then flush any work which the CommandRunners have buffered.
+    if let Err(msg) = self.command_runner.shutdown().await {
+      log::warn!("During shutdown: {}", msg);
+    }
     // Then clear the Graph to ensure that drop handlers run (particular for running processes).
     self.graph.clear();
   }
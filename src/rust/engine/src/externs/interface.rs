@@ -275,6 +275,11 @@ py_module_initializer!(native_engine, |py, m| {
     "session_new_run_id",
     py_fn!(py, session_new_run_id(a: PySession)),
   )?;
+  m.add(
+    py,
+    "session_get_cache_summary",
+    py_fn!(py, session_get_cache_summary(a: PySession)),
+  )?;
   m.add(
     py,
     "session_poll_workunits",
@@ -1372,6 +1377,12 @@ fn session_new_run_id(py: Python, session_ptr: PySession) -> PyUnitResult {
   })
 }
 
+fn session_get_cache_summary(py: Python, session_ptr: PySession) -> CPyResult<String> {
+  with_session(py, session_ptr, |session| {
+    Ok(session.cache_stats().to_string())
+  })
+}
+
 fn session_get_observation_histograms(
   py: Python,
   scheduler_ptr: PyScheduler,
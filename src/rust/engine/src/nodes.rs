@@ -346,6 +346,11 @@ impl MultiPlatformExecuteProcess {
       is_nailgunnable,
       execution_slot_variable,
       cache_scope,
+      semantic_cache_key: false,
+      materialize_cached_outputs: None,
+      hermetic: true,
+      output_schema_version: None,
+      expires_at: None,
     })
   }
 
@@ -412,7 +417,8 @@ impl WrappedNode for MultiPlatformExecuteProcess {
       let execution_context = process_execution::Context::new(
         context.session.workunit_store(),
         context.session.build_id().to_string(),
-      );
+      )
+      .with_session_cache_stats(context.session.cache_stats());
 
       let res = command_runner
         .run(execution_context, workunit, request)
@@ -1571,7 +1577,7 @@ impl Node for NodeKey {
         ProcessCacheScope::Successful | ProcessCacheScope::PerRestartSuccessful => {
           process_result.0.exit_code == 0
         }
-        ProcessCacheScope::PerSession => false,
+        ProcessCacheScope::PerSession | ProcessCacheScope::Never => false,
       },
       (NodeKey::Task(ref t), NodeOutput::Value(ref v)) if t.task.engine_aware_return_type => {
         engine_aware::EngineAwareReturnType::cacheable(v).unwrap_or(true)
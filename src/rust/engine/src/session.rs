@@ -17,6 +17,7 @@ use futures::FutureExt;
 use graph::LastObserved;
 use log::warn;
 use parking_lot::{Mutex, RwLock};
+use process_execution::SessionCacheStats;
 use task_executor::Executor;
 use tokio::signal::unix::{signal, SignalKind};
 use ui::ConsoleUI;
@@ -90,6 +91,8 @@ struct SessionState {
   // Session/build_id would be stable.
   run_id: Mutex<Uuid>,
   workunit_metadata_map: RwLock<HashMap<UserMetadataPyValue, Value>>,
+  // The hits and misses of the process cache in this Session.
+  cache_stats: Arc<SessionCacheStats>,
 }
 
 ///
@@ -172,6 +175,7 @@ impl Session {
         session_values: Mutex::new(session_values),
         run_id: Mutex::new(Uuid::new_v4()),
         workunit_metadata_map: RwLock::new(HashMap::new()),
+        cache_stats: Arc::default(),
       }),
     })
   }
@@ -267,6 +271,10 @@ impl Session {
     self.state.workunit_store.clone()
   }
 
+  pub fn cache_stats(&self) -> Arc<SessionCacheStats> {
+    self.state.cache_stats.clone()
+  }
+
   pub fn build_id(&self) -> &String {
     &self.handle.build_id
   }
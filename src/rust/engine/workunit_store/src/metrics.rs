@@ -47,8 +47,70 @@ pub enum Metric {
   LocalCacheRequests,
   LocalCacheRequestsCached,
   LocalCacheRequestsUncached,
+  /// The number of uncached local cache requests for which there was no entry.
+  LocalCacheUncachedMiss,
+  /// The number of uncached local cache requests whose entry had expired.
+  LocalCacheUncachedExpired,
+  /// The number of uncached local cache requests whose entry was for an incompatible (or
+  /// unknown) platform.
+  LocalCacheUncachedPlatformMismatch,
+  /// The number of uncached local cache requests whose entry was for a failed process, when
+  /// failures are not cached.
+  LocalCacheUncachedCachedFailure,
+  /// The number of uncached local cache requests whose entry referenced outputs which could not
+  /// be loaded.
+  LocalCacheUncachedOutputsUnavailable,
+  /// The number of uncached local cache requests whose entry was structurally broken (and so was
+  /// evicted).
+  LocalCacheUncachedMalformed,
+  /// The number of uncached local cache requests whose entry was stored for different inputs
+  /// under the same fingerprint.
+  LocalCacheFingerprintCollision,
+  /// The number of uncached local cache requests whose entry carried a non-OK status (as may be
+  /// imported from a remote cache), so could not be served.
+  LocalCacheNonOkStatus,
   LocalCacheReadErrors,
   LocalCacheWriteErrors,
+  /// The number of successful results which were written to the local cache.
+  LocalCacheWritesSuccess,
+  /// The number of failed results which were written to the local cache, because their process
+  /// had `ProcessCacheScope::Always`.
+  LocalCacheWritesAlwaysScope,
+  /// The number of results which were not stored in the local cache because their output paths
+  /// were ambiguous.
+  LocalCacheInvalidOutputs,
+  /// The number of local cache entries which did not read back as written, when
+  /// `verify_on_write` is enabled.
+  LocalCacheWriteVerifyFailures,
+  /// The number of results which were not stored in the local cache because they referenced
+  /// digests which were not in the Store, when `verify_digests_on_write` is enabled.
+  LocalCacheWriteRejectedDangling,
+  /// The number of lines of the local cache decision log which were dropped because its writer
+  /// had fallen behind (or failed).
+  LocalCacheDecisionLogDropped,
+  /// The number of requests which bypassed the local cache because they were not hermetic.
+  LocalCacheSkippedNonHermetic,
+  /// The number of results which were not stored in the local cache because storing them would
+  /// have exceeded its `max_in_flight_store_bytes`.
+  LocalCacheSkippedMemoryPressure,
+  /// The number of results which were not stored in the local cache because their outputs were
+  /// smaller than its `min_output_bytes_to_cache`.
+  LocalCacheSkippedOutputTooSmall,
+  /// The number of results which were not stored in the local cache because their outputs were
+  /// larger than its `max_output_bytes_to_cache`.
+  LocalCacheSkippedOutputTooLarge,
+  /// With `speculate`, the number of local cache hits which completed before the process did.
+  LocalCacheSpeculationLookupCompletedFirst,
+  /// With `speculate`, the number of processes which completed before their local cache lookup.
+  LocalCacheSpeculationExecutionCompletedFirst,
+  /// The number of deferred local cache writes which replaced an earlier deferred write for the
+  /// same fingerprint before it was flushed.
+  LocalCacheDeferredWritesCoalesced,
+  /// The number of local cache entries which were read without a recorded platform.
+  LocalCacheUnknownPlatform,
+  /// The number of expired local cache entries which were served because re-executing their
+  /// process failed: see `serve_expired_on_error_within`.
+  LocalCacheServedExpiredOnError,
   /// The total time saved (in milliseconds) thanks to local cache hits instead of running the
   /// processes directly.
   LocalCacheTotalTimeSavedMs,
@@ -104,4 +166,42 @@ pub enum ObservationMetric {
   /// The time saved (in milliseconds) thanks to a remote cache hit instead of running the process
   /// directly.
   RemoteCacheTimeSavedMs,
+  /// The time (in microseconds) spent converting a local cache entry back into a process result,
+  /// excluding the time spent reading the entry itself.
+  LocalCachePopulateLatencyUs,
+  /// The time (in microseconds) spent on local cache lookups which did not produce a usable
+  /// result, and so only added latency before the process was run.
+  LocalCacheMissOverheadUs,
+  /// The age (in seconds) of local cache entries at the time that they are hit.
+  LocalCacheHitAgeSeconds,
+  /// The bytes read by a local cache hit: the entry itself, plus whichever of its outputs the
+  /// materialization policy ensured.
+  LocalCacheBytesRead,
+  /// The bytes written for each entry stored in the local cache. (Outputs are stored in the file
+  /// Store by the runner which produced them, and so are not included.)
+  LocalCacheBytesWritten,
+  /// The time (in microseconds) that the local cache spent waiting to begin a write transaction
+  /// on a shard of its store, which indicates contention on the shard.
+  LocalCacheShardWaitUs,
+  /// The number of local cache lookups which were already waiting for a permit when a lookup
+  /// began, if `max_concurrent_lookups` is set.
+  LocalCacheReadQueueDepth,
+  /// The time (in microseconds) spent serializing a local cache entry (with bincode, or in the
+  /// framed format) to store it.
+  LocalCacheEntrySerializeUs,
+  /// The time (in microseconds) spent deserializing a local cache entry (with bincode, or from the
+  /// framed format) on lookup.
+  LocalCacheEntryDeserializeUs,
+  /// The time (in microseconds) spent encoding the ExecuteResponse of a local cache entry to
+  /// store it, excluding any compression.
+  LocalCacheResponseEncodeUs,
+  /// The time (in microseconds) spent decoding the ExecuteResponse of a local cache entry on
+  /// lookup, including any decompression.
+  LocalCacheResponseDecodeUs,
+  /// The time (in microseconds) spent writing a local cache entry to its store, excluding
+  /// deferred writes (which are written in batches).
+  LocalCacheStoreWriteUs,
+  /// The time (in microseconds) spent reading a local cache entry from its store on lookup,
+  /// excluding its deserialization.
+  LocalCacheStoreReadUs,
 }